@@ -0,0 +1,418 @@
+//! Splitting long meter data requests into smaller windows and merging the results back.
+
+use std::collections::{HashMap, VecDeque};
+
+use chrono::{Datelike, Duration as ChronoDuration, NaiveDate, NaiveDateTime};
+use chrono_tz::Tz;
+
+use crate::resolution::add_months;
+use crate::get_timezone;
+use crate::models::{ConsumptionsResult, ResolutionDuration, SpotData, Sum, TimeRange, TimeSeries, Values, TSV};
+use crate::time::LocalDateTime;
+
+/// Largest range fetched with a single `meterdata2` request.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RequestWindow {
+    /// Calendar months in local time
+    Months(u32),
+    Days(i64),
+}
+
+impl RequestWindow {
+    pub fn for_resolution(resolution: &ResolutionDuration) -> RequestWindow {
+        if !resolution.is_fixed() {
+            RequestWindow::Months(12)
+        } else if resolution.minutes() < 60 {
+            RequestWindow::Days(7)
+        } else {
+            RequestWindow::Months(1)
+        }
+    }
+
+    fn next_boundary(&self, time: NaiveDateTime) -> NaiveDateTime {
+        match self {
+            RequestWindow::Months(months) => {
+                add_months(NaiveDate::from_ymd(time.year(), time.month(), 1).and_hms(0, 0, 0), *months as i64)
+            }
+            RequestWindow::Days(days) => time.date().and_hms(0, 0, 0) + ChronoDuration::days(*days),
+        }
+    }
+}
+
+/// Splits the range into consecutive windows that end on window boundaries, e.g. on the
+/// first day of each month for monthly windows.
+pub fn split_range(range: &TimeRange, window: RequestWindow) -> Vec<TimeRange> {
+    let mut windows = Vec::new();
+    let mut start = range.start;
+
+    while start < range.stop {
+        let stop = window.next_boundary(start).min(range.stop);
+        windows.push(TimeRange::new(start, stop));
+        start = stop;
+    }
+
+    windows
+}
+
+/// Merges the results of consecutive windows into one result.
+///
+/// Timestamps are calculated from the position of a value in the series, so the values of each
+/// window are first aligned to the intervals of the window (see [`align_series`]) and the later
+/// windows are rebased onto the start of the first one.
+/// Consumption sums are added together while spot sums, being prices, are averaged. Windows
+/// without spot data are padded with empty prices when the other windows have them.
+pub fn merge_results(
+    windows: &[TimeRange],
+    mut results: Vec<ConsumptionsResult>,
+    resolution: &ResolutionDuration,
+) -> Option<ConsumptionsResult> {
+    if results.len() <= 1 {
+        return results.pop();
+    }
+
+    let spot_unit = results
+        .iter()
+        .find_map(|result| result.getconsumptionsresult.spotdata.as_ref().map(|spot| spot.sum.unit.clone()));
+
+    let last = results.len() - 1;
+    let mut merged: Option<ConsumptionsResult> = None;
+    let mut spot_weight = 0;
+
+    for (index, (window, mut result)) in windows.iter().zip(results).enumerate() {
+        let is_last = index == last;
+        align_series(&mut result.getconsumptionsresult.consumptiondata.timeseries, window, resolution, is_last);
+
+        let mut spot = result.getconsumptionsresult.spotdata.take();
+        match (spot.as_mut(), &spot_unit) {
+            (Some(spot), _) => align_series(&mut spot.timeseries, window, resolution, is_last),
+            (None, Some(unit)) => {
+                warn!("Window {} is missing spot data, padding its prices as missing", window);
+                let len = result.getconsumptionsresult.consumptiondata.timeseries.values.tsv.len();
+                spot = Some(empty_spot_data(window, resolution, unit, len));
+            }
+            (None, None) => {}
+        }
+        let weight = spot.as_ref().map(|spot| known_values(&spot.timeseries)).unwrap_or(0);
+
+        let target = match merged.as_mut() {
+            Some(target) => target,
+            None => {
+                result.getconsumptionsresult.spotdata = spot;
+                merged = Some(result);
+                spot_weight = weight;
+                continue;
+            }
+        };
+
+        let consumption = &mut result.getconsumptionsresult.consumptiondata;
+        let target_consumption = &mut target.getconsumptionsresult.consumptiondata;
+        append_sum(&mut target_consumption.sum, &consumption.sum, false, 0, 0);
+        append_series(&mut target_consumption.timeseries, std::mem::take(&mut consumption.timeseries.values.tsv), consumption.timeseries.stop);
+
+        if let (Some(target), Some(spot)) = (target.getconsumptionsresult.spotdata.as_mut(), spot) {
+            append_sum(&mut target.sum, &spot.sum, true, spot_weight, weight);
+            spot_weight += weight;
+            append_series(&mut target.timeseries, spot.timeseries.values.tsv, spot.timeseries.stop);
+        }
+    }
+
+    merged
+}
+
+fn known_values(series: &TimeSeries) -> usize {
+    series.values.tsv.iter().filter(|tsv| tsv.quantity.is_some()).count()
+}
+
+/// Empty spot data of a window the API returned none for, as long as its consumption series.
+fn empty_spot_data(window: &TimeRange, resolution: &ResolutionDuration, unit: &str, len: usize) -> SpotData {
+    let start = LocalDateTime::from(window.start);
+    let stop = LocalDateTime::from(window.stop);
+    let mut timeseries = TimeSeries { start, stop, resolution: *resolution, values: Values { tsv: Vec::new() } };
+    align_series(&mut timeseries, window, resolution, false);
+    timeseries.values.tsv.truncate(len);
+
+    SpotData { sum: Sum { quantity: 0.0, start, stop, unit: unit.to_string() }, timeseries }
+}
+
+fn append_sum(target: &mut Sum, sum: &Sum, average: bool, target_weight: usize, weight: usize) {
+    if average {
+        let total = target_weight + weight;
+        if total > 0 {
+            target.quantity = (target.quantity * target_weight as f32 + sum.quantity * weight as f32) / total as f32;
+        }
+    } else {
+        target.quantity += sum.quantity;
+    }
+    target.stop = sum.stop;
+}
+
+fn append_series(target: &mut TimeSeries, values: Vec<TSV>, stop: LocalDateTime) {
+    // Rebase onto the first window so that `start + position * resolution` stays correct
+    let base_start = Some(target.start);
+    target.values.tsv.extend(values.into_iter().map(|mut tsv| {
+        tsv.start = base_start;
+        tsv
+    }));
+    target.stop = stop;
+}
+
+/// Puts every value of the window at the position of its own interval and fills the missing
+/// intervals with empty values, so that a gap anywhere in the window doesn't shift the values
+/// after it. The series then starts at the start of the window, and so do its values.
+///
+/// Values are matched by their local time, the times repeated when DST ends in the order they
+/// occur. WattiVahti doesn't label the intervals around DST reliably, e.g. a spring day can have a
+/// value at the skipped 03:00 and none at 06:00, so when any time isn't one of the intervals the
+/// values are aligned by their position instead. The last window isn't filled past its last
+/// value, like the result of a single request.
+fn align_series(series: &mut TimeSeries, window: &TimeRange, resolution: &ResolutionDuration, is_last: bool) {
+    let tz = get_timezone();
+    let start = LocalDateTime::from(window.start);
+    let expected = resolution.count_intervals(start, LocalDateTime::from(window.stop), &tz);
+    let values = std::mem::take(&mut series.values.tsv);
+    let received = values.len();
+
+    let mut aligned: Vec<Option<TSV>> = (0..expected).map(|_| None).collect();
+    match positions_by_time(&values, start, expected, resolution, &tz) {
+        Some(positions) => {
+            for (position, tsv) in positions.into_iter().zip(values) {
+                aligned[position] = Some(tsv);
+            }
+        }
+        None => {
+            warn!("Window {} has values outside of its {} {} intervals, aligning them by position", window, expected, resolution);
+            if received > expected {
+                warn!("Window {} returned {} values for {} intervals, dropping the values past its end", window, received, expected);
+            }
+            for (slot, tsv) in aligned.iter_mut().zip(values) {
+                *slot = Some(tsv);
+            }
+        }
+    }
+
+    if is_last {
+        while let Some(None) = aligned.last() {
+            aligned.pop();
+        }
+    }
+    if received < aligned.len() {
+        warn!("Window {} returned {} of {} values, padding the rest as missing", window, received, aligned.len());
+    }
+
+    series.start = start;
+    series.values.tsv = aligned
+        .into_iter()
+        .enumerate()
+        .map(|(position, tsv)| {
+            let mut tsv = tsv.unwrap_or_else(|| TSV {
+                quantity: None,
+                time: LocalDateTime::from_utc_in(&resolution.step_from(start, position, &tz), &tz),
+                start: None,
+                stop: None,
+                unit: None,
+            });
+            tsv.start = Some(start);
+            tsv
+        })
+        .collect();
+}
+
+/// Positions of the values among the `expected` intervals from `start` by their local times, or
+/// `None` when a time isn't one of the intervals.
+fn positions_by_time(values: &[TSV], start: LocalDateTime, expected: usize, resolution: &ResolutionDuration, tz: &Tz) -> Option<Vec<usize>> {
+    let mut positions: HashMap<NaiveDateTime, VecDeque<usize>> = HashMap::new();
+    for position in 0..expected {
+        let time = LocalDateTime::from_utc_in(&resolution.step_from(start, position, tz), tz);
+        positions.entry(time.naive()).or_default().push_back(position);
+    }
+
+    values
+        .iter()
+        .map(|tsv| positions.get_mut(&tsv.time.naive()).and_then(|positions| positions.pop_front()))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{DateTime, Utc};
+
+    use super::*;
+    use crate::models::{ConsumptionData, GetConsumptionsResult, SpotData, Values};
+
+    fn series(start: &str, stop: &str, quantities: &[f32]) -> TimeSeries {
+        let start: LocalDateTime = start.parse().unwrap();
+        let stop: LocalDateTime = stop.parse().unwrap();
+        TimeSeries {
+            start,
+            stop,
+            resolution: ResolutionDuration::PT1H,
+            values: Values {
+                tsv: quantities
+                    .iter()
+                    .enumerate()
+                    .map(|(pos, quantity)| TSV {
+                        quantity: Some(*quantity),
+                        time: LocalDateTime::new(start.naive() + ChronoDuration::hours(pos as i64)),
+                        start: Some(start),
+                        stop: Some(stop),
+                        unit: None,
+                    })
+                    .collect(),
+            },
+        }
+    }
+
+    fn result(start: &str, stop: &str, quantities: &[f32], prices: &[f32]) -> ConsumptionsResult {
+        let sum = |unit: &str, quantity: f32| Sum {
+            quantity,
+            start: start.parse().unwrap(),
+            stop: stop.parse().unwrap(),
+            unit: unit.to_string(),
+        };
+        ConsumptionsResult {
+            getconsumptionsresult: GetConsumptionsResult {
+                consumptiondata: ConsumptionData {
+                    meteringpointcode: "1337".to_string(),
+                    sum: sum("kWh", quantities.iter().sum()),
+                    timeseries: series(start, stop, quantities),
+                },
+                spotdata: Some(SpotData {
+                    sum: sum("EUR/MWh", prices.iter().sum::<f32>() / prices.len() as f32),
+                    timeseries: series(start, stop, prices),
+                }),
+            },
+        }
+    }
+
+    #[test]
+    fn test_split_range_by_month() {
+        let range = TimeRange::parse("2020-01-15T00:00:00", "2020-04-01T00:00:00").unwrap();
+        let windows = split_range(&range, RequestWindow::Months(1));
+
+        let windows: Vec<String> = windows.iter().map(|window| window.to_string()).collect();
+        assert_eq!(
+            windows,
+            vec![
+                "2020-01-15T00:00:00 - 2020-02-01T00:00:00",
+                "2020-02-01T00:00:00 - 2020-03-01T00:00:00",
+                "2020-03-01T00:00:00 - 2020-04-01T00:00:00",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_split_range_by_days() {
+        let range = TimeRange::parse("2020-12-20T00:00:00", "2021-01-05T00:00:00").unwrap();
+        let windows = split_range(&range, RequestWindow::for_resolution(&ResolutionDuration::PT15M));
+
+        assert_eq!(windows.len(), 3);
+        assert_eq!(windows[1].to_string(), "2020-12-27T00:00:00 - 2021-01-03T00:00:00");
+        assert_eq!(windows[2].stop, range.stop);
+
+        let short = TimeRange::parse("2020-12-20T00:00:00", "2020-12-21T00:00:00").unwrap();
+        assert_eq!(split_range(&short, RequestWindow::Days(7)), vec![short]);
+    }
+
+    #[test]
+    fn test_merge_results() {
+        let windows = vec![
+            TimeRange::parse("2022-08-01T00:00:00", "2022-08-01T03:00:00").unwrap(),
+            TimeRange::parse("2022-08-01T03:00:00", "2022-08-01T05:00:00").unwrap(),
+        ];
+        let results = vec![
+            // The first window is missing its last value
+            result("2022-08-01T00:00:00", "2022-08-01T03:00:00", &[1.0, 2.0], &[10.0, 20.0]),
+            result("2022-08-01T03:00:00", "2022-08-01T05:00:00", &[3.0, 4.0], &[30.0, 40.0]),
+        ];
+
+        let merged = merge_results(&windows, results, &ResolutionDuration::PT1H).unwrap();
+        let consumption = &merged.getconsumptionsresult.consumptiondata;
+        let quantities: Vec<Option<f32>> = consumption.timeseries.values.tsv.iter().map(|tsv| tsv.quantity).collect();
+        assert_eq!(quantities, vec![Some(1.0), Some(2.0), None, Some(3.0), Some(4.0)]);
+        assert_eq!(consumption.sum.quantity, 10.0);
+        assert_eq!(consumption.sum.stop.to_string(), "2022-08-01T05:00:00");
+        assert_eq!(consumption.timeseries.stop.to_string(), "2022-08-01T05:00:00");
+        assert!(consumption.timeseries.values.tsv.iter().all(|tsv| tsv.start == Some(consumption.timeseries.start)));
+        assert_eq!(consumption.timeseries.values.tsv[2].time.to_string(), "2022-08-01T02:00:00");
+
+        let last = consumption.timeseries.values.tsv[4].get_timestamp_utc_calculated(4, &ResolutionDuration::PT1H).unwrap();
+        let first = consumption.timeseries.values.tsv[0].get_timestamp_utc_calculated(0, &ResolutionDuration::PT1H).unwrap();
+        assert_eq!(last - first, ChronoDuration::hours(4));
+
+        let spot = merged.getconsumptionsresult.spotdata.unwrap();
+        assert_eq!(spot.timeseries.values.tsv.len(), 5);
+        assert_eq!(spot.sum.quantity, 25.0);
+    }
+
+    #[test]
+    fn test_merge_aligns_values_by_time() {
+        let windows = vec![
+            TimeRange::parse("2022-08-01T00:00:00", "2022-08-01T03:00:00").unwrap(),
+            TimeRange::parse("2022-08-01T03:00:00", "2022-08-01T05:00:00").unwrap(),
+        ];
+        // The first window is missing its value of 01:00 and the second one its spot data
+        let mut first = result("2022-08-01T00:00:00", "2022-08-01T03:00:00", &[1.0, 2.0, 3.0], &[10.0, 20.0, 30.0]);
+        first.getconsumptionsresult.consumptiondata.timeseries.values.tsv.remove(1);
+        let mut second = result("2022-08-01T03:00:00", "2022-08-01T05:00:00", &[4.0, 5.0], &[]);
+        second.getconsumptionsresult.spotdata = None;
+
+        let merged = merge_results(&windows, vec![first, second], &ResolutionDuration::PT1H).unwrap();
+        let consumption = &merged.getconsumptionsresult.consumptiondata.timeseries.values.tsv;
+        let quantities: Vec<Option<f32>> = consumption.iter().map(|tsv| tsv.quantity).collect();
+        assert_eq!(quantities, vec![Some(1.0), None, Some(3.0), Some(4.0), Some(5.0)]);
+        assert_eq!(consumption[1].time.to_string(), "2022-08-01T01:00:00");
+
+        let spot = merged.getconsumptionsresult.spotdata.unwrap();
+        let prices: Vec<Option<f32>> = spot.timeseries.values.tsv.iter().map(|tsv| tsv.quantity).collect();
+        assert_eq!(prices, vec![Some(10.0), Some(20.0), Some(30.0), None, None]);
+        assert_eq!(spot.sum.quantity, 20.0);
+        assert_eq!(spot.sum.stop.to_string(), "2022-08-01T05:00:00");
+    }
+
+    #[test]
+    fn test_merge_aligns_values_outside_the_window_by_position() {
+        let windows = vec![
+            TimeRange::parse("2022-08-01T00:00:00", "2022-08-01T02:00:00").unwrap(),
+            TimeRange::parse("2022-08-01T02:00:00", "2022-08-01T04:00:00").unwrap(),
+        ];
+        // The first window returned one value too many
+        let results = vec![
+            result("2022-08-01T00:00:00", "2022-08-01T02:00:00", &[1.0, 2.0, 3.0], &[10.0, 20.0, 30.0]),
+            result("2022-08-01T02:00:00", "2022-08-01T04:00:00", &[4.0, 5.0], &[40.0, 50.0]),
+        ];
+
+        let merged = merge_results(&windows, results, &ResolutionDuration::PT1H).unwrap();
+        let quantities: Vec<Option<f32>> =
+            merged.getconsumptionsresult.consumptiondata.timeseries.values.tsv.iter().map(|tsv| tsv.quantity).collect();
+        assert_eq!(quantities, vec![Some(1.0), Some(2.0), Some(4.0), Some(5.0)]);
+    }
+
+    #[test]
+    fn test_merge_spring_forward_day_by_position() {
+        let windows = vec![
+            TimeRange::parse("2023-03-26T00:00:00", "2023-03-27T00:00:00").unwrap(),
+            TimeRange::parse("2023-03-27T00:00:00", "2023-03-27T02:00:00").unwrap(),
+        ];
+        // Like the days WattiVahti returns when clocks are turned forward: a value at the skipped
+        // 03:00 and none at 06:00
+        let quantities: Vec<f32> = (0..23).map(|index| index as f32).collect();
+        let mut spring = result("2023-03-26T00:00:00", "2023-03-27T00:00:00", &quantities, &[]);
+        spring.getconsumptionsresult.spotdata = None;
+        let hours = (0..6).chain(7..24);
+        for (tsv, hour) in spring.getconsumptionsresult.consumptiondata.timeseries.values.tsv.iter_mut().zip(hours) {
+            tsv.time = format!("2023-03-26T{:02}:00:00", hour).parse().unwrap();
+        }
+        let mut next = result("2023-03-27T00:00:00", "2023-03-27T02:00:00", &[23.0, 24.0], &[]);
+        next.getconsumptionsresult.spotdata = None;
+
+        let merged = merge_results(&windows, vec![spring, next], &ResolutionDuration::PT1H).unwrap();
+        let tsv = &merged.getconsumptionsresult.consumptiondata.timeseries.values.tsv;
+        assert_eq!(tsv.len(), 25);
+        for (position, value) in tsv.iter().enumerate() {
+            assert_eq!(value.quantity, Some(position as f32));
+        }
+        let utc = |time: &str| DateTime::parse_from_rfc3339(time).unwrap().with_timezone(&Utc);
+        assert_eq!(tsv[3].get_timestamp_utc_calculated(3, &ResolutionDuration::PT1H), Some(utc("2023-03-26T04:00:00+03:00")));
+        assert_eq!(tsv[23].get_timestamp_utc_calculated(23, &ResolutionDuration::PT1H), Some(utc("2023-03-27T00:00:00+03:00")));
+    }
+}
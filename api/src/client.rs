@@ -0,0 +1,264 @@
+use std::time::Duration;
+
+use http::{
+    header::{HeaderMap, HeaderValue, AUTHORIZATION, ORIGIN, REFERER, RETRY_AFTER},
+    StatusCode,
+};
+
+use futures_util::stream::{self, StreamExt, TryStreamExt};
+use serde::de::DeserializeOwned;
+
+use crate::chunk::{merge_results, split_range, RequestWindow};
+use crate::error::ApiError;
+use crate::retry::{retry, RetryPolicy};
+use crate::models::{ConsumptionsResult, MeasurementType, ResolutionDuration, TimeRange};
+
+pub const DEFAULT_BASE_URL: &str = r#"https://porienergia-prod-agent.frendsapp.com:9999/api/onlineapi/v1/"#;
+pub const DEFAULT_USER_AGENT: &str = r#"Mozilla/5.0 (Windows NT 10.0; Win64; x64; rv:121.0) Gecko/20100101 Firefox/121.0"#;
+pub const DEFAULT_ORIGIN: &str = r#"https://www.wattivahti.fi"#;
+pub const DEFAULT_REFERER: &str = r#"https://www.wattivahti.fi/"#;
+
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(60);
+const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+const DEFAULT_MAX_CONCURRENT_REQUESTS: usize = 4;
+
+/// Client for the WattiVahti online API.
+///
+/// Holds a pooled `reqwest::Client` so it is cheap to clone and should be
+/// created once and shared instead of building a new one for every request.
+#[derive(Clone, Debug)]
+pub struct WattiVahtiClient {
+    http: reqwest::Client,
+    base_url: String,
+    max_concurrent_requests: usize,
+    retry_policy: RetryPolicy,
+}
+
+impl WattiVahtiClient {
+    pub fn builder() -> WattiVahtiClientBuilder {
+        WattiVahtiClientBuilder::default()
+    }
+
+    pub fn base_url(&self) -> &str {
+        &self.base_url
+    }
+
+    /// The underlying HTTP client, for sharing its connection pool with related requests.
+    pub fn http(&self) -> &reqwest::Client {
+        &self.http
+    }
+
+    pub fn retry_policy(&self) -> &RetryPolicy {
+        &self.retry_policy
+    }
+
+    /// Returns a client sharing the same connection pool but retrying with the given policy,
+    /// e.g. `client.with_retry_policy(RetryPolicy::no_retry()).get_meter_data(..)`.
+    pub fn with_retry_policy(&self, retry_policy: RetryPolicy) -> Self {
+        Self {
+            retry_policy,
+            ..self.clone()
+        }
+    }
+
+    pub async fn get_production_data(&self, access_token: &str, metering_point_code: &str, start: &str, stop: &str, resolution: &str) -> Result<ConsumptionsResult, ApiError> {
+        let range = TimeRange::parse(start, stop)?;
+        self.get_meter_data(access_token, metering_point_code, MeasurementType::Production, &range, &resolution.parse()?).await
+    }
+
+    pub async fn get_consumption_data(&self, access_token: &str, metering_point_code: &str, start: &str, stop: &str, resolution: &str) -> Result<ConsumptionsResult, ApiError> {
+        let range = TimeRange::parse(start, stop)?;
+        self.get_meter_data(access_token, metering_point_code, MeasurementType::Consumption, &range, &resolution.parse()?).await
+    }
+
+    /// Fetches the `meterdata2` series of the given measurement type for the metering point.
+    ///
+    /// Long ranges are split into resolution appropriate windows (see [`RequestWindow`]) which are
+    /// fetched concurrently and merged back into a single result in order.
+    pub async fn get_meter_data(&self, access_token: &str, metering_point_code: &str, measurement_type: MeasurementType, range: &TimeRange, resolution: &ResolutionDuration) -> Result<ConsumptionsResult, ApiError> {
+        let windows = split_range(range, RequestWindow::for_resolution(resolution));
+        if windows.len() <= 1 {
+            return self.get_meter_data_window(access_token, metering_point_code, measurement_type, range, resolution).await;
+        }
+
+        info!("Fetching {} {} in {} windows", measurement_type, range, windows.len());
+        let results: Vec<ConsumptionsResult> = stream::iter(windows.iter())
+            .map(|window| self.get_meter_data_window(access_token, metering_point_code, measurement_type, window, resolution))
+            .buffered(self.max_concurrent_requests)
+            .try_collect()
+            .await?;
+
+        merge_results(&windows, results, resolution)
+            .ok_or_else(|| ApiError::Config(format!("empty time range {}", range)))
+    }
+
+    async fn get_meter_data_window(&self, access_token: &str, metering_point_code: &str, measurement_type: MeasurementType, range: &TimeRange, resolution: &ResolutionDuration) -> Result<ConsumptionsResult, ApiError> {
+        let operation = format!("Fetching {} {} for {}", measurement_type, range, metering_point_code);
+        retry(&self.retry_policy, &operation, || {
+            self.request_meter_data(access_token, metering_point_code, measurement_type, range, resolution)
+        })
+        .await
+    }
+
+    async fn request_meter_data(&self, access_token: &str, metering_point_code: &str, measurement_type: MeasurementType, range: &TimeRange, resolution: &ResolutionDuration) -> Result<ConsumptionsResult, ApiError> {
+        let data: ConsumptionsResult = self
+            .send(self.http
+                .get(format!("{}meterdata2?meteringPointCode={}&measurementType={}&start={}&stop={}&resultStep={}", self.base_url, metering_point_code, measurement_type.code(), range.start_str(), range.stop_str(), resolution.to_query_string()))
+                .header(AUTHORIZATION, format!("Bearer {}", access_token)))
+            .await?;
+        debug!("ConsumptionsResult: {:#?}", data);
+
+        Ok(data)
+    }
+
+    /// Sends the request and decodes a successful JSON response.
+    async fn send<T: DeserializeOwned>(&self, request: reqwest::RequestBuilder) -> Result<T, ApiError> {
+        let res = request
+            .send()
+            .await?;
+
+        let status = res.status();
+
+        if status == StatusCode::UNAUTHORIZED {
+            return Err(ApiError::Unauthorized);
+        }
+
+        if status == StatusCode::TOO_MANY_REQUESTS {
+            let retry_after = res
+                .headers()
+                .get(RETRY_AFTER)
+                .and_then(|value| value.to_str().ok())
+                .and_then(|value| value.trim().parse::<u64>().ok())
+                .map(Duration::from_secs);
+            return Err(ApiError::RateLimited { retry_after });
+        }
+
+        let data_str = res
+            .text()
+            .await?;
+        debug!("{}", data_str);
+
+        if status != StatusCode::OK {
+            return Err(ApiError::Server { status, body: data_str });
+        }
+
+        match serde_json::from_str(&data_str) {
+            Ok(data) => Ok(data),
+            Err(source) => Err(ApiError::Decode { body: data_str, source }),
+        }
+    }
+}
+
+impl Default for WattiVahtiClient {
+    fn default() -> Self {
+        WattiVahtiClient::builder()
+            .build()
+            .expect("Failed to build the default WattiVahti client")
+    }
+}
+
+/// Builder for [`WattiVahtiClient`]. Every setting defaults to the values used by
+/// the Porienergia WattiVahti web app.
+#[derive(Clone, Debug)]
+pub struct WattiVahtiClientBuilder {
+    base_url: String,
+    user_agent: String,
+    origin: String,
+    referer: String,
+    timeout: Duration,
+    connect_timeout: Duration,
+    max_concurrent_requests: usize,
+    retry_policy: RetryPolicy,
+}
+
+impl Default for WattiVahtiClientBuilder {
+    fn default() -> Self {
+        Self {
+            base_url: DEFAULT_BASE_URL.to_string(),
+            user_agent: DEFAULT_USER_AGENT.to_string(),
+            origin: DEFAULT_ORIGIN.to_string(),
+            referer: DEFAULT_REFERER.to_string(),
+            timeout: DEFAULT_TIMEOUT,
+            connect_timeout: DEFAULT_CONNECT_TIMEOUT,
+            max_concurrent_requests: DEFAULT_MAX_CONCURRENT_REQUESTS,
+            retry_policy: RetryPolicy::default(),
+        }
+    }
+}
+
+impl WattiVahtiClientBuilder {
+    /// Base URL of the online API, e.g. `https://<utility>-prod-agent.frendsapp.com:9999/api/onlineapi/v1/`.
+    pub fn base_url(mut self, base_url: impl Into<String>) -> Self {
+        self.base_url = base_url.into();
+        self
+    }
+
+    pub fn user_agent(mut self, user_agent: impl Into<String>) -> Self {
+        self.user_agent = user_agent.into();
+        self
+    }
+
+    pub fn origin(mut self, origin: impl Into<String>) -> Self {
+        self.origin = origin.into();
+        self
+    }
+
+    pub fn referer(mut self, referer: impl Into<String>) -> Self {
+        self.referer = referer.into();
+        self
+    }
+
+    /// Total timeout for a single request, including reading the response body.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    pub fn connect_timeout(mut self, connect_timeout: Duration) -> Self {
+        self.connect_timeout = connect_timeout;
+        self
+    }
+
+    /// How many windows of a long range are fetched at the same time.
+    pub fn max_concurrent_requests(mut self, max_concurrent_requests: usize) -> Self {
+        self.max_concurrent_requests = max_concurrent_requests.max(1);
+        self
+    }
+
+    /// Default retry policy for transient failures, see [`WattiVahtiClient::with_retry_policy`].
+    pub fn retry_policy(mut self, retry_policy: RetryPolicy) -> Self {
+        self.retry_policy = retry_policy;
+        self
+    }
+
+    pub fn build(self) -> Result<WattiVahtiClient, ApiError> {
+        let mut headers = HeaderMap::new();
+        headers.insert(ORIGIN, to_header_value("origin", &self.origin)?);
+        headers.insert(REFERER, to_header_value("referer", &self.referer)?);
+
+        let http = reqwest::Client::builder()
+            .user_agent(self.user_agent)
+            .default_headers(headers)
+            .timeout(self.timeout)
+            .connect_timeout(self.connect_timeout)
+            .build()?;
+
+        // Endpoints are appended to the base URL, so make sure it ends with a slash
+        let mut base_url = self.base_url;
+        if !base_url.ends_with('/') {
+            base_url.push('/');
+        }
+
+        Ok(WattiVahtiClient {
+            http,
+            base_url,
+            max_concurrent_requests: self.max_concurrent_requests,
+            retry_policy: self.retry_policy,
+        })
+    }
+}
+
+fn to_header_value(name: &str, value: &str) -> Result<HeaderValue, ApiError> {
+    HeaderValue::from_str(value)
+        .map_err(|_| ApiError::Config(format!("invalid {} header value '{}'", name, value)))
+}
@@ -0,0 +1,53 @@
+use std::time::Duration;
+
+use http::StatusCode;
+use thiserror::Error;
+
+use crate::resolution::ParseResolutionError;
+
+/// Errors returned by the WattiVahti API client.
+#[derive(Error, Debug)]
+pub enum ApiError {
+    /// The access token is missing, invalid or has expired.
+    #[error("Unauthorized")]
+    Unauthorized,
+    /// Too many requests, `retry_after` is taken from the `Retry-After` header when present.
+    #[error("Rate limited by the WattiVahti API (retry after {retry_after:?})")]
+    RateLimited { retry_after: Option<Duration> },
+    /// Any other non-successful status, with the raw response body.
+    #[error("WattiVahti API responded with {status}: {body}")]
+    Server { status: StatusCode, body: String },
+    /// The response was successful but didn't match the expected schema.
+    #[error("Failed to decode the WattiVahti response: {source}")]
+    Decode {
+        body: String,
+        #[source]
+        source: serde_json::Error,
+    },
+    /// Connecting, sending the request or reading the response failed.
+    #[error("Request to the WattiVahti API failed: {0}")]
+    Transport(#[from] reqwest::Error),
+    #[error("Invalid time range: {0}")]
+    InvalidRange(#[from] chrono::ParseError),
+    #[error(transparent)]
+    InvalidResolution(#[from] ParseResolutionError),
+    #[error("Invalid client configuration: {0}")]
+    Config(String),
+}
+
+impl ApiError {
+    pub fn is_unauthorized(&self) -> bool {
+        matches!(self, ApiError::Unauthorized)
+    }
+
+    /// HTTP status of the failed response, if the request got that far.
+    pub fn status(&self) -> Option<StatusCode> {
+        match self {
+            ApiError::Unauthorized => Some(StatusCode::UNAUTHORIZED),
+            ApiError::RateLimited { .. } => Some(StatusCode::TOO_MANY_REQUESTS),
+            ApiError::Server { status, .. } => Some(*status),
+            ApiError::Transport(err) => err.status(),
+            _ => None,
+        }
+    }
+}
@@ -1,93 +1,76 @@
 #[macro_use]
 extern crate log;
 
+pub mod chunk;
+pub mod client;
+pub mod error;
+#[cfg(any(test, feature = "mock"))]
+pub mod mock;
 pub mod models;
+pub mod resolution;
+pub mod retry;
+pub mod time;
+pub mod validation;
+
+use std::sync::RwLock;
 
 use chrono_tz::Tz;
-use http::{StatusCode, header::USER_AGENT, header::AUTHORIZATION};
+pub use client::{WattiVahtiClient, WattiVahtiClientBuilder};
+pub use error::ApiError;
 pub use models::*;
-
-const API_URL: &str = r#"https://porienergia-prod-agent.frendsapp.com:9999/api/onlineapi/v1/"#;
-const MOZILLA_USER_AGENT: &str = r#"Mozilla/5.0 (Windows NT 10.0; Win64; x64; rv:121.0) Gecko/20100101 Firefox/121.0"#;
-const ORIGIN: &str = r#"https://www.wattivahti.fi"#;
-const REFERER: &str = r#"https://www.wattivahti.fi/"#;
-
-pub async fn get_production_data(access_token: &str, metering_point_code: &str, start: &str, stop: &str, resolution: &str) -> Result<ConsumptionsResult, anyhow::Error> {
-    let res = reqwest::Client::new()
-        .get(format!("{}meterdata2?meteringPointCode={}&measurementType=6&start={}&stop={}&resultStep={}", API_URL, metering_point_code, start, stop, resolution))
-        .header(USER_AGENT, MOZILLA_USER_AGENT)
-        .header(AUTHORIZATION, format!("Bearer {}", access_token))
-        .header("Origin", ORIGIN)
-        .header("Referer", REFERER)
-        .send()
-        .await?;
-
-    let status = res.status();
-
-    if status == StatusCode::UNAUTHORIZED {
-        return Err(anyhow::anyhow!("Unauthorized"));
-    }
-
-    let data_str = res
-        .text()
-        .await?;
-    debug!("{}", data_str);
-
-    if status != StatusCode::OK {
-        return Err(anyhow::anyhow!(data_str));
-    }
-
-    let data: ConsumptionsResult = serde_json::from_str(&data_str)?;
-    debug!("ConsumptionResult: {:#?}", data);
-
-    Ok(data)
+pub use retry::{RetryPolicy, Retryable};
+pub use time::LocalDateTime;
+pub use validation::ValidationReport;
+
+/// Fetches production data with a default [`WattiVahtiClient`].
+pub async fn get_production_data(access_token: &str, metering_point_code: &str, start: &str, stop: &str, resolution: &str) -> Result<ConsumptionsResult, ApiError> {
+    WattiVahtiClient::default()
+        .get_production_data(access_token, metering_point_code, start, stop, resolution)
+        .await
 }
 
-pub async fn get_consumption_data(access_token: &str, metering_point_code: &str, start: &str, stop: &str, resolution: &str) -> Result<ConsumptionsResult, anyhow::Error> {
-    let res = reqwest::Client::new()
-        .get(format!("{}meterdata2?meteringPointCode={}&measurementType=1&start={}&stop={}&resultStep={}", API_URL, metering_point_code, start, stop, resolution))
-        .header(USER_AGENT, MOZILLA_USER_AGENT)
-        .header(AUTHORIZATION, format!("Bearer {}", access_token))
-        .header("Origin", ORIGIN)
-        .header("Referer", REFERER)
-        .send()
-        .await?;
-
-    let status = res.status();
+/// Fetches consumption data with a default [`WattiVahtiClient`].
+pub async fn get_consumption_data(access_token: &str, metering_point_code: &str, start: &str, stop: &str, resolution: &str) -> Result<ConsumptionsResult, ApiError> {
+    WattiVahtiClient::default()
+        .get_consumption_data(access_token, metering_point_code, start, stop, resolution)
+        .await
+}
 
-    if status == StatusCode::UNAUTHORIZED {
-        return Err(anyhow::anyhow!("Unauthorized"));
-    }
+/// Fetches meter data of any measurement type with a default [`WattiVahtiClient`].
+pub async fn get_meter_data(access_token: &str, metering_point_code: &str, measurement_type: MeasurementType, range: &TimeRange, resolution: &ResolutionDuration) -> Result<ConsumptionsResult, ApiError> {
+    WattiVahtiClient::default()
+        .get_meter_data(access_token, metering_point_code, measurement_type, range, resolution)
+        .await
+}
 
-    let data_str = res
-        .text()
-        .await?;
-    debug!("{}", data_str);
+static TIMEZONE: RwLock<Option<Tz>> = RwLock::new(None);
 
-    if status != StatusCode::OK {
-        return Err(anyhow::anyhow!(data_str));
+/// Time zone of the local times in the API, set with [`set_timezone`] or read from
+/// `CHRONO_TIMEZONE`.
+pub fn get_timezone() -> Tz {
+    if let Some(timezone) = *TIMEZONE.read().unwrap() {
+        return timezone;
     }
 
-    let data: ConsumptionsResult = serde_json::from_str(&data_str)?;
-    debug!("ConsumptionsResult: {:#?}", data);
-
-    Ok(data)
-}
-
-pub fn get_timezone() -> Tz {
     let timezone = dotenv::var("CHRONO_TIMEZONE").unwrap_or("Europe/Helsinki".to_string());
     timezone.parse().unwrap()
 }
 
+/// Overrides the time zone used by [`get_timezone`] for the rest of the process.
+pub fn set_timezone(timezone: Tz) {
+    *TIMEZONE.write().unwrap() = Some(timezone);
+}
+
 #[cfg(test)]
 mod tests {
     use chrono::TimeZone;
-    use chrono::{NaiveDateTime, DateTime, Utc};
+    use chrono::{DateTime, Utc};
     use chrono::Duration as ChronoDuration;
     use dotenv::dotenv;
     use super::*;
 
     #[tokio::test]
+    #[ignore = "requires ACCESS_TOKEN and network access"]
     async fn test_production_data() {
         dotenv().ok();
 
@@ -101,6 +84,7 @@ mod tests {
     }
 
     #[tokio::test]
+    #[ignore = "requires ACCESS_TOKEN and network access"]
     async fn test_consumption_data() {
         dotenv().ok();
 
@@ -114,6 +98,7 @@ mod tests {
     }
 
     #[tokio::test]
+    #[ignore = "requires ACCESS_TOKEN and network access"]
     async fn test_spot_data_data() {
         dotenv().ok();
 
@@ -122,10 +107,52 @@ mod tests {
         let start = "2023-02-01T00:00:00";
         let stop = "2023-03-01T00:00:00";
 
-        let data: ConsumptionsResult = get_consumption_data(&access_token, &metering_point_code, &start, &stop, "PT1H").await.unwrap();
+        let data: ConsumptionsResult = get_consumption_data(&access_token, &metering_point_code, start, stop, "PT1H").await.unwrap();
         info!("ConsumptionResult: {:#?}", data);
     }
 
+    #[test]
+    fn test_client_builder() {
+        let client = WattiVahtiClient::builder()
+            .base_url("http://localhost:8080/api/onlineapi/v1")
+            .user_agent("wattivahti-logger")
+            .timeout(std::time::Duration::from_secs(5))
+            .build()
+            .unwrap();
+        assert_eq!(client.base_url(), "http://localhost:8080/api/onlineapi/v1/");
+
+        let client = WattiVahtiClient::default();
+        assert_eq!(client.base_url(), client::DEFAULT_BASE_URL);
+    }
+
+    #[test]
+    fn test_measurement_type() {
+        assert_eq!(MeasurementType::Consumption.code(), 1);
+        assert_eq!(MeasurementType::Production.code(), 6);
+        assert_eq!(MeasurementType::from(6), MeasurementType::Production);
+        assert_eq!(MeasurementType::from(3), MeasurementType::Other(3));
+        assert!(!MeasurementType::Other(3).is_production());
+    }
+
+    #[test]
+    fn test_api_error() {
+        assert!(ApiError::Unauthorized.is_unauthorized());
+        assert_eq!(ApiError::RateLimited { retry_after: None }.status(), Some(http::StatusCode::TOO_MANY_REQUESTS));
+
+        let source = serde_json::from_str::<ConsumptionsResult>("{}").unwrap_err();
+        let err = ApiError::Decode { body: "{}".to_string(), source };
+        assert!(!err.is_unauthorized());
+        assert_eq!(err.status(), None);
+    }
+
+    #[test]
+    fn test_time_range() {
+        let range = TimeRange::parse("2023-03-26T00:00:00", "2023-03-27T00:00:00").unwrap();
+        assert_eq!(range.start_str(), "2023-03-26T00:00:00");
+        assert_eq!(range.to_string(), "2023-03-26T00:00:00 - 2023-03-27T00:00:00");
+        assert!(TimeRange::parse("2023-03-26", "2023-03-27").is_err());
+    }
+
     #[tokio::test]
     async fn test_production_data_struct() {
         dotenv().ok();
@@ -186,10 +213,10 @@ mod tests {
             }
         }"#;
 
-        let data: ConsumptionsResult = serde_json::from_str(&data_str).unwrap();
+        let data: ConsumptionsResult = serde_json::from_str(data_str).unwrap();
         info!("ConsumptionResult: {:#?}", data);
-        info!("Start: {:#?}", data.getconsumptionsresult.consumptiondata.sum.get_start_utc());
-        info!("Stop: {:#?}", data.getconsumptionsresult.consumptiondata.sum.get_stop_utc());
+        info!("Start: {:#?}", data.getconsumptionsresult.consumptiondata.sum.start.to_utc());
+        info!("Stop: {:#?}", data.getconsumptionsresult.consumptiondata.sum.stop.to_utc());
     }
 
     #[tokio::test]
@@ -301,10 +328,10 @@ mod tests {
             }
         }"#;
 
-        let data: ConsumptionsResult = serde_json::from_str(&data_str).unwrap();
+        let data: ConsumptionsResult = serde_json::from_str(data_str).unwrap();
         info!("ConsumptionResult: {:#?}", data);
-        info!("Start: {:#?}", data.getconsumptionsresult.consumptiondata.sum.get_start_utc());
-        info!("Stop: {:#?}", data.getconsumptionsresult.consumptiondata.sum.get_stop_utc());
+        info!("Start: {:#?}", data.getconsumptionsresult.consumptiondata.sum.start.to_utc());
+        info!("Stop: {:#?}", data.getconsumptionsresult.consumptiondata.sum.stop.to_utc());
         info!("Has spot data: {:#?}", data.getconsumptionsresult.spotdata.is_some());
     }
 
@@ -399,14 +426,14 @@ mod tests {
             }
         }"#;
 
-        let data: ConsumptionsResult = serde_json::from_str(&data_str).unwrap();
+        let data: ConsumptionsResult = serde_json::from_str(data_str).unwrap();
         info!("ConsumptionResult: {:#?}", data);
-        info!("Start: {:#?}", data.getconsumptionsresult.consumptiondata.sum.get_start_utc());
-        info!("Stop: {:#?}", data.getconsumptionsresult.consumptiondata.sum.get_stop_utc());
+        info!("Start: {:#?}", data.getconsumptionsresult.consumptiondata.sum.start.to_utc());
+        info!("Stop: {:#?}", data.getconsumptionsresult.consumptiondata.sum.stop.to_utc());
         info!("Has spot data: {:#?}", data.getconsumptionsresult.spotdata.is_some());
 
         for (pos, tsv) in data.getconsumptionsresult.consumptiondata.timeseries.values.tsv.iter().enumerate() {
-            let time = &tsv.get_timestamp_utc_calculated(pos, ResolutionDuration::PT1H);
+            let time = &tsv.get_timestamp_utc_calculated(pos, &ResolutionDuration::PT1H);
             if time.is_none() {
                 warn!("Time couldn't be parsed");
                 return;
@@ -420,29 +447,25 @@ mod tests {
     async fn test_daylight_savings() {
         dotenv().ok();
 
-        let time = "2022-03-27T03:00:00";
+        let time: LocalDateTime = "2022-03-27T03:00:00".parse().unwrap();
         // let time = "2023-03-26T04:00:00";
-        let naive_time = NaiveDateTime::parse_from_str(&time, "%Y-%m-%dT%H:%M:%S");
-        info!("System Time UTC {}", naive_time.unwrap());
+        info!("System Time Local {}", time);
 
-        let converted = Utc.from_utc_datetime(&get_timezone().from_local_datetime(&naive_time.unwrap())
-            .unwrap()
-            .naive_utc());
+        // Doesn't exist in Europe/Helsinki, resolves to the end of the DST gap
+        let converted = time.to_utc_in(&chrono_tz::Europe::Helsinki);
+        assert_eq!(converted, Utc.ymd(2022, 3, 27).and_hms(1, 0, 0));
 
-            info!("Converted Time Local {}", converted);
+        info!("Converted Time UTC {}", converted);
     }
 
     #[tokio::test]
     async fn test_daylight_savings_2() {
         dotenv().ok();
 
-        let start = "2022-03-01T00:00:00";
-        let naive_time = NaiveDateTime::parse_from_str(&start, "%Y-%m-%dT%H:%M:%S");
-        info!("System Time Local {}", naive_time.unwrap());
+        let start: LocalDateTime = "2022-03-01T00:00:00".parse().unwrap();
+        info!("System Time Local {}", start);
 
-        let converted : DateTime<Utc> = Utc.from_utc_datetime(&get_timezone().from_local_datetime(&naive_time.unwrap())
-            .unwrap()
-            .naive_utc());
+        let converted : DateTime<Utc> = start.to_utc();
 
             info!("Converted Time UTC {}", converted);
 
@@ -0,0 +1,415 @@
+//! In-process mock of the WattiVahti API for offline tests, enabled with the `mock` feature.
+//!
+//! Responses are replayed from the JSON fixtures in `api/tests/fixtures`. New fixtures can be
+//! recorded from the real API with [`record_fixture`], see the ignored `test_record_fixture`.
+
+use std::path::PathBuf;
+use std::time::Duration;
+
+use http::StatusCode;
+use wiremock::matchers::{method, path, query_param};
+use wiremock::{Mock, MockServer, Request, ResponseTemplate};
+
+use crate::client::{WattiVahtiClient, WattiVahtiClientBuilder};
+use crate::models::{ConsumptionsResult, MeasurementType};
+use crate::retry::RetryPolicy;
+
+const METER_DATA_PATH: &str = "/meterdata2";
+const TOKEN_PATH: &str = "/wattivahti/token";
+
+/// Mocks that should win over the fixtures, e.g. failures for the next few requests.
+const PRIORITY_OVERRIDE: u8 = 1;
+
+pub fn fixture_path(name: &str) -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures").join(name)
+}
+
+/// Reads a recorded `meterdata2` response, panics if the fixture doesn't exist.
+pub fn fixture(name: &str) -> String {
+    let path = fixture_path(name);
+    std::fs::read_to_string(&path).unwrap_or_else(|err| panic!("Failed to read fixture {}: {}", path.display(), err))
+}
+
+/// Saves a response as a fixture. Replace the metering point code before committing it.
+pub fn record_fixture(name: &str, result: &ConsumptionsResult) -> std::io::Result<PathBuf> {
+    let path = fixture_path(name);
+    let json = serde_json::to_string_pretty(result)?;
+    std::fs::write(&path, json + "\n")?;
+    Ok(path)
+}
+
+/// Mock server serving `meterdata2` and the token endpoint.
+pub struct MockWattiVahti {
+    server: MockServer,
+}
+
+impl MockWattiVahti {
+    pub async fn start() -> Self {
+        Self { server: MockServer::start().await }
+    }
+
+    pub fn base_url(&self) -> String {
+        format!("{}/", self.server.uri())
+    }
+
+    /// Endpoint for `get_access_token`, which appends `/wattivahti/token`.
+    pub fn token_endpoint(&self) -> String {
+        self.server.uri()
+    }
+
+    /// Client builder pointed at the mock with short retry delays.
+    pub fn client_builder(&self) -> WattiVahtiClientBuilder {
+        WattiVahtiClient::builder()
+            .base_url(self.base_url())
+            .timeout(Duration::from_secs(5))
+            .retry_policy(RetryPolicy::new(3, Duration::from_millis(1), Duration::from_millis(10)))
+    }
+
+    pub fn client(&self) -> WattiVahtiClient {
+        self.client_builder().build().expect("Failed to build the mock WattiVahti client")
+    }
+
+    /// Replays the fixture for every request of the measurement type.
+    pub async fn serve_fixture(&self, measurement_type: MeasurementType, name: &str) {
+        self.serve_fixture_with_delay(measurement_type, name, Duration::ZERO).await;
+    }
+
+    /// Replays the fixture for every request of the measurement type after the delay.
+    pub async fn serve_fixture_with_delay(&self, measurement_type: MeasurementType, name: &str, delay: Duration) {
+        Mock::given(method("GET"))
+            .and(path(METER_DATA_PATH))
+            .and(query_param("measurementType", measurement_type.code().to_string()))
+            .respond_with(json_response(StatusCode::OK, fixture(name)).set_delay(delay))
+            .mount(&self.server)
+            .await;
+    }
+
+    /// Replays the fixture for requests of the measurement type starting at `start`.
+    pub async fn serve_fixture_from(&self, measurement_type: MeasurementType, start: &str, name: &str) {
+        Mock::given(method("GET"))
+            .and(path(METER_DATA_PATH))
+            .and(query_param("measurementType", measurement_type.code().to_string()))
+            .and(query_param("start", start))
+            .respond_with(json_response(StatusCode::OK, fixture(name)))
+            .mount(&self.server)
+            .await;
+    }
+
+    /// Serves the result for requests of the measurement type starting at `start`, e.g. one
+    /// window of a split request.
+    pub async fn serve_result_from(&self, measurement_type: MeasurementType, start: &str, result: &ConsumptionsResult) {
+        let body = serde_json::to_string(result).expect("Failed to serialize the result");
+        Mock::given(method("GET"))
+            .and(path(METER_DATA_PATH))
+            .and(query_param("measurementType", measurement_type.code().to_string()))
+            .and(query_param("start", start))
+            .respond_with(json_response(StatusCode::OK, body))
+            .mount(&self.server)
+            .await;
+    }
+
+    /// Replays the fixture for every request for the metering point, as if it had been recorded
+    /// from that metering point.
+    pub async fn serve_fixture_for_point(&self, metering_point_code: &str, name: &str) {
+        let mut body: serde_json::Value = serde_json::from_str(&fixture(name)).expect("Invalid fixture");
+        body["getconsumptionsresult"]["consumptiondata"]["meteringpointcode"] = metering_point_code.into();
+
+        Mock::given(method("GET"))
+            .and(path(METER_DATA_PATH))
+            .and(query_param("meteringPointCode", metering_point_code))
+            .respond_with(json_response(StatusCode::OK, body.to_string()))
+            .mount(&self.server)
+            .await;
+    }
+
+    /// Fails the next `times` `meterdata2` requests with the status.
+    pub async fn fail_next(&self, status: StatusCode, times: u64) {
+        Mock::given(method("GET"))
+            .and(path(METER_DATA_PATH))
+            .respond_with(json_response(status, format!(r#"{{"message":"{}"}}"#, status)))
+            .up_to_n_times(times)
+            .with_priority(PRIORITY_OVERRIDE)
+            .mount(&self.server)
+            .await;
+    }
+
+    /// Delays the next `times` `meterdata2` requests, e.g. past the client timeout.
+    pub async fn delay_next(&self, delay: Duration, times: u64) {
+        Mock::given(method("GET"))
+            .and(path(METER_DATA_PATH))
+            .respond_with(ResponseTemplate::new(StatusCode::OK.as_u16()).set_delay(delay))
+            .up_to_n_times(times)
+            .with_priority(PRIORITY_OVERRIDE)
+            .mount(&self.server)
+            .await;
+    }
+
+    /// Rejects every `meterdata2` request with 401 as if the access token had expired.
+    pub async fn reject_tokens(&self) {
+        Mock::given(method("GET"))
+            .and(path(METER_DATA_PATH))
+            .respond_with(ResponseTemplate::new(StatusCode::UNAUTHORIZED.as_u16()))
+            .with_priority(PRIORITY_OVERRIDE)
+            .mount(&self.server)
+            .await;
+    }
+
+    /// Hands out the access token to any username and password.
+    pub async fn serve_token(&self, access_token: &str) {
+        let body = serde_json::json!({ "status": "OK", "message": null, "accessToken": access_token });
+        Mock::given(method("POST"))
+            .and(path(TOKEN_PATH))
+            .respond_with(json_response(StatusCode::OK, body.to_string()))
+            .mount(&self.server)
+            .await;
+    }
+
+    /// Fails the next `times` token requests with the status.
+    pub async fn fail_next_token(&self, status: StatusCode, times: u64) {
+        Mock::given(method("POST"))
+            .and(path(TOKEN_PATH))
+            .respond_with(json_response(status, format!(r#"{{"status":"{}"}}"#, status)))
+            .up_to_n_times(times)
+            .with_priority(PRIORITY_OVERRIDE)
+            .mount(&self.server)
+            .await;
+    }
+
+    /// `meterdata2` requests received so far.
+    pub async fn meter_data_requests(&self) -> Vec<Request> {
+        self.requests_to(METER_DATA_PATH).await
+    }
+
+    pub async fn token_requests(&self) -> Vec<Request> {
+        self.requests_to(TOKEN_PATH).await
+    }
+
+    async fn requests_to(&self, request_path: &str) -> Vec<Request> {
+        self.server
+            .received_requests()
+            .await
+            .unwrap_or_default()
+            .into_iter()
+            .filter(|request| request.url.path() == request_path)
+            .collect()
+    }
+}
+
+fn json_response(status: StatusCode, body: String) -> ResponseTemplate {
+    ResponseTemplate::new(status.as_u16()).set_body_raw(body, "application/json")
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Duration as ChronoDuration;
+
+    use super::*;
+    use crate::chunk::{split_range, RequestWindow};
+    use crate::error::ApiError;
+    use crate::get_timezone;
+    use crate::models::{ConsumptionData, GetConsumptionsResult, ResolutionDuration, SpotData, Sum, TimeRange, TimeSeries, Values, TSV};
+    use crate::time::LocalDateTime;
+    use crate::validation;
+
+    const TOKEN: &str = "mock-token";
+
+    async fn fetch(mock: &MockWattiVahti, start: &str, stop: &str, resolution: ResolutionDuration) -> Result<ConsumptionsResult, ApiError> {
+        let range = TimeRange::parse(start, stop).unwrap();
+        mock.client()
+            .get_meter_data(TOKEN, "1337", MeasurementType::Consumption, &range, &resolution)
+            .await
+    }
+
+    #[tokio::test]
+    async fn test_replay_fixture() {
+        let mock = MockWattiVahti::start().await;
+        mock.serve_fixture(MeasurementType::Consumption, "consumption_2022-08-01_PT1H.json").await;
+
+        let data = fetch(&mock, "2022-08-01T00:00:00", "2022-08-02T00:00:00", ResolutionDuration::PT1H).await.unwrap();
+        assert_eq!(data.getconsumptionsresult.consumptiondata.timeseries.values.tsv.len(), 24);
+        assert!(data.getconsumptionsresult.spotdata.is_some());
+        assert!(validation::validate(&data).is_ok());
+
+        let requests = mock.meter_data_requests().await;
+        assert_eq!(requests.len(), 1);
+        let query = requests[0].url.query().unwrap();
+        assert!(query.contains("measurementType=1"), "{}", query);
+        assert!(query.contains("resultStep=PT1H"), "{}", query);
+        assert_eq!(requests[0].headers.get(&"authorization".into()).unwrap().as_str(), "Bearer mock-token");
+    }
+
+    #[tokio::test]
+    async fn test_replay_dst_days() {
+        let mock = MockWattiVahti::start().await;
+        mock.serve_fixture_from(MeasurementType::Consumption, "2023-03-26T00:00:00", "consumption_2023-03-26_PT1H.json").await;
+        mock.serve_fixture_from(MeasurementType::Consumption, "2023-10-29T00:00:00", "consumption_2023-10-29_PT15M.json").await;
+
+        let spring = fetch(&mock, "2023-03-26T00:00:00", "2023-03-27T00:00:00", ResolutionDuration::PT1H).await.unwrap();
+        let report = validation::validate(&spring);
+        assert_eq!(report.expected_intervals, 23);
+        assert!(report.is_ok(), "{:?}", report);
+        // The fixture is labeled like WattiVahti labels the day, with 03:00 and without 06:00
+        assert_eq!(report.mislabeled.len(), 3);
+
+        let autumn = fetch(&mock, "2023-10-29T00:00:00", "2023-10-30T00:00:00", ResolutionDuration::PT15M).await.unwrap();
+        let report = validation::validate(&autumn);
+        assert_eq!(report.expected_intervals, 100);
+        assert!(report.is_ok(), "{:?}", report);
+
+        let tsv = &autumn.getconsumptionsresult.consumptiondata.timeseries.values.tsv;
+        let first = tsv[0].get_timestamp_utc_calculated(0, &ResolutionDuration::PT15M).unwrap();
+        let last = tsv[99].get_timestamp_utc_calculated(99, &ResolutionDuration::PT15M).unwrap();
+        assert_eq!(last - first, ChronoDuration::minutes(99 * 15));
+    }
+
+    /// Result of the window with the global interval index as the quantity of each value,
+    /// leaving out the values at the `missing` positions of the window.
+    fn window_result(window: &TimeRange, first_index: usize, missing: &[usize], spot: bool) -> ConsumptionsResult {
+        let tz = get_timezone();
+        let resolution = ResolutionDuration::PT15M;
+        let start = LocalDateTime::from(window.start);
+        let stop = LocalDateTime::from(window.stop);
+        let expected = resolution.count_intervals(start, stop, &tz);
+        let series = || TimeSeries {
+            start,
+            stop,
+            resolution,
+            values: Values {
+                tsv: (0..expected)
+                    .filter(|position| !missing.contains(position))
+                    .map(|position| TSV {
+                        quantity: Some((first_index + position) as f32),
+                        time: LocalDateTime::from_utc_in(&resolution.step_from(start, position, &tz), &tz),
+                        start: Some(start),
+                        stop: Some(stop),
+                        unit: Some("kWh".to_string()),
+                    })
+                    .collect(),
+            },
+        };
+        let sum = |unit: &str| Sum { quantity: 1.0, start, stop, unit: unit.to_string() };
+
+        ConsumptionsResult {
+            getconsumptionsresult: GetConsumptionsResult {
+                consumptiondata: ConsumptionData { meteringpointcode: "1337".to_string(), sum: sum("kWh"), timeseries: series() },
+                spotdata: if spot { Some(SpotData { sum: sum("EUR/MWh"), timeseries: series() }) } else { None },
+            },
+        }
+    }
+
+    #[tokio::test]
+    async fn test_split_request_aligns_windows() {
+        let mock = MockWattiVahti::start().await;
+        let range = TimeRange::parse("2023-10-20T00:00:00", "2023-11-04T00:00:00").unwrap();
+        let windows = split_range(&range, RequestWindow::for_resolution(&ResolutionDuration::PT15M));
+        assert_eq!(windows.len(), 3);
+        assert_eq!(windows[1].to_string(), "2023-10-27T00:00:00 - 2023-11-03T00:00:00");
+
+        // The middle window spans the end of DST, misses two values and has no spot data
+        let tz = get_timezone();
+        let mut first_index = 0;
+        for (index, window) in windows.iter().enumerate() {
+            let missing: &[usize] = if index == 1 { &[10, 11] } else { &[] };
+            let result = window_result(window, first_index, missing, index != 1);
+            mock.serve_result_from(MeasurementType::Consumption, &window.start_str(), &result).await;
+            first_index += ResolutionDuration::PT15M.count_intervals(window.start.into(), window.stop.into(), &tz);
+        }
+
+        let data = mock
+            .client()
+            .get_meter_data(TOKEN, "1337", MeasurementType::Consumption, &range, &ResolutionDuration::PT15M)
+            .await
+            .unwrap();
+        assert_eq!(mock.meter_data_requests().await.len(), 3);
+
+        // 15 days of which one is 25 hours long
+        let tsv = &data.getconsumptionsresult.consumptiondata.timeseries.values.tsv;
+        assert_eq!(tsv.len(), first_index);
+        assert_eq!(tsv.len(), (15 * 24 + 1) * 4);
+        let gap = ResolutionDuration::PT15M.count_intervals(windows[0].start.into(), windows[0].stop.into(), &tz);
+        let start = LocalDateTime::from(range.start).to_utc_in(&tz);
+        for (position, value) in tsv.iter().enumerate() {
+            let time = value.get_timestamp_utc_calculated(position, &ResolutionDuration::PT15M).unwrap();
+            assert_eq!(time, start + ChronoDuration::minutes(15 * position as i64));
+            if position == gap + 10 || position == gap + 11 {
+                assert_eq!(value.quantity, None, "{}", position);
+            } else {
+                assert_eq!(value.quantity, Some(position as f32), "{}", position);
+            }
+        }
+
+        let spot = data.getconsumptionsresult.spotdata.unwrap();
+        assert_eq!(spot.timeseries.values.tsv.len(), tsv.len());
+        let missing_prices = spot.timeseries.values.tsv.iter().filter(|tsv| tsv.quantity.is_none()).count();
+        assert_eq!(missing_prices, ResolutionDuration::PT15M.count_intervals(windows[1].start.into(), windows[1].stop.into(), &tz));
+    }
+
+    #[tokio::test]
+    async fn test_unauthorized_is_not_retried() {
+        let mock = MockWattiVahti::start().await;
+        mock.serve_fixture(MeasurementType::Consumption, "consumption_2022-08-01_PT1H.json").await;
+        mock.reject_tokens().await;
+
+        let err = fetch(&mock, "2022-08-01T00:00:00", "2022-08-02T00:00:00", ResolutionDuration::PT1H).await.unwrap_err();
+        assert!(err.is_unauthorized());
+        assert_eq!(mock.meter_data_requests().await.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_server_errors_are_retried() {
+        let mock = MockWattiVahti::start().await;
+        mock.serve_fixture(MeasurementType::Consumption, "consumption_2022-08-01_PT1H.json").await;
+        mock.fail_next(StatusCode::INTERNAL_SERVER_ERROR, 2).await;
+
+        let data = fetch(&mock, "2022-08-01T00:00:00", "2022-08-02T00:00:00", ResolutionDuration::PT1H).await.unwrap();
+        assert_eq!(data.getconsumptionsresult.consumptiondata.timeseries.values.tsv.len(), 24);
+        assert_eq!(mock.meter_data_requests().await.len(), 3);
+
+        mock.fail_next(StatusCode::INTERNAL_SERVER_ERROR, 3).await;
+        let err = fetch(&mock, "2022-08-01T00:00:00", "2022-08-02T00:00:00", ResolutionDuration::PT1H).await.unwrap_err();
+        assert_eq!(err.status(), Some(StatusCode::INTERNAL_SERVER_ERROR));
+    }
+
+    #[tokio::test]
+    async fn test_slow_responses_time_out() {
+        let mock = MockWattiVahti::start().await;
+        mock.serve_fixture(MeasurementType::Consumption, "consumption_2022-08-01_PT1H.json").await;
+        mock.delay_next(Duration::from_millis(500), 1).await;
+
+        let range = TimeRange::parse("2022-08-01T00:00:00", "2022-08-02T00:00:00").unwrap();
+        let client = mock.client_builder().timeout(Duration::from_millis(100)).build().unwrap();
+
+        // The first attempt times out and the retry gets the fixture
+        let data = client
+            .get_meter_data(TOKEN, "1337", MeasurementType::Consumption, &range, &ResolutionDuration::PT1H)
+            .await
+            .unwrap();
+        assert_eq!(data.getconsumptionsresult.consumptiondata.timeseries.values.tsv.len(), 24);
+
+        mock.delay_next(Duration::from_millis(500), 1).await;
+        let err = client
+            .with_retry_policy(RetryPolicy::no_retry())
+            .get_meter_data(TOKEN, "1337", MeasurementType::Consumption, &range, &ResolutionDuration::PT1H)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, ApiError::Transport(ref err) if err.is_timeout()), "{:?}", err);
+    }
+
+    #[tokio::test]
+    #[ignore = "requires ACCESS_TOKEN and network access"]
+    async fn test_record_fixture() {
+        dotenv::dotenv().ok();
+
+        let access_token = dotenv::var("ACCESS_TOKEN").unwrap();
+        let metering_point_code = dotenv::var("CONSUMPTION_METERING_POINT_CODE").unwrap();
+        let start = dotenv::var("START").unwrap();
+        let stop = dotenv::var("STOP").unwrap();
+
+        let data = WattiVahtiClient::default()
+            .get_consumption_data(&access_token, &metering_point_code, &start, &stop, "PT1H")
+            .await
+            .unwrap();
+        let path = record_fixture(&format!("recorded_consumption_{}_PT1H.json", &start[..10]), &data).unwrap();
+        info!("Recorded {}", path.display());
+    }
+}
@@ -1,9 +1,9 @@
-use chrono::Duration as ChronoDuration;
-use chrono::TimeZone;
 use chrono::{DateTime, NaiveDateTime, Utc};
 use serde::{Deserialize, Serialize};
 
 use crate::get_timezone;
+pub use crate::resolution::{ParseResolutionError, ResolutionDuration};
+use crate::time::LocalDateTime;
 
 #[derive(Debug, Serialize, Deserialize)]
 #[serde(rename_all = "lowercase")]
@@ -37,86 +37,20 @@ pub struct SpotData {
 #[serde(rename_all = "lowercase")]
 pub struct Sum {
     pub quantity: f32,
-    pub start: String,
-    pub stop: String,
+    pub start: LocalDateTime,
+    pub stop: LocalDateTime,
     pub unit: String,
 }
 
-impl Sum {
-    pub fn get_start_utc(&self) -> Option<DateTime<Utc>> {
-        let naive_time = NaiveDateTime::parse_from_str(&self.start, "%Y-%m-%dT%H:%M:%S");
-        if naive_time.is_err() {
-            return None;
-        }
-
-        Some(
-            Utc.from_utc_datetime(
-                &get_timezone()
-                    .from_local_datetime(&naive_time.unwrap())
-                    .unwrap()
-                    .naive_utc(),
-            ),
-        )
-    }
-    pub fn get_stop_utc(&self) -> Option<DateTime<Utc>> {
-        let naive_time = NaiveDateTime::parse_from_str(&self.stop, "%Y-%m-%dT%H:%M:%S");
-        if naive_time.is_err() {
-            return None;
-        }
-
-        Some(
-            Utc.from_utc_datetime(
-                &get_timezone()
-                    .from_local_datetime(&naive_time.unwrap())
-                    .unwrap()
-                    .naive_utc(),
-            ),
-        )
-    }
-}
-
 #[derive(Debug, Serialize, Deserialize)]
 #[serde(rename_all = "lowercase")]
 pub struct TimeSeries {
-    pub start: String,
-    pub stop: String,
-    pub resolution: String,
+    pub start: LocalDateTime,
+    pub stop: LocalDateTime,
+    pub resolution: ResolutionDuration,
     pub values: Values,
 }
 
-impl TimeSeries {
-    pub fn get_start_utc(&self) -> Option<DateTime<Utc>> {
-        let naive_time = NaiveDateTime::parse_from_str(&self.start, "%Y-%m-%dT%H:%M:%S");
-        if naive_time.is_err() {
-            return None;
-        }
-
-        Some(
-            Utc.from_utc_datetime(
-                &get_timezone()
-                    .from_local_datetime(&naive_time.unwrap())
-                    .unwrap()
-                    .naive_utc(),
-            ),
-        )
-    }
-    pub fn get_stop_utc(&self) -> Option<DateTime<Utc>> {
-        let naive_time = NaiveDateTime::parse_from_str(&self.stop, "%Y-%m-%dT%H:%M:%S");
-        if naive_time.is_err() {
-            return None;
-        }
-
-        Some(
-            Utc.from_utc_datetime(
-                &get_timezone()
-                    .from_local_datetime(&naive_time.unwrap())
-                    .unwrap()
-                    .naive_utc(),
-            ),
-        )
-    }
-}
-
 #[derive(Debug, Serialize, Deserialize)]
 #[serde(rename_all = "lowercase")]
 pub struct Values {
@@ -127,125 +61,155 @@ pub struct Values {
 #[serde(rename_all = "lowercase")]
 pub struct TSV {
     pub quantity: Option<f32>,
-    pub time: String,
-    pub start: Option<String>,
-    pub stop: Option<String>,
+    pub time: LocalDateTime,
+    pub start: Option<LocalDateTime>,
+    pub stop: Option<LocalDateTime>,
     pub unit: Option<String>,
 }
 
-#[derive(PartialEq, Eq)]
-pub enum ResolutionDuration {
-    PT1H,
-    PT15M,
+/// Whether the metering point measures consumption or production, other types are kept as is.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum MeteringPointType {
+    Consumption,
+    Production,
+    Other(String),
 }
 
-impl ResolutionDuration {
-    pub fn from_str(s: &str) -> ResolutionDuration {
-        match s {
-            "PT1H" => ResolutionDuration::PT1H,
-            "PT15M" | "PT15MIN" => ResolutionDuration::PT15M,
-            _ => ResolutionDuration::PT1H,
+impl MeteringPointType {
+    /// Measurement type to request from `meterdata2` for the metering point.
+    pub fn measurement_type(&self) -> Option<MeasurementType> {
+        match self {
+            MeteringPointType::Consumption => Some(MeasurementType::Consumption),
+            MeteringPointType::Production => Some(MeasurementType::Production),
+            MeteringPointType::Other(_) => None,
+        }
+    }
+}
+
+impl From<&str> for MeteringPointType {
+    fn from(kind: &str) -> Self {
+        match kind.to_ascii_lowercase().as_str() {
+            "consumption" | "kulutus" => MeteringPointType::Consumption,
+            "production" | "tuotanto" => MeteringPointType::Production,
+            _ => MeteringPointType::Other(kind.to_string()),
         }
     }
+}
 
-    pub fn to_str(&self) -> &str {
+impl std::fmt::Display for MeteringPointType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
-            ResolutionDuration::PT1H => "PT1H",
-            ResolutionDuration::PT15M => "PT15M",
+            MeteringPointType::Consumption => write!(f, "consumption"),
+            MeteringPointType::Production => write!(f, "production"),
+            MeteringPointType::Other(kind) => write!(f, "{}", kind),
         }
     }
 }
 
-impl TSV {
-    #[deprecated(
-        note = "Unreliable since WattiVahti provides incorrect timestamps. Calculate correct one with `get_timestamp_utc_calculated` instead"
-    )]
-    pub fn get_timestamp_utc(&self) -> Option<DateTime<Utc>> {
-        let naive_time = NaiveDateTime::parse_from_str(&self.time, "%Y-%m-%dT%H:%M:%S");
-        if naive_time.is_err() {
-            return None;
+impl Serialize for MeteringPointType {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for MeteringPointType {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let kind = String::deserialize(deserializer)?;
+        Ok(MeteringPointType::from(kind.as_str()))
+    }
+}
+
+/// Measurement type requested from the `meterdata2` endpoint.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum MeasurementType {
+    /// Consumed active energy (`measurementType=1`)
+    Consumption,
+    /// Produced active energy (`measurementType=6`)
+    Production,
+    /// Any other measurement type offered by the endpoint (e.g. reactive energy) by its numeric code
+    Other(i32),
+}
+
+impl MeasurementType {
+    pub fn code(&self) -> i32 {
+        match self {
+            MeasurementType::Consumption => 1,
+            MeasurementType::Production => 6,
+            MeasurementType::Other(code) => *code,
         }
-        debug!("System Time UTC {}", naive_time.unwrap());
-
-        Some(
-            Utc.from_utc_datetime(
-                &get_timezone()
-                    .from_local_datetime(&naive_time.unwrap())
-                    .unwrap()
-                    .naive_utc(),
-            ),
-        )
     }
 
-    pub fn get_timestamp_utc_calculated(&self, index: usize, resolution: &ResolutionDuration) -> Option<DateTime<Utc>> {
-        if self.start.is_none() {
-            return None;
+    pub fn is_production(&self) -> bool {
+        matches!(self, MeasurementType::Production)
+    }
+}
+
+impl From<i32> for MeasurementType {
+    fn from(code: i32) -> Self {
+        match code {
+            1 => MeasurementType::Consumption,
+            6 => MeasurementType::Production,
+            _ => MeasurementType::Other(code),
         }
-        let naive_time =
-            NaiveDateTime::parse_from_str(&self.start.as_ref().unwrap(), "%Y-%m-%dT%H:%M:%S");
-        if naive_time.is_err() {
-            return None;
+    }
+}
+
+impl std::fmt::Display for MeasurementType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MeasurementType::Consumption => write!(f, "consumption"),
+            MeasurementType::Production => write!(f, "production"),
+            MeasurementType::Other(code) => write!(f, "measurement type {}", code),
         }
-        debug!("Time Local {}", naive_time.unwrap());
+    }
+}
 
-        let result = Utc.from_utc_datetime(
-            &get_timezone()
-                .from_local_datetime(&naive_time.unwrap())
-                .unwrap()
-                .naive_utc(),
-        );
+pub const TIME_FORMAT: &str = "%Y-%m-%dT%H:%M:%S";
 
-        debug!("Time UTC {}", result);
+/// `[start, stop)` range in WattiVahti local time.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TimeRange {
+    pub start: NaiveDateTime,
+    pub stop: NaiveDateTime,
+}
 
-        let result = match resolution {
-            ResolutionDuration::PT1H => result + ChronoDuration::hours(index as i64),
-            ResolutionDuration::PT15M => result + ChronoDuration::minutes(index as i64 * 15),
-        };
+impl TimeRange {
+    pub fn new(start: NaiveDateTime, stop: NaiveDateTime) -> Self {
+        Self { start, stop }
+    }
 
-        debug!("Time position UTC {}", result);
+    /// Parses a range from `%Y-%m-%dT%H:%M:%S` formatted local times.
+    pub fn parse(start: &str, stop: &str) -> Result<Self, chrono::ParseError> {
+        Ok(Self {
+            start: NaiveDateTime::parse_from_str(start, TIME_FORMAT)?,
+            stop: NaiveDateTime::parse_from_str(stop, TIME_FORMAT)?,
+        })
+    }
 
-        Some(result)
+    pub fn start_str(&self) -> String {
+        self.start.format(TIME_FORMAT).to_string()
     }
 
-    pub fn get_start_utc(&self) -> Option<DateTime<Utc>> {
-        if self.start.is_none() {
-            return None;
-        }
-        let naive_time =
-            NaiveDateTime::parse_from_str(&self.start.as_ref().unwrap(), "%Y-%m-%dT%H:%M:%S");
-        if naive_time.is_err() {
-            return None;
-        }
-        debug!("System Time UTC {}", naive_time.unwrap());
-
-        Some(
-            Utc.from_utc_datetime(
-                &get_timezone()
-                    .from_local_datetime(&naive_time.unwrap())
-                    .unwrap()
-                    .naive_utc(),
-            ),
-        )
+    pub fn stop_str(&self) -> String {
+        self.stop.format(TIME_FORMAT).to_string()
     }
+}
 
-    pub fn get_stop_utc(&self) -> Option<DateTime<Utc>> {
-        if self.stop.is_none() {
-            return None;
-        }
-        let naive_time =
-            NaiveDateTime::parse_from_str(&self.stop.as_ref().unwrap(), "%Y-%m-%dT%H:%M:%S");
-        if naive_time.is_err() {
-            return None;
-        }
-        debug!("System Time UTC {}", naive_time.unwrap());
-
-        Some(
-            Utc.from_utc_datetime(
-                &get_timezone()
-                    .from_local_datetime(&naive_time.unwrap())
-                    .unwrap()
-                    .naive_utc(),
-            ),
-        )
+impl std::fmt::Display for TimeRange {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} - {}", self.start_str(), self.stop_str())
+    }
+}
+
+impl TSV {
+    /// Timestamp of the value at `index` in its series. The `time` field can't be trusted around
+    /// DST transitions, so the timestamp is calculated from the start of the series instead.
+    pub fn get_timestamp_utc_calculated(&self, index: usize, resolution: &ResolutionDuration) -> Option<DateTime<Utc>> {
+        let start = self.start?;
+        let result = resolution.step_from(start, index, &get_timezone());
+
+        debug!("Time position UTC {}", result);
+
+        Some(result)
     }
 }
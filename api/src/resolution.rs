@@ -0,0 +1,310 @@
+//! ISO-8601 durations used as the resolution of a time series.
+
+use std::fmt;
+use std::str::FromStr;
+
+use chrono::{DateTime, Datelike, Duration as ChronoDuration, NaiveDate, NaiveDateTime, Utc};
+use chrono_tz::Tz;
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use thiserror::Error;
+
+use crate::time::LocalDateTime;
+
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+#[error("Invalid ISO-8601 resolution '{0}'")]
+pub struct ParseResolutionError(pub String);
+
+/// Resolution of a time series as an ISO-8601 duration, e.g. `PT15M`, `PT1H`, `P1D` or `P1M`.
+///
+/// Month and day steps are calendar steps in local time, so a `P1D` step is 23 or 25 hours long
+/// on DST transition days. Hour and minute steps are fixed lengths of time.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ResolutionDuration {
+    months: u32,
+    days: u32,
+    minutes: u32,
+}
+
+impl ResolutionDuration {
+    pub const PT15M: ResolutionDuration = ResolutionDuration::from_minutes(15);
+    pub const PT30M: ResolutionDuration = ResolutionDuration::from_minutes(30);
+    pub const PT1H: ResolutionDuration = ResolutionDuration::from_minutes(60);
+    pub const P1D: ResolutionDuration = ResolutionDuration { months: 0, days: 1, minutes: 0 };
+    pub const P1M: ResolutionDuration = ResolutionDuration { months: 1, days: 0, minutes: 0 };
+
+    pub const fn from_minutes(minutes: u32) -> Self {
+        Self { months: 0, days: 0, minutes }
+    }
+
+    pub fn months(&self) -> u32 {
+        self.months
+    }
+
+    pub fn days(&self) -> u32 {
+        self.days
+    }
+
+    pub fn minutes(&self) -> u32 {
+        self.minutes
+    }
+
+    /// `true` when every step is the same length of time, i.e. there are no day or month parts.
+    pub fn is_fixed(&self) -> bool {
+        self.months == 0 && self.days == 0
+    }
+
+    /// Value for the `resultStep` query parameter
+    pub fn to_query_string(&self) -> String {
+        // WattiVahti calls the quarter hour resolution PT15MIN
+        if *self == ResolutionDuration::PT15M {
+            return "PT15MIN".to_string();
+        }
+        self.to_string()
+    }
+
+    /// Start of the interval at `index` in a series starting at `start`.
+    pub fn step_from(&self, start: LocalDateTime, index: usize, tz: &Tz) -> DateTime<Utc> {
+        let index = index as i64;
+        let start = if self.is_fixed() {
+            start
+        } else {
+            let local = add_months(start.naive(), self.months as i64 * index)
+                + ChronoDuration::days(self.days as i64 * index);
+            LocalDateTime::new(local)
+        };
+
+        start.to_utc_in(tz) + ChronoDuration::minutes(self.minutes as i64 * index)
+    }
+
+    /// Number of intervals in `[start, stop)`.
+    pub fn count_intervals(&self, start: LocalDateTime, stop: LocalDateTime, tz: &Tz) -> usize {
+        let start_utc = start.to_utc_in(tz);
+        let stop_utc = stop.to_utc_in(tz);
+        if stop_utc <= start_utc {
+            return 0;
+        }
+
+        if self.is_fixed() {
+            let minutes = (stop_utc - start_utc).num_minutes();
+            return ((minutes + self.minutes as i64 - 1) / self.minutes as i64) as usize;
+        }
+
+        let mut count = 0;
+        while self.step_from(start, count, tz) < stop_utc {
+            count += 1;
+        }
+        count
+    }
+}
+
+/// Adds calendar months, clamping the day to the end of a shorter month.
+pub(crate) fn add_months(time: NaiveDateTime, months: i64) -> NaiveDateTime {
+    let total = time.year() as i64 * 12 + time.month0() as i64 + months;
+    let year = total.div_euclid(12) as i32;
+    let month = total.rem_euclid(12) as u32 + 1;
+
+    let mut day = time.day();
+    while NaiveDate::from_ymd_opt(year, month, day).is_none() {
+        day -= 1;
+    }
+    NaiveDate::from_ymd(year, month, day).and_time(time.time())
+}
+
+impl FromStr for ResolutionDuration {
+    type Err = ParseResolutionError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let error = || ParseResolutionError(s.to_string());
+
+        // WattiVahti calls the quarter hour resolution PT15MIN
+        let value = s.trim().to_ascii_uppercase();
+        let value = match value.as_str() {
+            "PT15MIN" => "PT15M",
+            value => value,
+        };
+
+        let rest = value.strip_prefix('P').ok_or_else(error)?;
+        let (date_part, time_part) = match rest.split_once('T') {
+            Some((_, "")) => return Err(error()),
+            Some((date_part, time_part)) => (date_part, Some(time_part)),
+            None => (rest, None),
+        };
+
+        // Amounts too large for the fields are as invalid as unknown designators
+        let add = |total: u32, amount: u32, factor: u32| amount.checked_mul(factor).and_then(|amount| total.checked_add(amount)).ok_or_else(error);
+
+        let mut resolution = ResolutionDuration { months: 0, days: 0, minutes: 0 };
+        for (amount, designator) in parse_components(date_part).ok_or_else(error)? {
+            match designator {
+                'Y' => resolution.months = add(resolution.months, amount, 12)?,
+                'M' => resolution.months = add(resolution.months, amount, 1)?,
+                'W' => resolution.days = add(resolution.days, amount, 7)?,
+                'D' => resolution.days = add(resolution.days, amount, 1)?,
+                _ => return Err(error()),
+            }
+        }
+        let mut seconds = 0;
+        for (amount, designator) in parse_components(time_part.unwrap_or("")).ok_or_else(error)? {
+            match designator {
+                'H' => seconds = add(seconds, amount, 3600)?,
+                'M' => seconds = add(seconds, amount, 60)?,
+                'S' => seconds = add(seconds, amount, 1)?,
+                _ => return Err(error()),
+            }
+        }
+
+        // Sub-minute resolutions aren't used for metering
+        if !seconds.is_multiple_of(60) {
+            return Err(error());
+        }
+        resolution.minutes = seconds / 60;
+
+        if resolution == (ResolutionDuration { months: 0, days: 0, minutes: 0 }) {
+            return Err(error());
+        }
+
+        Ok(resolution)
+    }
+}
+
+/// Splits e.g. `1Y2M` into `[(1, 'Y'), (2, 'M')]`.
+fn parse_components(s: &str) -> Option<Vec<(u32, char)>> {
+    let mut components = Vec::new();
+    let mut amount = String::new();
+
+    for c in s.chars() {
+        if c.is_ascii_digit() {
+            amount.push(c);
+        } else {
+            components.push((amount.parse().ok()?, c));
+            amount.clear();
+        }
+    }
+
+    if !amount.is_empty() {
+        return None;
+    }
+    Some(components)
+}
+
+impl fmt::Display for ResolutionDuration {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "P")?;
+        if self.months > 0 {
+            if self.months.is_multiple_of(12) {
+                write!(f, "{}Y", self.months / 12)?;
+            } else {
+                write!(f, "{}M", self.months)?;
+            }
+        }
+        if self.days > 0 {
+            write!(f, "{}D", self.days)?;
+        }
+        if self.minutes > 0 {
+            write!(f, "T")?;
+            if self.minutes >= 60 {
+                write!(f, "{}H", self.minutes / 60)?;
+            }
+            if !self.minutes.is_multiple_of(60) {
+                write!(f, "{}M", self.minutes % 60)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Serialize for ResolutionDuration {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for ResolutionDuration {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(de::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HELSINKI: Tz = chrono_tz::Europe::Helsinki;
+
+    fn local(s: &str) -> LocalDateTime {
+        s.parse().unwrap()
+    }
+
+    fn utc(s: &str) -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339(s).unwrap().with_timezone(&Utc)
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!("PT1H".parse::<ResolutionDuration>().unwrap(), ResolutionDuration::PT1H);
+        assert_eq!("PT60M".parse::<ResolutionDuration>().unwrap(), ResolutionDuration::PT1H);
+        assert_eq!("PT15M".parse::<ResolutionDuration>().unwrap(), ResolutionDuration::PT15M);
+        assert_eq!("PT15MIN".parse::<ResolutionDuration>().unwrap(), ResolutionDuration::PT15M);
+        assert_eq!("PT30M".parse::<ResolutionDuration>().unwrap(), ResolutionDuration::PT30M);
+        assert_eq!("P1D".parse::<ResolutionDuration>().unwrap(), ResolutionDuration::P1D);
+        assert_eq!("P1M".parse::<ResolutionDuration>().unwrap(), ResolutionDuration::P1M);
+        assert_eq!("P1W".parse::<ResolutionDuration>().unwrap().days(), 7);
+        assert_eq!("P1Y".parse::<ResolutionDuration>().unwrap().months(), 12);
+
+        for invalid in ["", "P", "PT", "1H", "PT1X", "PT30S", "P0D", "PTH", "PT1H2"] {
+            assert!(invalid.parse::<ResolutionDuration>().is_err(), "{}", invalid);
+        }
+        // Too large for the fields
+        for overflowing in ["PT2000000H", "P400000000Y", "P700000000W", "P4294967295D1D", "PT99999999999M"] {
+            assert_eq!(overflowing.parse::<ResolutionDuration>(), Err(ParseResolutionError(overflowing.to_string())));
+        }
+    }
+
+    #[test]
+    fn test_display() {
+        assert_eq!(ResolutionDuration::PT15M.to_string(), "PT15M");
+        assert_eq!(ResolutionDuration::PT1H.to_string(), "PT1H");
+        assert_eq!(ResolutionDuration::P1D.to_string(), "P1D");
+        assert_eq!(ResolutionDuration::P1M.to_string(), "P1M");
+        assert_eq!("PT90M".parse::<ResolutionDuration>().unwrap().to_string(), "PT1H30M");
+        assert_eq!("P12M".parse::<ResolutionDuration>().unwrap().to_string(), "P1Y");
+        assert_eq!(ResolutionDuration::PT15M.to_query_string(), "PT15MIN");
+        assert_eq!(ResolutionDuration::PT1H.to_query_string(), "PT1H");
+    }
+
+    #[test]
+    fn test_step_fixed_over_spring_transition() {
+        // Clocks are turned from 03:00 to 04:00 on 2023-03-26 in Helsinki
+        let start = local("2023-03-26T00:00:00");
+        assert_eq!(ResolutionDuration::PT15M.step_from(start, 12, &HELSINKI), utc("2023-03-26T01:00:00Z"));
+        assert_eq!(ResolutionDuration::PT1H.step_from(start, 3, &HELSINKI), utc("2023-03-26T04:00:00+03:00"));
+        assert_eq!(ResolutionDuration::PT1H.count_intervals(start, local("2023-03-27T00:00:00"), &HELSINKI), 23);
+        assert_eq!(ResolutionDuration::PT15M.count_intervals(start, local("2023-03-27T00:00:00"), &HELSINKI), 92);
+    }
+
+    #[test]
+    fn test_step_fixed_over_autumn_transition() {
+        // Clocks are turned from 04:00 back to 03:00 on 2023-10-29 in Helsinki
+        let start = local("2023-10-29T00:00:00");
+        assert_eq!(ResolutionDuration::PT1H.step_from(start, 3, &HELSINKI), utc("2023-10-29T03:00:00+03:00"));
+        assert_eq!(ResolutionDuration::PT1H.step_from(start, 4, &HELSINKI), utc("2023-10-29T03:00:00+02:00"));
+        assert_eq!(ResolutionDuration::PT1H.count_intervals(start, local("2023-10-30T00:00:00"), &HELSINKI), 25);
+        assert_eq!(ResolutionDuration::PT30M.count_intervals(start, local("2023-10-30T00:00:00"), &HELSINKI), 50);
+    }
+
+    #[test]
+    fn test_step_calendar() {
+        let start = local("2023-03-25T00:00:00");
+        assert_eq!(ResolutionDuration::P1D.step_from(start, 1, &HELSINKI), utc("2023-03-26T00:00:00+02:00"));
+        // 2023-03-26 is only 23 hours long
+        assert_eq!(ResolutionDuration::P1D.step_from(start, 2, &HELSINKI), utc("2023-03-27T00:00:00+03:00"));
+        assert_eq!(ResolutionDuration::P1D.count_intervals(start, local("2023-04-01T00:00:00"), &HELSINKI), 7);
+
+        let start = local("2023-01-31T00:00:00");
+        assert_eq!(ResolutionDuration::P1M.step_from(start, 1, &HELSINKI), utc("2023-02-28T00:00:00+02:00"));
+        let start = local("2023-10-01T00:00:00");
+        assert_eq!(ResolutionDuration::P1M.step_from(start, 1, &HELSINKI), utc("2023-11-01T00:00:00+02:00"));
+        assert_eq!(ResolutionDuration::P1M.count_intervals(local("2023-01-01T00:00:00"), local("2024-01-01T00:00:00"), &HELSINKI), 12);
+    }
+}
@@ -0,0 +1,200 @@
+//! Retrying transient failures with exponential backoff and jitter.
+
+use std::fmt::Display;
+use std::future::Future;
+use std::time::Duration;
+
+use http::StatusCode;
+use rand::Rng;
+
+use crate::error::ApiError;
+
+/// How many times and how long apart a failing call is attempted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RetryPolicy {
+    /// Total number of attempts including the first one, `1` disables retrying.
+    pub max_attempts: u32,
+    /// Upper bound of the delay before the first retry, doubled for each retry after that.
+    pub base_delay: Duration,
+    /// Upper bound of any single delay.
+    pub max_delay: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 3,
+            base_delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(30),
+        }
+    }
+}
+
+impl RetryPolicy {
+    pub fn new(max_attempts: u32, base_delay: Duration, max_delay: Duration) -> Self {
+        Self {
+            max_attempts: max_attempts.max(1),
+            base_delay,
+            max_delay,
+        }
+    }
+
+    /// Policy that makes a single attempt.
+    pub fn no_retry() -> Self {
+        Self::new(1, Duration::ZERO, Duration::ZERO)
+    }
+
+    /// Backoff before the given retry (1-based) with full jitter, i.e. a random delay
+    /// between zero and `min(max_delay, base_delay * 2^(retry - 1))`.
+    pub fn backoff(&self, retry: u32) -> Duration {
+        let exponent = retry.saturating_sub(1).min(31);
+        let ceiling = self
+            .base_delay
+            .checked_mul(1 << exponent)
+            .unwrap_or(self.max_delay)
+            .min(self.max_delay);
+
+        let millis = ceiling.as_millis() as u64;
+        if millis == 0 {
+            return Duration::ZERO;
+        }
+        Duration::from_millis(rand::thread_rng().gen_range(0..=millis))
+    }
+}
+
+/// Errors that can tell whether repeating the same call might succeed.
+pub trait Retryable {
+    fn is_transient(&self) -> bool;
+
+    /// Delay requested by the server, takes precedence over the backoff.
+    fn retry_after(&self) -> Option<Duration> {
+        None
+    }
+}
+
+impl Retryable for ApiError {
+    fn is_transient(&self) -> bool {
+        match self {
+            ApiError::RateLimited { .. } => true,
+            ApiError::Server { status, .. } => is_transient_status(*status),
+            ApiError::Transport(err) => {
+                err.is_timeout() || err.is_connect() || err.status().map(is_transient_status).unwrap_or(false)
+            }
+            _ => false,
+        }
+    }
+
+    fn retry_after(&self) -> Option<Duration> {
+        match self {
+            ApiError::RateLimited { retry_after } => *retry_after,
+            _ => None,
+        }
+    }
+}
+
+pub fn is_transient_status(status: StatusCode) -> bool {
+    status.is_server_error() || status == StatusCode::TOO_MANY_REQUESTS || status == StatusCode::REQUEST_TIMEOUT
+}
+
+/// Runs `call` until it succeeds, fails with a non-transient error or the policy runs out of
+/// attempts. Only use this for idempotent calls.
+pub async fn retry<T, E, F, Fut>(policy: &RetryPolicy, operation: &str, mut call: F) -> Result<T, E>
+where
+    E: Retryable + Display,
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, E>>,
+{
+    let mut attempt = 1;
+    loop {
+        match call().await {
+            Ok(value) => return Ok(value),
+            Err(err) if attempt < policy.max_attempts && err.is_transient() => {
+                let delay = err
+                    .retry_after()
+                    .map(|delay| delay.min(policy.max_delay))
+                    .unwrap_or_else(|| policy.backoff(attempt));
+                warn!(
+                    "{} failed on attempt {}/{}: {}. Retrying in {:?}",
+                    operation, attempt, policy.max_attempts, err, delay
+                );
+                tokio::time::sleep(delay).await;
+                attempt += 1;
+            }
+            Err(err) => {
+                if attempt > 1 {
+                    error!("{} failed on attempt {}/{}: {}", operation, attempt, policy.max_attempts, err);
+                }
+                return Err(err);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    use super::*;
+
+    #[test]
+    fn test_backoff_is_bounded() {
+        let policy = RetryPolicy::new(5, Duration::from_millis(100), Duration::from_millis(300));
+
+        for _ in 0..100 {
+            assert!(policy.backoff(1) <= Duration::from_millis(100));
+            assert!(policy.backoff(2) <= Duration::from_millis(200));
+            assert!(policy.backoff(3) <= Duration::from_millis(300));
+            assert!(policy.backoff(40) <= Duration::from_millis(300));
+        }
+        assert_eq!(RetryPolicy::no_retry().backoff(1), Duration::ZERO);
+    }
+
+    #[test]
+    fn test_api_error_is_transient() {
+        assert!(ApiError::RateLimited { retry_after: None }.is_transient());
+        assert!(ApiError::Server { status: StatusCode::BAD_GATEWAY, body: String::new() }.is_transient());
+        assert!(!ApiError::Server { status: StatusCode::BAD_REQUEST, body: String::new() }.is_transient());
+        assert!(!ApiError::Unauthorized.is_transient());
+    }
+
+    #[tokio::test]
+    async fn test_retry_transient_errors() {
+        let attempts = AtomicU32::new(0);
+        let policy = RetryPolicy::new(3, Duration::from_millis(1), Duration::from_millis(1));
+
+        let result = retry(&policy, "Test call", || async {
+            if attempts.fetch_add(1, Ordering::SeqCst) < 2 {
+                Err(ApiError::Server { status: StatusCode::SERVICE_UNAVAILABLE, body: String::new() })
+            } else {
+                Ok(42)
+            }
+        })
+        .await;
+
+        assert_eq!(result.unwrap(), 42);
+        assert_eq!(attempts.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn test_retry_gives_up() {
+        let attempts = AtomicU32::new(0);
+        let policy = RetryPolicy::new(3, Duration::from_millis(1), Duration::from_millis(1));
+
+        let result: Result<(), ApiError> = retry(&policy, "Test call", || async {
+            attempts.fetch_add(1, Ordering::SeqCst);
+            Err(ApiError::Unauthorized)
+        })
+        .await;
+        assert!(result.unwrap_err().is_unauthorized());
+        assert_eq!(attempts.load(Ordering::SeqCst), 1);
+
+        attempts.store(0, Ordering::SeqCst);
+        let result: Result<(), ApiError> = retry(&policy, "Test call", || async {
+            attempts.fetch_add(1, Ordering::SeqCst);
+            Err(ApiError::RateLimited { retry_after: Some(Duration::from_millis(1)) })
+        })
+        .await;
+        assert!(result.is_err());
+        assert_eq!(attempts.load(Ordering::SeqCst), 3);
+    }
+}
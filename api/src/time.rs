@@ -0,0 +1,134 @@
+//! Local wall clock times used by the WattiVahti API.
+
+use std::fmt;
+use std::str::FromStr;
+
+use chrono::{DateTime, Duration as ChronoDuration, NaiveDateTime, Offset, TimeZone, Utc};
+use chrono_tz::Tz;
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::get_timezone;
+use crate::models::TIME_FORMAT;
+
+/// A `%Y-%m-%dT%H:%M:%S` time without an offset, in the time zone of the metering point.
+///
+/// Converting to UTC never panics, DST transitions are resolved as follows:
+/// - ambiguous times (autumn, the hour is repeated) resolve to the earlier instant, i.e. the one
+///   still in daylight saving time
+/// - nonexistent times (spring, the hour is skipped) are shifted forward by the length of the gap,
+///   so `03:30` on the transition day becomes `04:30` daylight saving time in `Europe/Helsinki`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct LocalDateTime(NaiveDateTime);
+
+impl LocalDateTime {
+    pub fn new(time: NaiveDateTime) -> Self {
+        Self(time)
+    }
+
+    pub fn naive(&self) -> NaiveDateTime {
+        self.0
+    }
+
+    /// Converts to UTC in the configured time zone, see [`get_timezone`].
+    pub fn to_utc(&self) -> DateTime<Utc> {
+        self.to_utc_in(&get_timezone())
+    }
+
+    pub fn to_utc_in(&self, tz: &Tz) -> DateTime<Utc> {
+        if let Some(time) = tz.from_local_datetime(&self.0).earliest() {
+            return time.with_timezone(&Utc);
+        }
+
+        // Inside a DST gap, use the offset in effect before the gap. Gaps are at most a few hours.
+        let offset = tz
+            .offset_from_local_datetime(&(self.0 - ChronoDuration::hours(6)))
+            .earliest()
+            .map(|offset| offset.fix())
+            .unwrap_or_else(|| tz.offset_from_utc_datetime(&self.0).fix());
+        DateTime::<Utc>::from_utc(self.0 - offset, Utc)
+    }
+
+    pub fn from_utc_in(time: &DateTime<Utc>, tz: &Tz) -> Self {
+        Self(time.with_timezone(tz).naive_local())
+    }
+}
+
+impl From<NaiveDateTime> for LocalDateTime {
+    fn from(time: NaiveDateTime) -> Self {
+        Self(time)
+    }
+}
+
+impl FromStr for LocalDateTime {
+    type Err = chrono::ParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        NaiveDateTime::parse_from_str(s, TIME_FORMAT).map(Self)
+    }
+}
+
+impl fmt::Display for LocalDateTime {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0.format(TIME_FORMAT))
+    }
+}
+
+impl Serialize for LocalDateTime {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for LocalDateTime {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(|err| de::Error::custom(format!("invalid time '{}': {}", s, err)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn utc(s: &str) -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339(s).unwrap().with_timezone(&Utc)
+    }
+
+    #[test]
+    fn test_to_utc() {
+        let tz = chrono_tz::Europe::Helsinki;
+        let time: LocalDateTime = "2022-08-01T00:00:00".parse().unwrap();
+        assert_eq!(time.to_utc_in(&tz), utc("2022-07-31T21:00:00Z"));
+        assert_eq!(LocalDateTime::from_utc_in(&time.to_utc_in(&tz), &tz), time);
+
+        let time: LocalDateTime = "2022-01-01T00:00:00".parse().unwrap();
+        assert_eq!(time.to_utc_in(&tz), utc("2021-12-31T22:00:00Z"));
+    }
+
+    #[test]
+    fn test_to_utc_ambiguous() {
+        // 03:00 - 04:00 is repeated when clocks are turned back
+        let tz = chrono_tz::Europe::Helsinki;
+        let time: LocalDateTime = "2022-10-30T03:30:00".parse().unwrap();
+        assert_eq!(time.to_utc_in(&tz), utc("2022-10-30T00:30:00Z"));
+    }
+
+    #[test]
+    fn test_to_utc_nonexistent() {
+        // 03:00 - 04:00 is skipped when clocks are turned forward
+        let tz = chrono_tz::Europe::Helsinki;
+        let time: LocalDateTime = "2022-03-27T03:00:00".parse().unwrap();
+        assert_eq!(time.to_utc_in(&tz), utc("2022-03-27T01:00:00Z"));
+
+        let time: LocalDateTime = "2022-03-27T03:30:00".parse().unwrap();
+        assert_eq!(time.to_utc_in(&tz), utc("2022-03-27T01:30:00Z"));
+        assert_eq!(LocalDateTime::from_utc_in(&time.to_utc_in(&tz), &tz).to_string(), "2022-03-27T04:30:00");
+    }
+
+    #[test]
+    fn test_serde() {
+        let time: LocalDateTime = serde_json::from_str(r#""2023-03-26T02:00:00""#).unwrap();
+        assert_eq!(serde_json::to_string(&time).unwrap(), r#""2023-03-26T02:00:00""#);
+        assert!(serde_json::from_str::<LocalDateTime>(r#""2023-03-26""#).is_err());
+    }
+}
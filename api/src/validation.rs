@@ -0,0 +1,232 @@
+//! Consistency checks for the meter data returned by WattiVahti.
+
+use std::fmt;
+
+use chrono::{DateTime, Utc};
+use chrono_tz::Tz;
+
+use crate::get_timezone;
+use crate::models::{ConsumptionData, ConsumptionsResult, ResolutionDuration};
+use crate::time::LocalDateTime;
+
+/// Smallest difference between the reported sum and the sum of the values that is counted as
+/// drift. The reported sum is rounded, so small differences are expected.
+pub const SUM_TOLERANCE: f32 = 0.01;
+
+/// Result of checking a series against its reported sum and the intervals expected for its range.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ValidationReport {
+    pub metering_point_code: String,
+    pub resolution: ResolutionDuration,
+    /// Number of intervals in the range of the series, 23 or 25 hours on DST transition days
+    pub expected_intervals: usize,
+    pub received_intervals: usize,
+    /// Start of each expected interval without a value
+    pub missing: Vec<DateTime<Utc>>,
+    /// Start of each interval with a value but no quantity
+    pub null_quantities: Vec<DateTime<Utc>>,
+    /// Times of values past the last interval of the range
+    pub unexpected: Vec<LocalDateTime>,
+    /// Times of values that aren't the time of the interval at their position. Informational, as
+    /// WattiVahti mislabels the intervals around DST transitions
+    pub mislabeled: Vec<LocalDateTime>,
+    pub reported_sum: f32,
+    pub calculated_sum: f32,
+    pub unit: String,
+}
+
+impl ValidationReport {
+    /// Reported sum minus the sum of the values.
+    pub fn sum_drift(&self) -> f32 {
+        self.reported_sum - self.calculated_sum
+    }
+
+    pub fn has_sum_drift(&self) -> bool {
+        let tolerance = SUM_TOLERANCE.max(self.reported_sum.abs() * 1e-4);
+        self.sum_drift().abs() > tolerance
+    }
+
+    /// `true` when every interval has exactly one value and the sums agree.
+    pub fn is_ok(&self) -> bool {
+        self.missing.is_empty()
+            && self.null_quantities.is_empty()
+            && self.unexpected.is_empty()
+            && !self.has_sum_drift()
+    }
+}
+
+impl fmt::Display for ValidationReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "metering point {} ({}): {}/{} values, {} missing, {} null, {} unexpected, {} mislabeled, sum drift {:.3} {}",
+            self.metering_point_code,
+            self.resolution,
+            self.received_intervals,
+            self.expected_intervals,
+            self.missing.len(),
+            self.null_quantities.len(),
+            self.unexpected.len(),
+            self.mislabeled.len(),
+            self.sum_drift(),
+            self.unit
+        )
+    }
+}
+
+/// Validates the consumption data of a result in the configured time zone, see [`get_timezone`].
+/// Spot data isn't validated, its sum is an average price.
+pub fn validate(result: &ConsumptionsResult) -> ValidationReport {
+    validate_in(&result.getconsumptionsresult.consumptiondata, &get_timezone())
+}
+
+/// Checks each value against the interval at its position from the start of the series, the
+/// same way the values are timestamped when they are stored.
+///
+/// The `time` of the values isn't reliable around DST transitions, so a value whose `time`
+/// differs from its interval is only listed as mislabeled.
+pub fn validate_in(data: &ConsumptionData, tz: &Tz) -> ValidationReport {
+    let series = &data.timeseries;
+    let resolution = series.resolution;
+    let expected_intervals = resolution.count_intervals(series.start, series.stop, tz);
+
+    let mut report = ValidationReport {
+        metering_point_code: data.meteringpointcode.clone(),
+        resolution,
+        expected_intervals,
+        received_intervals: series.values.tsv.len(),
+        missing: Vec::new(),
+        null_quantities: Vec::new(),
+        unexpected: Vec::new(),
+        mislabeled: Vec::new(),
+        reported_sum: data.sum.quantity,
+        calculated_sum: series.values.tsv.iter().filter_map(|tsv| tsv.quantity).sum(),
+        unit: data.sum.unit.clone(),
+    };
+
+    for (position, tsv) in series.values.tsv.iter().enumerate() {
+        if position >= expected_intervals {
+            report.unexpected.push(tsv.time);
+            continue;
+        }
+
+        let start = resolution.step_from(series.start, position, tz);
+        if LocalDateTime::from_utc_in(&start, tz) != tsv.time {
+            report.mislabeled.push(tsv.time);
+        }
+        if tsv.quantity.is_none() {
+            report.null_quantities.push(start);
+        }
+    }
+    for position in report.received_intervals..expected_intervals {
+        report.missing.push(resolution.step_from(series.start, position, tz));
+    }
+
+    report
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Duration as ChronoDuration;
+
+    use super::*;
+    use crate::models::{Sum, TimeSeries, Values, TSV};
+
+    const TZ: Tz = chrono_tz::Europe::Helsinki;
+
+    /// A day of hourly values with times as local wall clock times.
+    fn day(start: &str, stop: &str, values: &[(&str, Option<f32>)], sum: f32) -> ConsumptionData {
+        let start: LocalDateTime = start.parse().unwrap();
+        let stop: LocalDateTime = stop.parse().unwrap();
+        ConsumptionData {
+            meteringpointcode: "1337".to_string(),
+            sum: Sum { quantity: sum, start, stop, unit: "kWh".to_string() },
+            timeseries: TimeSeries {
+                start,
+                stop,
+                resolution: ResolutionDuration::PT1H,
+                values: Values {
+                    tsv: values
+                        .iter()
+                        .map(|(time, quantity)| TSV {
+                            quantity: *quantity,
+                            time: time.parse().unwrap(),
+                            start: Some(start),
+                            stop: Some(stop),
+                            unit: None,
+                        })
+                        .collect(),
+                },
+            },
+        }
+    }
+
+    fn hours(date: &str, hours: impl Iterator<Item = u32>) -> Vec<String> {
+        hours.map(|hour| format!("{}T{:02}:00:00", date, hour)).collect()
+    }
+
+    fn values(times: &[String]) -> Vec<(&str, Option<f32>)> {
+        times.iter().map(|time| (time.as_str(), Some(1.0))).collect()
+    }
+
+    #[test]
+    fn test_validate_dst_days() {
+        // 02:00 is followed by 04:00 when clocks are turned forward
+        let times = hours("2023-03-26", (0..3).chain(4..24));
+        let report = validate_in(&day("2023-03-26T00:00:00", "2023-03-27T00:00:00", &values(&times), 23.0), &TZ);
+        assert_eq!(report.expected_intervals, 23);
+        assert!(report.is_ok(), "{:?}", report);
+        assert!(report.mislabeled.is_empty());
+
+        // WattiVahti labels that day with the skipped 03:00 and without 06:00
+        let times = hours("2023-03-26", (0..6).chain(7..24));
+        let report = validate_in(&day("2023-03-26T00:00:00", "2023-03-27T00:00:00", &values(&times), 23.0), &TZ);
+        assert!(report.is_ok(), "{:?}", report);
+        let mislabeled: Vec<LocalDateTime> = times[3..6].iter().map(|time| time.parse().unwrap()).collect();
+        assert_eq!(report.mislabeled, mislabeled);
+
+        // 03:00 is repeated when clocks are turned back
+        let times = hours("2023-10-29", (0..4).chain(3..24));
+        let report = validate_in(&day("2023-10-29T00:00:00", "2023-10-30T00:00:00", &values(&times), 25.0), &TZ);
+        assert_eq!(report.expected_intervals, 25);
+        assert!(report.is_ok(), "{:?}", report);
+        assert!(report.mislabeled.is_empty());
+    }
+
+    #[test]
+    fn test_validate_partial_data() {
+        let times = hours("2022-08-01", 0..6);
+        let mut partial = values(&times);
+        partial[2].1 = None;
+        partial[4].0 = partial[3].0;
+
+        let report = validate_in(&day("2022-08-01T00:00:00", "2022-08-01T08:00:00", &partial, 6.0), &TZ);
+        let start = times[0].parse::<LocalDateTime>().unwrap().to_utc_in(&TZ);
+        let at = |hour: i64| start + ChronoDuration::hours(hour);
+
+        assert_eq!(report.expected_intervals, 8);
+        assert_eq!(report.received_intervals, 6);
+        assert_eq!(report.missing, vec![at(6), at(7)]);
+        assert_eq!(report.null_quantities, vec![at(2)]);
+        assert_eq!(report.mislabeled, vec!["2022-08-01T03:00:00".parse().unwrap()]);
+        assert!(report.unexpected.is_empty());
+        assert_eq!(report.sum_drift(), 1.0);
+        assert!(report.has_sum_drift());
+        assert!(!report.is_ok());
+
+        // Values past the end of the range
+        let times = hours("2022-08-01", 0..3);
+        let report = validate_in(&day("2022-08-01T00:00:00", "2022-08-01T02:00:00", &values(&times), 3.0), &TZ);
+        assert_eq!(report.unexpected, vec!["2022-08-01T02:00:00".parse().unwrap()]);
+        assert!(!report.is_ok());
+    }
+
+    #[test]
+    fn test_sum_drift_tolerance() {
+        let times = hours("2022-08-01", 0..2);
+        let values = vec![(times[0].as_str(), Some(0.024)), (times[1].as_str(), Some(0.031))];
+        let report = validate_in(&day("2022-08-01T00:00:00", "2022-08-01T02:00:00", &values, 0.06), &TZ);
+        assert!(!report.has_sum_drift());
+        assert!(report.is_ok());
+    }
+}
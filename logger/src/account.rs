@@ -0,0 +1,386 @@
+//! WattiVahti accounts with their own credentials, cached access tokens and metering points.
+
+use api::{ResolutionDuration, RetryPolicy, WattiVahtiClient};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use chrono::{DateTime, Duration as ChronoDuration, TimeZone, Utc};
+use serde::Deserialize;
+use thiserror::Error;
+
+use crate::app::{fetch_meter_data_for_interval, get_access_token, report_fetch_error, resolve_metering_points, MeteringPointCodes, TokenError};
+use crate::settings::config_model::{AccountConfig, MeteringPointConfig, SettingsConfig};
+use crate::storage::sink::SinkRegistry;
+
+/// Tokens expiring within this margin are refreshed before they are used.
+const EXPIRY_MARGIN_SECONDS: i64 = 60;
+
+#[derive(Error, Debug)]
+pub enum AccountError {
+    #[error(transparent)]
+    Token(#[from] TokenError),
+    #[error("Token endpoint didn't return an access token: {0}")]
+    MissingToken(String),
+    #[error("No access token or credentials configured")]
+    NoCredentials,
+}
+
+/// Where the access token of an account comes from.
+#[derive(Debug, Clone)]
+pub enum TokenSource {
+    /// Fetched from the token endpoint with the credentials
+    Credentials { endpoint: String, username: String, password: String },
+    /// Fixed token, e.g. copied from the browser
+    Static(String),
+    None,
+}
+
+impl TokenSource {
+    /// Credentials take precedence over a fixed access token.
+    pub fn from_config(config: &AccountConfig) -> Self {
+        match (&config.username, &config.password, &config.token_endpoint, &config.access_token) {
+            (Some(username), Some(password), Some(endpoint), _) => TokenSource::Credentials {
+                endpoint: endpoint.clone(),
+                username: username.clone(),
+                password: password.clone(),
+            },
+            (_, _, _, Some(access_token)) => TokenSource::Static(access_token.clone()),
+            _ => TokenSource::None,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct CachedToken {
+    access_token: String,
+    expires_at: Option<DateTime<Utc>>,
+}
+
+/// Access token of a single account, reused until it expires or is rejected.
+#[derive(Debug)]
+pub struct TokenCache {
+    source: TokenSource,
+    cached: Option<CachedToken>,
+}
+
+impl TokenCache {
+    pub fn new(source: TokenSource) -> Self {
+        Self { source, cached: None }
+    }
+
+    pub async fn get(&mut self, http: &reqwest::Client, retry_policy: &RetryPolicy) -> Result<String, AccountError> {
+        if let Some(cached) = &self.cached {
+            let expired = cached
+                .expires_at
+                .map(|expires_at| expires_at - ChronoDuration::seconds(EXPIRY_MARGIN_SECONDS) <= Utc::now())
+                .unwrap_or(false);
+            if !expired {
+                return Ok(cached.access_token.clone());
+            }
+        }
+
+        let access_token = match &self.source {
+            TokenSource::Credentials { endpoint, username, password } => {
+                let response = get_access_token(http, endpoint, username, password, retry_policy).await?;
+                match response.access_token {
+                    Some(access_token) => access_token,
+                    None => return Err(AccountError::MissingToken(response.message.unwrap_or(response.status))),
+                }
+            }
+            TokenSource::Static(access_token) => access_token.clone(),
+            TokenSource::None => return Err(AccountError::NoCredentials),
+        };
+
+        self.cached = Some(CachedToken {
+            expires_at: get_token_expiry(&access_token),
+            access_token: access_token.clone(),
+        });
+        Ok(access_token)
+    }
+
+    /// Forgets a rejected token so that the next `get` fetches a new one.
+    pub fn invalidate(&mut self) {
+        self.cached = None;
+    }
+
+    /// `false` for fixed tokens that have to be replaced by hand once rejected.
+    pub fn can_refresh(&self) -> bool {
+        matches!(self.source, TokenSource::Credentials { .. })
+    }
+}
+
+#[derive(Deserialize)]
+struct Claims {
+    exp: Option<i64>,
+}
+
+/// Expiry time from the `exp` claim of a JWT, `None` for other kinds of tokens.
+pub fn get_token_expiry(access_token: &str) -> Option<DateTime<Utc>> {
+    let payload = access_token.split('.').nth(1)?;
+    let payload = URL_SAFE_NO_PAD.decode(payload.trim_end_matches('=')).ok()?;
+    let claims: Claims = serde_json::from_slice(&payload).ok()?;
+
+    Utc.timestamp_opt(claims.exp?, 0).single()
+}
+
+/// Outcome of fetching every metering point of an account once.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct AccountUpdate {
+    pub fetched: usize,
+    pub failed: usize,
+    /// Fetched data that one or more sinks failed to store
+    pub store_failed: usize,
+    pub token_rejected: bool,
+}
+
+/// Metering points and resolution to fetch instead of all configured ones.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Selection {
+    /// Metering point codes, every metering point when empty
+    pub points: Vec<String>,
+    /// Replaces the resolutions of the metering points
+    pub resolution: Option<ResolutionDuration>,
+}
+
+impl Selection {
+    pub fn apply(&self, metering_points: Vec<MeteringPointConfig>) -> Vec<MeteringPointConfig> {
+        metering_points
+            .into_iter()
+            .filter(|metering_point| self.points.is_empty() || self.points.contains(&metering_point.code))
+            .map(|mut metering_point| {
+                if let Some(resolution) = self.resolution {
+                    metering_point.resolutions = vec![resolution];
+                }
+                metering_point
+            })
+            .collect()
+    }
+}
+
+pub struct Account {
+    pub name: String,
+    config: AccountConfig,
+    tokens: TokenCache,
+    configured_metering_points: Vec<MeteringPointConfig>,
+    selection: Selection,
+    metering_points: Option<Vec<MeteringPointConfig>>,
+    is_default: bool,
+}
+
+impl Account {
+    pub fn from_config(config: &AccountConfig) -> Self {
+        Self {
+            name: config.name.clone(),
+            config: config.clone(),
+            tokens: TokenCache::new(TokenSource::from_config(config)),
+            configured_metering_points: config.metering_points.clone(),
+            selection: Selection::default(),
+            metering_points: None,
+            is_default: config.is_default(),
+        }
+    }
+
+    pub fn config(&self) -> &AccountConfig {
+        &self.config
+    }
+
+    pub fn can_refresh_token(&self) -> bool {
+        self.tokens.can_refresh()
+    }
+
+    /// Fetches only the selected metering points.
+    pub fn with_selection(mut self, selection: Selection) -> Self {
+        self.selection = selection;
+        self.metering_points = None;
+        self
+    }
+
+    /// Access token and the selected metering points. The metering points are resolved on first
+    /// use.
+    pub async fn prepare(
+        &mut self,
+        client: &WattiVahtiClient,
+        config: &SettingsConfig,
+        retry_policy: &RetryPolicy,
+    ) -> Result<(String, Vec<MeteringPointConfig>), AccountError> {
+        let access_token = self.tokens.get(client.http(), retry_policy).await?;
+
+        if self.metering_points.is_none() {
+            let codes = if self.is_default { MeteringPointCodes::from_config(&config.wattivahti) } else { MeteringPointCodes::default() };
+            let resolved = resolve_metering_points(&self.configured_metering_points, codes, &config.wattivahti.fetch_resolutions);
+            let resolved = self.selection.apply(resolved);
+            if resolved.is_empty() {
+                warn!("Account {} | No metering points configured", self.name);
+            }
+            for metering_point in &resolved {
+                info!("Account {} | Fetching {} with resolutions {:?}", self.name, metering_point, metering_point.resolutions);
+            }
+            self.metering_points = Some(resolved);
+        }
+
+        Ok((access_token, self.metering_points.clone().unwrap_or_default()))
+    }
+
+    /// Fetches and stores every selected metering point of the account for the interval.
+    pub async fn update(
+        &mut self,
+        client: &WattiVahtiClient,
+        config: &SettingsConfig,
+        sinks: &SinkRegistry,
+        retry_policy: &RetryPolicy,
+        start: &str,
+        stop: &str,
+    ) -> Result<AccountUpdate, AccountError> {
+        let (access_token, metering_points) = self.prepare(client, config, retry_policy).await?;
+
+        let mut update = AccountUpdate::default();
+        for metering_point in &metering_points {
+            for resolution in &metering_point.resolutions {
+                let result = fetch_meter_data_for_interval(client, &access_token, config, sinks, metering_point, start, stop, resolution).await;
+                match result {
+                    Ok(report) => {
+                        update.fetched += 1;
+                        if !report.is_ok() {
+                            error!("Account {} | Storing {} failed: {}", self.name, metering_point, report.failure_messages().join(", "));
+                            update.store_failed += 1;
+                        }
+                    }
+                    Err(err) => {
+                        error!("Account {} | Fetching {} failed", self.name, metering_point);
+                        update.failed += 1;
+                        update.token_rejected |= report_fetch_error(&err);
+                    }
+                }
+            }
+        }
+
+        if update.token_rejected {
+            self.tokens.invalidate();
+        }
+
+        Ok(update)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use api::{mock::MockWattiVahti, MeasurementType};
+    use reqwest::StatusCode;
+
+    use super::*;
+    use crate::settings::config::load_settings;
+
+    fn jwt(exp: i64) -> String {
+        let claims = URL_SAFE_NO_PAD.encode(format!(r#"{{"sub":"user","exp":{}}}"#, exp));
+        format!("eyJhbGciOiJIUzI1NiJ9.{}.signature", claims)
+    }
+
+    fn retry_policy() -> RetryPolicy {
+        RetryPolicy::new(2, Duration::from_millis(1), Duration::from_millis(1))
+    }
+
+    fn account_config(name: &str, mock: &MockWattiVahti, metering_points: Vec<MeteringPointConfig>) -> AccountConfig {
+        AccountConfig {
+            name: name.to_string(),
+            username: Some(name.to_string()),
+            password: Some("password".to_string()),
+            token_endpoint: Some(mock.token_endpoint()),
+            access_token: None,
+            metering_points,
+        }
+    }
+
+    #[test]
+    fn test_token_expiry() {
+        assert_eq!(get_token_expiry(&jwt(1_700_000_000)), Utc.timestamp_opt(1_700_000_000, 0).single());
+        assert_eq!(get_token_expiry("not-a-jwt"), None);
+        assert_eq!(get_token_expiry("a.b.c"), None);
+    }
+
+    #[tokio::test]
+    async fn test_token_cache() {
+        let mock = MockWattiVahti::start().await;
+        let http = reqwest::Client::new();
+        let valid = jwt((Utc::now() + ChronoDuration::hours(1)).timestamp());
+        mock.serve_token(&valid).await;
+
+        let mut tokens = TokenCache::new(TokenSource::Credentials {
+            endpoint: mock.token_endpoint(),
+            username: "user".to_string(),
+            password: "password".to_string(),
+        });
+        assert_eq!(tokens.get(&http, &retry_policy()).await.unwrap(), valid);
+        assert_eq!(tokens.get(&http, &retry_policy()).await.unwrap(), valid);
+        assert_eq!(mock.token_requests().await.len(), 1);
+
+        tokens.invalidate();
+        tokens.get(&http, &retry_policy()).await.unwrap();
+        assert_eq!(mock.token_requests().await.len(), 2);
+
+        // Expired tokens are refreshed
+        let mock = MockWattiVahti::start().await;
+        mock.serve_token(&jwt((Utc::now() - ChronoDuration::hours(1)).timestamp())).await;
+        let mut tokens = TokenCache::new(TokenSource::Credentials {
+            endpoint: mock.token_endpoint(),
+            username: "user".to_string(),
+            password: "password".to_string(),
+        });
+        tokens.get(&http, &retry_policy()).await.unwrap();
+        tokens.get(&http, &retry_policy()).await.unwrap();
+        assert_eq!(mock.token_requests().await.len(), 2);
+
+        let mut tokens = TokenCache::new(TokenSource::None);
+        assert!(matches!(tokens.get(&http, &retry_policy()).await, Err(AccountError::NoCredentials)));
+    }
+
+    #[tokio::test]
+    async fn test_token_failure_does_not_block_other_accounts() {
+        let config = load_settings("configs/test.yaml").unwrap();
+        let metering_point = MeteringPointConfig::new("1337", MeasurementType::Consumption, vec![ResolutionDuration::PT1H]);
+
+        let broken = MockWattiVahti::start().await;
+        broken.fail_next_token(StatusCode::SERVICE_UNAVAILABLE, 10).await;
+        let working = MockWattiVahti::start().await;
+        working.serve_token("working-token").await;
+        working.serve_fixture(MeasurementType::Consumption, "consumption_2022-08-01_PT1H.json").await;
+
+        let client = working.client();
+        let mut accounts = [
+            Account::from_config(&account_config("broken", &broken, vec![metering_point.clone()])),
+            Account::from_config(&account_config("working", &working, vec![metering_point])),
+        ];
+
+        let mut results = Vec::new();
+        for account in accounts.iter_mut() {
+            results.push(account.update(&client, &config, &SinkRegistry::default(), &retry_policy(), "2022-08-01T00:00:00", "2022-08-02T00:00:00").await);
+        }
+
+        assert!(matches!(results[0], Err(AccountError::Token(_))));
+        assert_eq!(results[1].as_ref().unwrap(), &AccountUpdate { fetched: 1, ..AccountUpdate::default() });
+        assert_eq!(working.meter_data_requests().await.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_rejected_token_is_refreshed() {
+        let config = load_settings("configs/test.yaml").unwrap();
+        let metering_point = MeteringPointConfig::new("1337", MeasurementType::Consumption, vec![ResolutionDuration::PT1H]);
+
+        let mock = MockWattiVahti::start().await;
+        mock.serve_token("token").await;
+        mock.reject_tokens().await;
+
+        let mut account = Account::from_config(&account_config("user", &mock, vec![metering_point]));
+        let update = account
+            .update(&mock.client(), &config, &SinkRegistry::default(), &retry_policy(), "2022-08-01T00:00:00", "2022-08-02T00:00:00")
+            .await
+            .unwrap();
+        assert!(update.token_rejected);
+        assert_eq!(update.failed, 1);
+
+        account
+            .update(&mock.client(), &config, &SinkRegistry::default(), &retry_policy(), "2022-08-01T00:00:00", "2022-08-02T00:00:00")
+            .await
+            .unwrap();
+        assert_eq!(mock.token_requests().await.len(), 2);
+    }
+}
@@ -1,28 +1,66 @@
-use api::{get_consumption_data, get_production_data};
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+use api::{
+    retry::{is_transient_status, retry},
+    validation, ApiError, ConsumptionsResult, MeasurementType, ResolutionDuration, RetryPolicy, Retryable, TimeRange, ValidationReport,
+    WattiVahtiClient,
+};
+use chrono::{DateTime, Duration as ChronoDuration, Utc};
 use reqwest::StatusCode;
+use thiserror::Error;
 
 use crate::{
     authmodels::{TokenRequest, TokenResponse},
-    settings,
-    storage::{
-        influxdb::influx::{upsert_consumptions_into_influxdb, upsert_productions_into_influxdb},
-        timescaledb::timescale::{
-            self, refresh_consumption_views, refresh_production_views, upsert_consumptions_into_timescaledb, upsert_productions_into_timescaledb
-        },
-    },
+    pricing::{price_intervals, reprice_intervals, time_span, PricedInterval},
+    settings::config_model::{ContractsConfig, MeteringPointConfig, SettingsConfig, WattiVahtiConfig},
+    storage::sink::{IntervalQuery, MeterDataBatch, SinkRegistry, SinkReport},
 };
 
+#[derive(Error, Debug)]
+pub enum TokenError {
+    #[error("Token endpoint responded with {status}: {body}")]
+    Server { status: StatusCode, body: String },
+    #[error("Failed to decode the token response: {0}")]
+    Decode(#[from] serde_json::Error),
+    #[error("Request to the token endpoint failed: {0}")]
+    Transport(#[from] reqwest::Error),
+}
+
+impl Retryable for TokenError {
+    fn is_transient(&self) -> bool {
+        match self {
+            TokenError::Server { status, .. } => is_transient_status(*status),
+            TokenError::Transport(err) => err.is_timeout() || err.is_connect(),
+            TokenError::Decode(_) => false,
+        }
+    }
+}
+
 pub async fn get_access_token(
+    http: &reqwest::Client,
     endpoint: &str,
     username: &str,
     password: &str,
-) -> Result<TokenResponse, anyhow::Error> {
+    retry_policy: &RetryPolicy,
+) -> Result<TokenResponse, TokenError> {
     info!(
         "Fetching a new access_token for WattiVahti user - {}",
         &username
     );
 
-    let res = reqwest::Client::new()
+    retry(retry_policy, "Fetching the access token", || {
+        request_access_token(http, endpoint, username, password)
+    })
+    .await
+}
+
+async fn request_access_token(
+    http: &reqwest::Client,
+    endpoint: &str,
+    username: &str,
+    password: &str,
+) -> Result<TokenResponse, TokenError> {
+    let res = http
         .post(format!("{}/wattivahti/token", endpoint))
         .json(&TokenRequest {
             username: username.to_string(),
@@ -37,7 +75,7 @@ pub async fn get_access_token(
     // info!("{}", data_str);
 
     if status != StatusCode::OK {
-        return Err(anyhow::anyhow!(data_str));
+        return Err(TokenError::Server { status, body: data_str });
     }
 
     let data: TokenResponse = serde_json::from_str(&data_str)?;
@@ -46,120 +84,462 @@ pub async fn get_access_token(
     Ok(data)
 }
 
-pub async fn fetch_production_for_interval(
+pub fn build_wattivahti_client(config: &WattiVahtiConfig) -> Result<WattiVahtiClient, ApiError> {
+    let mut builder = WattiVahtiClient::builder().retry_policy(config.retry.policy());
+    if let Some(base_url) = &config.api_url {
+        builder = builder.base_url(base_url);
+    }
+
+    builder.build()
+}
+
+/// Metering points to fetch from the `wattivahti` section.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct MeteringPointCodes {
+    pub consumption: Option<String>,
+    pub production: Option<String>,
+}
+
+impl MeteringPointCodes {
+    pub fn from_config(config: &WattiVahtiConfig) -> Self {
+        Self {
+            consumption: config.consumption_metering_point_code.clone(),
+            production: config.production_metering_point_code.clone(),
+        }
+    }
+
+    pub fn into_metering_points(self, resolutions: &[ResolutionDuration]) -> Vec<MeteringPointConfig> {
+        let consumption = self.consumption.map(|code| (code, MeasurementType::Consumption));
+        let production = self.production.map(|code| (code, MeasurementType::Production));
+
+        consumption
+            .into_iter()
+            .chain(production)
+            .map(|(code, measurement_type)| MeteringPointConfig::new(code, measurement_type, resolutions.to_vec()))
+            .collect()
+    }
+}
+
+/// Configured metering points, or the known `codes` fetched with the `resolutions`.
+pub fn resolve_metering_points(
+    configured: &[MeteringPointConfig],
+    codes: MeteringPointCodes,
+    resolutions: &[ResolutionDuration],
+) -> Vec<MeteringPointConfig> {
+    if !configured.is_empty() {
+        return configured.to_vec();
+    }
+
+    codes.into_metering_points(resolutions)
+}
+
+/// Fetches the meter data and writes it to every sink. Sink failures are in the report, only
+/// failing to fetch is an error.
+#[allow(clippy::too_many_arguments)]
+pub async fn fetch_meter_data_for_interval(
+    client: &WattiVahtiClient,
     access_token: &str,
+    config: &SettingsConfig,
+    sinks: &SinkRegistry,
+    metering_point: &MeteringPointConfig,
     start: &str,
     stop: &str,
-    resolution: &str,
-) -> Result<(), anyhow::Error> {
-    let metering_point_code = dotenv::var("PRODUCTION_METERING_POINT_CODE").unwrap();
-
+    resolution: &ResolutionDuration,
+) -> Result<SinkReport<usize>, anyhow::Error> {
     info!(
-        "Fetching production data for interval {} - {} in metering point {} with resolution {}",
-        &start, &stop, &metering_point_code, &resolution
+        "Fetching {} data for interval {} - {} with resolution {}",
+        &metering_point, &start, &stop, &resolution
     );
 
-    let config = settings::config::load_settings(format!("configs/{}.yaml", "production"))
-        .expect("Failed to load settings file.");
+    let measurement_type = metering_point
+        .measurement_type()
+        .ok_or_else(|| anyhow::anyhow!("Unsupported metering point kind {}", metering_point.kind))?;
+
+    let range = TimeRange::parse(start, stop)?;
+    let intervals = fetch_priced_intervals(client, access_token, config, sinks, metering_point, &range, resolution).await?;
+
+    let batch = MeterDataBatch {
+        metering_point,
+        resolution: *resolution,
+        intervals: &intervals,
+    };
+    let mut report = sinks.write(&batch).await;
+
+    // Refreshing is part of storing, so its failures are reported the same way
+    report.results.extend(
+        sinks
+            .refresh(measurement_type)
+            .await
+            .results
+            .into_iter()
+            .filter_map(|(name, result)| result.err().map(|err| (name, Err(err)))),
+    );
 
-    match get_production_data(&access_token, &metering_point_code, &start, &stop, &resolution).await {
-        Ok(data) => {
-            let timescale_future = upsert_productions_into_timescaledb(&data, &config);
-            let influx_future = upsert_productions_into_influxdb(&data, &config);
+    Ok(report)
+}
 
-            let (timescale_result, influx_result) = tokio::join!(timescale_future, influx_future);
+/// Fetches the meter data and prices it with the day-ahead prices stored in the sinks.
+pub async fn fetch_priced_intervals(
+    client: &WattiVahtiClient,
+    access_token: &str,
+    config: &SettingsConfig,
+    sinks: &SinkRegistry,
+    metering_point: &MeteringPointConfig,
+    range: &TimeRange,
+    resolution: &ResolutionDuration,
+) -> Result<Vec<PricedInterval>, anyhow::Error> {
+    let measurement_type = metering_point
+        .measurement_type()
+        .ok_or_else(|| anyhow::anyhow!("Unsupported metering point kind {}", metering_point.kind))?;
+
+    let data = fetch_meter_data(client, access_token, &metering_point.code, measurement_type, range, resolution).await?;
+
+    let (price_start, price_stop) = time_span(&data);
+    let spot_prices = sinks.day_ahead_prices(price_start, price_stop).await;
+    Ok(price_intervals(&data, measurement_type, config.get_metering_point_contracts(metering_point), &spot_prices))
+}
 
-            if timescale_result.is_err() {
-                error!("Error inserting into TimescaleDB: {:?}", timescale_result);
-            }
+/// Fetches the meter data and logs its validation report.
+pub async fn fetch_meter_data(
+    client: &WattiVahtiClient,
+    access_token: &str,
+    metering_point_code: &str,
+    measurement_type: MeasurementType,
+    range: &TimeRange,
+    resolution: &ResolutionDuration,
+) -> Result<ConsumptionsResult, ApiError> {
+    let data = client
+        .get_meter_data(access_token, metering_point_code, measurement_type, range, resolution)
+        .await?;
 
-            if influx_result.is_err() {
-                error!("Error inserting into InfluxDB: {:?}", influx_result);
-            }
+    log_validation_report(&validation::validate(&data));
 
-            if timescale::is_enabled() {
-                if let Err(err) = refresh_production_views().await {
-                    // Handle the error here
-                    error!("Error refreshing the production views: {:?}", err);
-                }
-            }
+    Ok(data)
+}
 
-            Ok(())
-        }
-        Err(err) => Err(anyhow::anyhow!(err)),
-    }
+/// Outcome of repricing the stored intervals whose price was pending.
+#[derive(Debug, Default)]
+pub struct RepriceReport {
+    pub repriced: usize,
+    /// Intervals that still have no price
+    pub pending: Vec<PricedInterval>,
+    /// Failures of writing the repriced intervals, as `sink: error` lines
+    pub failures: Vec<String>,
 }
 
-pub async fn fetch_consumption_for_interval(
-    access_token: &str,
-    start: &str,
-    stop: &str,
-    resolution: &str,
-) -> Result<(), anyhow::Error> {
-    let metering_point_code = dotenv::var("CONSUMPTION_METERING_POINT_CODE").unwrap();
+/// Reprices the stored intervals of `start..stop` whose price was pending with the day-ahead
+/// prices stored since, and writes them back to every sink. Does nothing when no sink can be
+/// read back.
+pub async fn reprice_pending_intervals(
+    config: &SettingsConfig,
+    sinks: &SinkRegistry,
+    start: DateTime<Utc>,
+    stop: DateTime<Utc>,
+) -> Result<RepriceReport, anyhow::Error> {
+    let query = IntervalQuery { start, stop, points: Vec::new(), resolution: None, pending_price_only: true };
+    let pending = match sinks.try_read_intervals(&query).await? {
+        Some(pending) => pending,
+        None => {
+            debug!("None of the sinks can be read back, not repricing {} - {}", start, stop);
+            return Ok(RepriceReport::default());
+        }
+    };
+    if pending.is_empty() {
+        return Ok(RepriceReport::default());
+    }
 
-    info!(
-        "Fetching consumption data for interval {} - {} in metering point {} with resolution {}",
-        &start, &stop, &metering_point_code, &resolution
-    );
+    let (first, last) = pending.iter().fold((stop, start), |(first, last), interval| (first.min(interval.time), last.max(interval.time)));
+    let spot_prices = sinks.day_ahead_prices(first, last + ChronoDuration::hours(1)).await;
 
-    let config = settings::config::load_settings(format!("configs/{}.yaml", "production"))
-        .expect("Failed to load settings file.");
+    let mut contracts: HashMap<(String, i32), ContractsConfig> = HashMap::new();
+    for interval in &pending {
+        let key = (interval.metering_point_code.clone(), interval.measurement_type.code());
+        contracts
+            .entry(key)
+            .or_insert_with(|| config.get_contracts_by_code(&interval.metering_point_code, interval.measurement_type));
+    }
+    let repriced = reprice_intervals(&pending, &spot_prices, |interval| {
+        &contracts[&(interval.metering_point_code.clone(), interval.measurement_type.code())]
+    });
+
+    // Written back like fetched batches, a metering point and resolution at a time
+    let mut batches: BTreeMap<(String, i32, String), Vec<PricedInterval>> = BTreeMap::new();
+    for interval in &repriced {
+        let key = (interval.metering_point_code.clone(), interval.measurement_type.code(), interval.resolution.to_string());
+        batches.entry(key).or_default().push(interval.clone());
+    }
 
-    match get_consumption_data(&access_token, &metering_point_code, &start, &stop, &resolution).await {
-        Ok(data) => {
-            let timescale_future = upsert_consumptions_into_timescaledb(&data, &config);
-            let influx_future = upsert_consumptions_into_influxdb(&data, &config);
+    let mut report = RepriceReport::default();
+    let mut measurement_types = Vec::new();
+    for intervals in batches.values() {
+        let first = &intervals[0];
+        let metering_point = MeteringPointConfig::new(first.metering_point_code.clone(), first.measurement_type, vec![first.resolution]);
+        let batch = MeterDataBatch { metering_point: &metering_point, resolution: first.resolution, intervals };
+
+        let written = sinks.write(&batch).await;
+        report.failures.extend(written.failure_messages());
+        if written.is_ok() {
+            report.repriced += intervals.len();
+        }
+        if !measurement_types.contains(&first.measurement_type) {
+            measurement_types.push(first.measurement_type);
+        }
+    }
+    for measurement_type in measurement_types {
+        report.failures.extend(sinks.refresh(measurement_type).await.failure_messages());
+    }
 
-            let (timescale_result, influx_result) = tokio::join!(timescale_future, influx_future);
+    // Intervals without a day-ahead price or a contract for it stay pending
+    let key = |interval: &PricedInterval| (interval.time, interval.metering_point_code.clone(), interval.measurement_type.code(), interval.resolution.to_string());
+    let repriced: HashSet<_> = repriced.iter().map(key).collect();
+    report.pending = pending.into_iter().filter(|interval| !repriced.contains(&key(interval))).collect();
+    info!("Repriced {} intervals of {} - {}, {} still without a price", report.repriced, start, stop, report.pending.len());
+    Ok(report)
+}
 
-            if timescale_result.is_err() {
-                error!("Error inserting into TimescaleDB: {:?}", timescale_result);
-            }
+/// Logs the result of validating fetched data, with the affected intervals when the utility
+/// returned partial or inconsistent data.
+pub fn log_validation_report(report: &ValidationReport) {
+    if !report.mislabeled.is_empty() {
+        info!("Values timestamped by position instead of their times: {}", format_list(&report.mislabeled));
+    }
+    if report.is_ok() {
+        info!("Validated {}", report);
+        return;
+    }
 
-            if influx_result.is_err() {
-                error!("Error inserting into InfluxDB: {:?}", influx_result);
-            }
+    warn!("Fetched data failed validation, {}", report);
+    if !report.missing.is_empty() {
+        warn!("Missing intervals: {}", format_list(&report.missing));
+    }
+    if !report.null_quantities.is_empty() {
+        warn!("Intervals without a quantity: {}", format_list(&report.null_quantities));
+    }
+    if !report.unexpected.is_empty() {
+        warn!("Values past the expected intervals at: {}", format_list(&report.unexpected));
+    }
+    if report.has_sum_drift() {
+        warn!(
+            "Reported sum {} {} differs from the sum of the values {} {}",
+            report.reported_sum, report.unit, report.calculated_sum, report.unit
+        );
+    }
+}
 
-            if timescale::is_enabled() {
-                if let Err(err) = refresh_consumption_views().await {
-                    // Handle the error here
-                    error!("Error refreshing the consumption views: {:?}", err);
-                }
-            }
+/// Joins at most the first ten items of a list.
+fn format_list<T: std::fmt::Display>(items: &[T]) -> String {
+    const MAX_ITEMS: usize = 10;
+    let mut list = items.iter().take(MAX_ITEMS).map(|item| item.to_string()).collect::<Vec<_>>().join(", ");
+    if items.len() > MAX_ITEMS {
+        list.push_str(&format!(" and {} more", items.len() - MAX_ITEMS));
+    }
+    list
+}
 
-            Ok(())
+/// Logs a failed fetch according to its cause. Returns `true` when the access token
+/// was rejected and should be refreshed before the next fetch.
+pub fn report_fetch_error(err: &anyhow::Error) -> bool {
+    match err.downcast_ref::<ApiError>() {
+        Some(ApiError::Unauthorized) => {
+            warn!("WattiVahti rejected the access token, it has probably expired");
+            true
+        }
+        Some(ApiError::RateLimited { retry_after }) => {
+            warn!("WattiVahti rate limited the request, retry after {:?}", retry_after);
+            false
+        }
+        Some(ApiError::Server { status, body }) => {
+            error!("WattiVahti responded with {}: {}", status, body);
+            false
+        }
+        Some(ApiError::Decode { body, source }) => {
+            error!("WattiVahti response couldn't be decoded, has the schema changed? {} | {}", source, body);
+            false
+        }
+        Some(err) => {
+            warn!("Fetching from WattiVahti failed: {}", err);
+            false
+        }
+        None => {
+            error!("Fetching from WattiVahti failed: {:?}", err);
+            false
         }
-        Err(err) => Err(anyhow::anyhow!(err)),
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    use api::mock::MockWattiVahti;
+    use chrono::{Duration as ChronoDuration, TimeZone, Utc};
     use dotenv::dotenv;
 
     use super::*;
+    use crate::pricing::SpotPrices;
+    use crate::settings::config_model::ContractType;
+    use crate::storage::memory::MemoryStore;
+
+    fn test_retry_policy() -> RetryPolicy {
+        RetryPolicy::new(3, Duration::from_millis(1), Duration::from_millis(1))
+    }
+
+    #[tokio::test]
+    async fn test_fetch_price_store_pipeline() {
+        let mock = MockWattiVahti::start().await;
+        mock.serve_token("mock-token").await;
+        mock.fail_next_token(StatusCode::INTERNAL_SERVER_ERROR, 1).await;
+        mock.serve_fixture_from(MeasurementType::Consumption, "2023-10-29T00:00:00", "consumption_2023-10-29_PT1H.json").await;
+
+        let token = get_access_token(mock.client().http(), &mock.token_endpoint(), "user", "password", &test_retry_policy())
+            .await
+            .unwrap()
+            .access_token
+            .unwrap();
+        assert_eq!(token, "mock-token");
+        assert_eq!(mock.token_requests().await.len(), 2);
+
+        // Midnight in Helsinki is 21:00 UTC while still in daylight saving time
+        let day_start = Utc.ymd(2023, 10, 28).and_hms(21, 0, 0);
+        let store = Arc::new(Mutex::new(MemoryStore::default()));
+        for hour in 0..25 {
+            store.lock().unwrap().insert_day_ahead_price(day_start + ChronoDuration::hours(hour), 100.0 + hour as f32);
+        }
+        let mut sinks = SinkRegistry::default();
+        sinks.register(store.clone());
+
+        let config = crate::settings::config::load_settings("configs/test.yaml").unwrap();
+        let metering_point = MeteringPointConfig::new("1337", MeasurementType::Consumption, vec![ResolutionDuration::PT1H]);
+        for _ in 0..2 {
+            // Upserting again replaces the rows
+            let report = fetch_meter_data_for_interval(&mock.client(), &token, &config, &sinks, &metering_point, "2023-10-29T00:00:00", "2023-10-30T00:00:00", &ResolutionDuration::PT1H)
+                .await
+                .unwrap();
+            assert_eq!(report.results[0].1.as_ref().unwrap(), &25);
+        }
+
+        let contracts = config.get_contracts(MeasurementType::Consumption);
+        let values = store.lock().unwrap().values(MeasurementType::Consumption).into_iter().cloned().collect::<Vec<_>>();
+        assert_eq!(values.len(), 25);
+        for (hour, value) in values.iter().enumerate() {
+            let time = day_start + ChronoDuration::hours(hour as i64);
+            let price = 100.0 + hour as f32;
+            let contract = contracts.get_contract(time).unwrap();
+
+            assert_eq!(value.time, time);
+            assert_eq!(value.metering_point_code, "1337");
+            assert_eq!(value.resolution, ResolutionDuration::PT1H);
+            // The priced interval carries the spot price in c/kWh, the InfluxDB sink converts it to
+            // EUR/kWh with `cents_to_eur` when writing
+            assert!((value.spot_price.unwrap() - price / 10.0).abs() < 1e-6);
+            assert!((value.energy_fee.unwrap() - contract.get_energy_fee_spot(price)).abs() < 1e-6);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_reprice_pending_intervals() {
+        let config = crate::settings::config::load_settings("configs/test.yaml").unwrap();
+        let data: ConsumptionsResult = serde_json::from_str(&api::mock::fixture("consumption_2022-08-01_PT1H.json")).unwrap();
+        let contracts = config.get_contracts(MeasurementType::Consumption);
+        let (start, stop) = time_span(&data);
+
+        // Stored before the day-ahead prices arrived, then half of the day gets its prices
+        let store = Arc::new(Mutex::new(MemoryStore::default()));
+        store.lock().unwrap().upsert_intervals(&price_intervals(&data, MeasurementType::Consumption, contracts, &SpotPrices::default()));
+        for hour in 0..12 {
+            store.lock().unwrap().insert_day_ahead_price(start + ChronoDuration::hours(hour), 100.0 + hour as f32);
+        }
+        let mut sinks = SinkRegistry::default();
+        sinks.register(store.clone());
+
+        let report = reprice_pending_intervals(&config, &sinks, start, stop).await.unwrap();
+        assert_eq!(report.repriced, 12);
+        assert!(report.failures.is_empty());
+        assert_eq!(report.pending.len(), 12);
+        assert_eq!(report.pending[0].time, start + ChronoDuration::hours(12));
+
+        for (hour, value) in store.lock().unwrap().values(MeasurementType::Consumption).into_iter().enumerate() {
+            if hour < 12 {
+                let price = 100.0 + hour as f32;
+                assert_eq!(value.spot_price, Some(price / 10.0));
+                let contract = contracts.get_contract(value.time).unwrap();
+                if contract.contract_type == ContractType::Spot {
+                    assert_eq!(value.energy_fee, Some(contract.get_energy_fee_spot(price)));
+                }
+            } else {
+                assert!(value.is_price_pending());
+            }
+        }
+
+        // Nothing is left to reprice until more prices arrive
+        let report = reprice_pending_intervals(&config, &sinks, start, stop).await.unwrap();
+        assert_eq!(report.repriced, 0);
+        assert_eq!(report.pending.len(), 12);
+    }
 
     #[tokio::test]
+    async fn test_multiple_metering_points() {
+        let mock = MockWattiVahti::start().await;
+        mock.serve_fixture_for_point("1337", "consumption_2022-08-01_PT1H.json").await;
+        mock.serve_fixture_for_point("7331", "production_2022-08-01_PT1H.json").await;
+        mock.serve_fixture_for_point("4242", "consumption_2022-08-01_PT1H.json").await;
+
+        let config = crate::settings::config::load_settings("configs/test.yaml").unwrap();
+        let metering_points = resolve_metering_points(&config.metering_points, MeteringPointCodes::default(), &[]);
+        assert_eq!(metering_points.len(), 3);
+
+        let range = TimeRange::parse("2022-08-01T00:00:00", "2022-08-02T00:00:00").unwrap();
+        let mut store = MemoryStore::default();
+        for metering_point in &metering_points {
+            let measurement_type = metering_point.measurement_type().unwrap();
+            let data = fetch_meter_data(&mock.client(), "mock-token", &metering_point.code, measurement_type, &range, &ResolutionDuration::PT1H)
+                .await
+                .unwrap();
+            let contracts = config.get_metering_point_contracts(metering_point);
+            store.upsert_intervals(&price_intervals(&data, measurement_type, contracts, &SpotPrices::default()));
+        }
+
+        let production = store.values(MeasurementType::Production);
+        assert_eq!(production.len(), 24);
+        assert!(production.iter().all(|value| value.metering_point_code == "7331" && value.measurement_type == MeasurementType::Production));
+
+        // Both consumption points are stored apart, the cottage is priced with its own fixed contract
+        let consumption = store.values(MeasurementType::Consumption);
+        assert_eq!(consumption.len(), 48);
+        let (cottage, house) = consumption.into_iter().partition::<Vec<_>, _>(|value| value.metering_point_code == "4242");
+        assert_eq!(cottage.len(), 24);
+        assert!(cottage.iter().all(|value| value.energy_fee == Some(9.50)));
+        assert!(house.iter().all(|value| value.metering_point_code == "1337" && value.energy_fee != Some(9.50)));
+    }
+
+    #[tokio::test]
+    async fn test_rejected_token_is_reported() {
+        let mock = MockWattiVahti::start().await;
+        mock.reject_tokens().await;
+
+        let range = TimeRange::parse("2022-08-01T00:00:00", "2022-08-02T00:00:00").unwrap();
+        let client = mock.client();
+        let err = fetch_meter_data(&client, "expired", "1337", MeasurementType::Consumption, &range, &ResolutionDuration::PT1H)
+            .await
+            .unwrap_err();
+        assert!(report_fetch_error(&err.into()));
+    }
+
+    #[tokio::test]
+    #[ignore = "requires WattiVahti credentials and network access"]
     async fn test_get_consumptions_and_productions() {
         dotenv().ok();
 
         let start = "2020-01-01T00:00:00";
         let stop = "2020-12-31T00:00:00";
 
-        let mut access_token = dotenv::var("ACCESS_TOKEN").unwrap_or("".to_string());
-        let wattivahti_username = dotenv::var("WATTIVAHTI_USERNAME").unwrap_or("".to_string());
-        let wattivahti_password = dotenv::var("WATTIVAHTI_PASSWORD").unwrap_or("".to_string());
-        let wattivahti_token_endpoint =
-            dotenv::var("WATTIVAHTI_TOKEN_ENDPOINT").unwrap_or("".to_string());
+        let config = crate::settings::config::load_settings(format!("configs/{}.yaml", "production")).unwrap();
+        let wattivahti = &config.wattivahti;
+        let mut access_token = wattivahti.access_token.clone().unwrap_or_default();
 
-        if !wattivahti_username.is_empty() {
-            let result = get_access_token(
-                &wattivahti_token_endpoint,
-                &wattivahti_username,
-                &wattivahti_password,
-            )
-            .await;
+        if let (Some(username), Some(password), Some(token_endpoint)) = (&wattivahti.username, &wattivahti.password, &wattivahti.token_endpoint) {
+            let result = get_access_token(&reqwest::Client::new(), token_endpoint, username, password, &wattivahti.retry.policy()).await;
 
             if result.is_err() {
                 panic!("Logging {} - {} failed because of accessToken", start, stop);
@@ -171,16 +551,17 @@ mod tests {
             access_token = result.access_token.unwrap();
         }
 
-        if let Err(err) = fetch_consumption_for_interval(&access_token, &start, &stop, "PT1H").await
-        {
-            // Handle the error here
-            panic!("Error fetching consumptions: {:?}", err);
-        }
+        let client = build_wattivahti_client(wattivahti).unwrap();
+        let sinks = SinkRegistry::from_config(&config, crate::storage::SINKS).unwrap();
+        let codes = MeteringPointCodes::from_config(wattivahti);
+        let metering_points = resolve_metering_points(&config.metering_points, codes, &[ResolutionDuration::PT1H]);
 
-        if let Err(err) = fetch_production_for_interval(&access_token, &start, &stop, "PT1H").await
-        {
-            // Handle the error here
-            panic!("Error fetching productions: {:?}", err);
+        for metering_point in &metering_points {
+            if let Err(err) = fetch_meter_data_for_interval(&client, &access_token, &config, &sinks, metering_point, start, stop, &ResolutionDuration::PT1H).await
+            {
+                // Handle the error here
+                panic!("Error fetching {}: {:?}", metering_point, err);
+            }
         }
     }
 }
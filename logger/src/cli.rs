@@ -0,0 +1,217 @@
+use std::path::PathBuf;
+
+use api::{LocalDateTime, MeasurementType, MeteringPointType, ResolutionDuration};
+use chrono::NaiveDate;
+use clap::{Args, Parser, Subcommand};
+
+use crate::account::Selection;
+use crate::export::{ExportFormat, ExportSource};
+use crate::settings::config::SettingsFiles;
+
+/// Logs the WattiVahti meter data into InfluxDB and TimescaleDB.
+#[derive(Parser, Debug)]
+#[command(version, about)]
+pub struct Cli {
+    /// Settings file, `configs/<profile>.yaml` by default. A `.local.yaml` file next to it is
+    /// merged on top when it exists.
+    #[arg(long, env = "LOGGER_CONFIG", global = true)]
+    pub config: Option<PathBuf>,
+    /// Settings profile
+    #[arg(long, env = "LOGGER_PROFILE", default_value = "production", global = true)]
+    pub profile: String,
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+impl Cli {
+    pub fn settings_files(&self) -> SettingsFiles {
+        match &self.config {
+            Some(path) => SettingsFiles::new(path),
+            None => SettingsFiles::for_profile(&self.profile),
+        }
+    }
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Serve the REST API and fetch the previous day daily, as enabled in the settings (default)
+    Run,
+    /// Fetch and store the meter data of a range
+    Fetch(FetchArgs),
+    /// Fetch and store a long range one window at a time, continuing past failed windows
+    Backfill(BackfillArgs),
+    /// Load the settings and report every invalid value
+    ValidateConfig,
+    /// Show the contract and fees in effect at a time
+    Price(PriceArgs),
+    /// Fetch the ENTSO-E day-ahead prices of a range and store them
+    FetchPrices(FetchPricesArgs),
+    /// List the stored intervals that are still waiting for their day-ahead price
+    Unpriced(UnpricedArgs),
+    /// Write the priced consumption and production of a range as CSV, JSON Lines or monthly Parquet files
+    Export(ExportArgs),
+    /// Get an access token for each account and show when it expires
+    Token(TokenArgs),
+}
+
+/// Metering points to fetch, every point of every account by default.
+#[derive(Args, Debug, Clone)]
+pub struct SelectionArgs {
+    /// Metering point code, can be repeated
+    #[arg(long = "point")]
+    pub points: Vec<String>,
+    /// Resolution such as PT1H or PT15M, the configured resolutions of each point by default
+    #[arg(long)]
+    pub resolution: Option<ResolutionDuration>,
+    /// Name of the account, `default` when no accounts are listed in the settings
+    #[arg(long)]
+    pub account: Option<String>,
+}
+
+impl SelectionArgs {
+    pub fn selection(&self) -> Selection {
+        Selection { points: self.points.clone(), resolution: self.resolution }
+    }
+}
+
+#[derive(Args, Debug)]
+pub struct FetchArgs {
+    /// Start as a local date or time, e.g. 2023-01-01 or 2023-01-01T06:00:00
+    #[arg(long, value_parser = parse_local_time)]
+    pub from: LocalDateTime,
+    /// End as a local date or time, exclusive
+    #[arg(long, value_parser = parse_local_time)]
+    pub to: LocalDateTime,
+    #[command(flatten)]
+    pub selection: SelectionArgs,
+}
+
+#[derive(Args, Debug)]
+pub struct BackfillArgs {
+    #[arg(long, value_parser = parse_local_time)]
+    pub from: LocalDateTime,
+    /// End, exclusive, the start of today by default
+    #[arg(long, value_parser = parse_local_time)]
+    pub to: Option<LocalDateTime>,
+    /// Length of each window in days, calendar months by default
+    #[arg(long)]
+    pub window_days: Option<i64>,
+    #[command(flatten)]
+    pub selection: SelectionArgs,
+}
+
+#[derive(Args, Debug)]
+pub struct PriceArgs {
+    /// Local time, e.g. 2023-01-01T18:00:00
+    #[arg(long, value_parser = parse_local_time)]
+    pub at: LocalDateTime,
+    /// Use the contracts of a metering point
+    #[arg(long)]
+    pub point: Option<String>,
+    /// Contracts to use when no metering point is given, consumption or production
+    #[arg(long, default_value = "consumption", value_parser = parse_measurement_type)]
+    pub kind: MeasurementType,
+    /// Day-ahead price in EUR/MWh for spot contracts
+    #[arg(long)]
+    pub spot_price: Option<f32>,
+}
+
+#[derive(Args, Debug)]
+pub struct FetchPricesArgs {
+    #[arg(long, value_parser = parse_local_time)]
+    pub from: LocalDateTime,
+    /// End, exclusive, the end of tomorrow by default
+    #[arg(long, value_parser = parse_local_time)]
+    pub to: Option<LocalDateTime>,
+}
+
+#[derive(Args, Debug)]
+pub struct UnpricedArgs {
+    #[arg(long, value_parser = parse_local_time)]
+    pub from: LocalDateTime,
+    /// End, exclusive, the end of tomorrow by default
+    #[arg(long, value_parser = parse_local_time)]
+    pub to: Option<LocalDateTime>,
+}
+
+#[derive(Args, Debug)]
+pub struct ExportArgs {
+    #[arg(long, value_parser = parse_local_time)]
+    pub from: LocalDateTime,
+    #[arg(long, value_parser = parse_local_time)]
+    pub to: LocalDateTime,
+    #[command(flatten)]
+    pub selection: SelectionArgs,
+    /// csv, jsonl or parquet
+    #[arg(long, default_value = "csv")]
+    pub format: ExportFormat,
+    /// stored to read back what the sinks have stored, fetch to fetch and price without storing
+    #[arg(long, default_value = "stored")]
+    pub source: ExportSource,
+    /// File to write, standard output by default. Directory of the monthly files for parquet
+    #[arg(long)]
+    pub output: Option<PathBuf>,
+}
+
+#[derive(Args, Debug)]
+pub struct TokenArgs {
+    /// Name of the account, all accounts by default
+    #[arg(long)]
+    pub account: Option<String>,
+    /// Print the access token as well
+    #[arg(long)]
+    pub show: bool,
+}
+
+/// Local date or time, a date meaning its midnight.
+fn parse_local_time(value: &str) -> Result<LocalDateTime, String> {
+    if let Ok(date) = NaiveDate::parse_from_str(value, "%Y-%m-%d") {
+        return Ok(LocalDateTime::new(date.and_hms(0, 0, 0)));
+    }
+
+    value
+        .parse()
+        .map_err(|_| format!("expected a date like 2023-01-01 or a time like 2023-01-01T06:00:00, got {}", value))
+}
+
+fn parse_measurement_type(value: &str) -> Result<MeasurementType, String> {
+    MeteringPointType::from(value)
+        .measurement_type()
+        .ok_or_else(|| format!("expected consumption or production, got {}", value))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_commands() {
+        let cli = Cli::parse_from(["logger", "fetch", "--from", "2023-01-01", "--to", "2023-01-02T06:00:00", "--point", "1337", "--resolution", "PT15M"]);
+        match cli.command {
+            Some(Command::Fetch(args)) => {
+                assert_eq!(args.from, "2023-01-01T00:00:00".parse().unwrap());
+                assert_eq!(args.to, "2023-01-02T06:00:00".parse().unwrap());
+                assert_eq!(args.selection.points, vec!["1337"]);
+                assert_eq!(args.selection.resolution, Some(ResolutionDuration::PT15M));
+            }
+            other => panic!("Expected fetch, got {:?}", other),
+        }
+
+        let cli = Cli::parse_from(["logger", "price", "--at", "2023-01-01T18:00:00", "--kind", "production", "--profile", "test"]);
+        assert_eq!(cli.settings_files(), SettingsFiles::for_profile("test"));
+        assert!(matches!(cli.command, Some(Command::Price(PriceArgs { kind: MeasurementType::Production, .. }))));
+
+        let cli = Cli::parse_from(["logger", "export", "--from", "2023-01-01", "--to", "2023-02-01", "--format", "jsonl"]);
+        assert!(matches!(cli.command, Some(Command::Export(ExportArgs { format: ExportFormat::Jsonl, source: ExportSource::Stored, .. }))));
+        assert!(Cli::try_parse_from(["logger", "export", "--from", "2023-01-01", "--to", "2023-02-01", "--format", "xlsx"]).is_err());
+
+        let cli = Cli::parse_from(["logger", "fetch-prices", "--from", "2023-01-01"]);
+        assert!(matches!(cli.command, Some(Command::FetchPrices(FetchPricesArgs { to: None, .. }))));
+
+        let cli = Cli::parse_from(["logger", "unpriced", "--from", "2023-01-01", "--to", "2023-02-01"]);
+        assert!(matches!(cli.command, Some(Command::Unpriced(UnpricedArgs { to: Some(_), .. }))));
+
+        assert!(Cli::parse_from(["logger"]).command.is_none());
+        assert!(Cli::try_parse_from(["logger", "fetch", "--from", "yesterday", "--to", "2023-01-02"]).is_err());
+    }
+}
@@ -0,0 +1,327 @@
+//! Subcommands of the logger binary other than `run`.
+
+use std::fs::File;
+use std::io::{self, Write};
+
+use anyhow::{anyhow, bail};
+use api::{
+    chunk::{split_range, RequestWindow},
+    LocalDateTime, TimeRange,
+};
+use chrono::{DateTime, Utc};
+
+use crate::account::{get_token_expiry, Account, AccountUpdate, TokenCache, TokenSource};
+use crate::export::{collect_intervals, write_intervals, write_parquet_partitions, ExportFormat, ExportRequest};
+use crate::app::{reprice_pending_intervals, resolve_metering_points, MeteringPointCodes};
+use crate::cli::{BackfillArgs, ExportArgs, FetchArgs, FetchPricesArgs, PriceArgs, SelectionArgs, TokenArgs, UnpricedArgs};
+use crate::entsoe::update_day_ahead_prices;
+use crate::settings::config::SettingsFiles;
+use crate::settings::config_model::{AccountConfig, ContractType, SettingsConfig};
+use crate::settings::time::get_timezone;
+use crate::pricing::pending_price_runs;
+use crate::state::AppState;
+use crate::storage::sink::{IntervalQuery, SinkRegistry};
+use crate::storage::SINKS;
+
+pub async fn fetch(state: &AppState, args: &FetchArgs) -> Result<(), anyhow::Error> {
+    let range = TimeRange::new(args.from.naive(), args.to.naive());
+    backfill_windows(state, &args.selection, &[range]).await
+}
+
+pub async fn backfill(state: &AppState, args: &BackfillArgs) -> Result<(), anyhow::Error> {
+    let to = args.to.unwrap_or_else(start_of_today);
+    let range = TimeRange::new(args.from.naive(), to.naive());
+    let window = match args.window_days {
+        Some(days) if days > 0 => RequestWindow::Days(days),
+        Some(days) => bail!("--window-days must be positive, got {}", days),
+        None => RequestWindow::Months(1),
+    };
+
+    backfill_windows(state, &args.selection, &split_range(&range, window)).await
+}
+
+/// Fetches and stores the windows in order for every selected account, continuing past
+/// failures. Fails at the end if any window failed.
+async fn backfill_windows(state: &AppState, args: &SelectionArgs, windows: &[TimeRange]) -> Result<(), anyhow::Error> {
+    let config = state.settings();
+    let client = state.client();
+    let sinks = state.sinks();
+    let retry_policy = config.wattivahti.retry.policy();
+
+    let mut failed_windows = Vec::new();
+    for account_config in select_accounts(&config, args.account.as_deref())? {
+        let mut account = Account::from_config(&account_config).with_selection(args.selection());
+        let mut total = AccountUpdate::default();
+
+        for (index, window) in windows.iter().enumerate() {
+            info!("Account {} | Window {}/{}: {} - {}", account.name, index + 1, windows.len(), window.start, window.stop);
+            match account.update(&client, &config, &sinks, &retry_policy, &window.start_str(), &window.stop_str()).await {
+                Ok(update) => {
+                    total.fetched += update.fetched;
+                    total.failed += update.failed;
+                    total.store_failed += update.store_failed;
+                    if update.failed > 0 || update.store_failed > 0 {
+                        failed_windows.push(format!("{}: {} - {}", account.name, window.start, window.stop));
+                    }
+                }
+                Err(err) => {
+                    failed_windows.push(format!("{}: {} - {} ({})", account.name, window.start, window.stop, err));
+                    total.failed += 1;
+                }
+            }
+        }
+
+        println!("{}: {} fetched, {} failed, {} not stored", account.name, total.fetched, total.failed, total.store_failed);
+    }
+
+    if !failed_windows.is_empty() {
+        bail!("{} windows failed:\n{}", failed_windows.len(), failed_windows.join("\n"));
+    }
+    Ok(())
+}
+
+/// Prints the accounts and metering points of valid settings, or every invalid value.
+pub fn validate_config(files: &SettingsFiles) -> Result<(), anyhow::Error> {
+    let config = files.load()?;
+
+    let layers: Vec<String> = files.layers().iter().map(|path| path.display().to_string()).collect();
+    println!("Settings in {} are valid", layers.join(" + "));
+    println!("Time zone: {}", config.timezone);
+    for account in config.get_accounts() {
+        let codes = if account.is_default() { MeteringPointCodes::from_config(&config.wattivahti) } else { MeteringPointCodes::default() };
+        let metering_points = resolve_metering_points(&account.metering_points, codes, &config.wattivahti.fetch_resolutions);
+        let metering_points = if metering_points.is_empty() {
+            "none listed".to_string()
+        } else {
+            metering_points.iter().map(|point| point.to_string()).collect::<Vec<_>>().join(", ")
+        };
+        println!("Account {}: {}", account.name, metering_points);
+    }
+    let sinks = SinkRegistry::from_config(&config, SINKS)?.names();
+    println!("Sinks: {}", if sinks.is_empty() { "none".to_string() } else { sinks.join(", ") });
+    Ok(())
+}
+
+/// Prints the contract in effect at the time and its fees.
+pub fn price(config: &SettingsConfig, args: &PriceArgs) -> Result<(), anyhow::Error> {
+    let contracts = match &args.point {
+        Some(code) => {
+            let metering_point = config
+                .get_accounts()
+                .into_iter()
+                .flat_map(|account| account.metering_points)
+                .find(|metering_point| &metering_point.code == code)
+                .ok_or_else(|| anyhow!("Metering point {} isn't listed in the settings", code))?;
+            config.get_metering_point_contracts(&metering_point).clone()
+        }
+        None => config.get_contracts(args.kind).clone(),
+    };
+
+    let time = args.at.to_utc();
+    let contract = contracts
+        .get_contract(time)
+        .ok_or_else(|| anyhow!("No contract in effect at {}", args.at))?;
+    let period = match contract.get_end_time() {
+        Some(end_time) => format!("{} - {}", contract.get_start_time(), end_time),
+        None => format!("from {}", contract.get_start_time()),
+    };
+    let day_or_night = if contract.get_is_night(time) { "night" } else { "day" };
+
+    println!("Contract: {} {}", contract.contract_type, period);
+    println!("Energy basic fee: {:.2} EUR/month", contract.get_energy_basic_fee());
+    match (&contract.contract_type, args.spot_price) {
+        (ContractType::Spot, Some(spot_price)) => println!(
+            "Energy fee: {:.4} c/kWh (spot {:.2} EUR/MWh, VAT {}%, margin {:.2} c/kWh)",
+            contract.get_energy_fee(spot_price, time),
+            spot_price,
+            contract.get_tax_percentage(),
+            contract.get_energy_margin()
+        ),
+        (ContractType::Spot, None) => println!(
+            "Energy fee: spot price + VAT {}% + margin {:.2} c/kWh, give --spot-price to calculate it",
+            contract.get_tax_percentage(),
+            contract.get_energy_margin()
+        ),
+        _ => println!("Energy fee: {:.4} c/kWh ({})", contract.get_energy_fee(0.0, time), day_or_night),
+    }
+    println!("Transfer basic fee: {:.2} EUR/month", contract.get_transfer_basic_fee());
+    println!("Transfer fee: {:.4} c/kWh ({})", contract.get_transfer_fee(time), day_or_night);
+    println!("Electricity tax: {:.5} c/kWh", contract.get_transfer_tax_fee());
+    Ok(())
+}
+
+/// Fetches the day-ahead prices of the range and stores them in every sink that stores prices.
+pub async fn fetch_prices(state: &AppState, args: &FetchPricesArgs) -> Result<(), anyhow::Error> {
+    let config = state.settings();
+    if config.entsoe.security_token.is_none() {
+        bail!("Set entsoe.security_token to fetch the day-ahead prices");
+    }
+    let to = args.to.unwrap_or_else(|| start_of_day_after(2));
+
+    let report = update_day_ahead_prices(&config.entsoe, &state.sinks(), args.from.to_utc(), to.to_utc()).await?;
+    if report.results.is_empty() {
+        bail!("None of the enabled sinks stores day-ahead prices");
+    }
+    for (name, result) in &report.results {
+        if let Ok(written) = result {
+            println!("{}: {} prices stored", name, written);
+        }
+    }
+    if !report.is_ok() {
+        bail!("Storing the day-ahead prices failed:\n{}", report.failure_messages().join("\n"));
+    }
+
+    let repriced = reprice_pending_intervals(&config, &state.sinks(), args.from.to_utc(), to.to_utc()).await?;
+    println!("{} intervals repriced, {} still without a price", repriced.repriced, repriced.pending.len());
+    if !repriced.failures.is_empty() {
+        bail!("Storing the repriced intervals failed:\n{}", repriced.failures.join("\n"));
+    }
+    Ok(())
+}
+
+/// Prints the runs of stored intervals whose day-ahead price is still pending.
+pub async fn unpriced(state: &AppState, args: &UnpricedArgs) -> Result<(), anyhow::Error> {
+    let to = args.to.unwrap_or_else(|| start_of_day_after(2));
+    let query = IntervalQuery {
+        start: args.from.to_utc(),
+        stop: to.to_utc(),
+        points: Vec::new(),
+        resolution: None,
+        pending_price_only: true,
+    };
+    let intervals = state.sinks().read_intervals(&query).await?;
+
+    let tz = get_timezone();
+    let format_time = |time: DateTime<Utc>| time.with_timezone(&tz).format("%Y-%m-%dT%H:%M:%S");
+    let runs = pending_price_runs(&intervals, &tz);
+    for run in &runs {
+        println!(
+            "{} {} {}: {} - {} ({} intervals)",
+            run.metering_point_code,
+            run.measurement_type,
+            run.resolution,
+            format_time(run.start),
+            format_time(run.stop),
+            run.count
+        );
+    }
+    println!("{} intervals without a price", intervals.len());
+    Ok(())
+}
+
+/// Writes the priced intervals of the range to a file or standard output.
+pub async fn export(state: &AppState, args: &ExportArgs) -> Result<(), anyhow::Error> {
+    let request = ExportRequest {
+        range: TimeRange::new(args.from.naive(), args.to.naive()),
+        selection: args.selection.selection(),
+        account: args.selection.account.clone(),
+        source: args.source,
+    };
+    let intervals = collect_intervals(state, &request).await?;
+
+    if args.format == ExportFormat::Parquet {
+        let dir = args.output.as_ref().ok_or_else(|| anyhow!("Parquet exports need an --output directory"))?;
+        let paths = write_parquet_partitions(dir, &intervals, &get_timezone())?;
+        info!("Exported {} intervals to {} Parquet files in {}", intervals.len(), paths.len(), dir.display());
+        return Ok(());
+    }
+
+    let output: Box<dyn Write + Send> = match &args.output {
+        Some(path) => Box::new(File::create(path)?),
+        None => Box::new(io::stdout()),
+    };
+    let written = write_intervals(output, args.format, &intervals)?;
+    info!("Exported {} intervals", written);
+    Ok(())
+}
+
+/// Gets a new access token for the accounts and prints when it expires.
+pub async fn token(state: &AppState, args: &TokenArgs) -> Result<(), anyhow::Error> {
+    let config = state.settings();
+    let client = state.client();
+    let retry_policy = config.wattivahti.retry.policy();
+
+    for account_config in select_accounts(&config, args.account.as_deref())? {
+        let mut tokens = TokenCache::new(TokenSource::from_config(&account_config));
+        let access_token = tokens.get(client.http(), &retry_policy).await?;
+
+        match get_token_expiry(&access_token) {
+            Some(expires_at) => {
+                let remaining = expires_at - Utc::now();
+                println!(
+                    "{}: expires at {} ({} minutes)",
+                    account_config.name,
+                    expires_at.with_timezone(&get_timezone()).format("%Y-%m-%dT%H:%M:%S%:z"),
+                    remaining.num_minutes()
+                );
+            }
+            None => println!("{}: expiry unknown, the token isn't a JWT", account_config.name),
+        }
+        if args.show {
+            println!("{}", access_token);
+        }
+    }
+    Ok(())
+}
+
+/// The named account, or every account.
+pub fn select_accounts(config: &SettingsConfig, name: Option<&str>) -> Result<Vec<AccountConfig>, anyhow::Error> {
+    let accounts = config.get_accounts();
+    match name {
+        Some(name) => accounts
+            .into_iter()
+            .find(|account| account.name == name)
+            .map(|account| vec![account])
+            .ok_or_else(|| anyhow!("No account named {}", name)),
+        None => Ok(accounts),
+    }
+}
+
+fn start_of_today() -> LocalDateTime {
+    start_of_day_after(0)
+}
+
+/// Local midnight `days` days from today.
+pub fn start_of_day_after(days: i64) -> LocalDateTime {
+    let today = Utc::now().with_timezone(&get_timezone()).date().naive_local();
+    LocalDateTime::new((today + chrono::Duration::days(days)).and_hms(0, 0, 0))
+}
+
+#[cfg(test)]
+mod tests {
+    use api::{mock::MockWattiVahti, MeasurementType};
+
+    use super::*;
+    use crate::account::Selection;
+
+    #[test]
+    fn test_select_accounts() {
+        let config = crate::settings::config::load_settings("configs/test.yaml").unwrap();
+        assert_eq!(select_accounts(&config, None).unwrap().len(), 1);
+        assert_eq!(select_accounts(&config, Some("default")).unwrap()[0].name, "default");
+        assert!(select_accounts(&config, Some("cottage")).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_selection_limits_the_fetched_points() {
+        let mock = MockWattiVahti::start().await;
+        mock.serve_fixture_for_point("4242", "consumption_2022-08-01_PT1H.json").await;
+
+        let config = crate::settings::config::load_settings("configs/test.yaml").unwrap();
+        let mut account_config = config.get_accounts().remove(0);
+        account_config.access_token = Some("token".to_string());
+        let selection = Selection { points: vec!["4242".to_string()], resolution: Some(api::ResolutionDuration::PT1H) };
+        let mut account = Account::from_config(&account_config).with_selection(selection);
+
+        let (_, metering_points) = account.prepare(&mock.client(), &config, &config.wattivahti.retry.policy()).await.unwrap();
+        assert_eq!(metering_points.len(), 1);
+        assert_eq!(metering_points[0].measurement_type(), Some(MeasurementType::Consumption));
+
+        let update = account
+            .update(&mock.client(), &config, &SinkRegistry::default(), &config.wattivahti.retry.policy(), "2022-08-01T00:00:00", "2022-08-02T00:00:00")
+            .await
+            .unwrap();
+        assert_eq!(update, AccountUpdate { fetched: 1, ..AccountUpdate::default() });
+        assert_eq!(mock.meter_data_requests().await.len(), 1);
+    }
+}
@@ -0,0 +1,74 @@
+use actix_web::{get, web, HttpResponse, Responder};
+use api::{ResolutionDuration, TimeRange};
+use serde::Deserialize;
+
+use crate::{
+    account::Selection,
+    export::{collect_intervals, write_intervals, ExportFormat, ExportRequest, ExportSource},
+    state::AppState,
+};
+
+#[derive(Deserialize)]
+pub struct ExportParams {
+    start: String,
+    stop: String,
+    /// Metering point code, every metering point by default
+    point: Option<String>,
+    resolution: Option<String>,
+    account: Option<String>,
+    #[serde(default = "default_format")]
+    format: String,
+    #[serde(default = "default_source")]
+    source: String,
+}
+
+fn default_format() -> String {
+    "csv".to_string()
+}
+
+fn default_source() -> String {
+    "stored".to_string()
+}
+
+/// Export priced intervals `/export?start=2023-01-01T00:00:00&stop=2023-02-01T00:00:00&format=csv`
+#[get("/export")]
+pub async fn export_intervals(state: web::Data<AppState>, params: web::Query<ExportParams>) -> impl Responder {
+    let request = match parse_request(&params) {
+        Ok(request) => request,
+        Err(err) => return HttpResponse::BadRequest().body(err),
+    };
+    let format: ExportFormat = match params.format.parse() {
+        Ok(format) => format,
+        Err(err) => return HttpResponse::BadRequest().body(err),
+    };
+
+    let intervals = match collect_intervals(&state, &request).await {
+        Ok(intervals) => intervals,
+        Err(err) => {
+            error!("Export failed: {:#}", err);
+            return HttpResponse::InternalServerError().body(format!("{:#}", err));
+        }
+    };
+
+    let mut body = Vec::new();
+    if let Err(err) = write_intervals(&mut body, format, &intervals) {
+        return HttpResponse::InternalServerError().body(err.to_string());
+    }
+    HttpResponse::Ok().content_type(format.content_type()).body(body)
+}
+
+fn parse_request(params: &ExportParams) -> Result<ExportRequest, String> {
+    let range = TimeRange::parse(&params.start, &params.stop).map_err(|err| format!("Invalid start or stop: {}", err))?;
+    let resolution = match &params.resolution {
+        Some(resolution) => Some(resolution.parse::<ResolutionDuration>().map_err(|err| err.to_string())?),
+        None => None,
+    };
+    let source: ExportSource = params.source.parse()?;
+
+    Ok(ExportRequest {
+        range,
+        selection: Selection { points: params.point.iter().cloned().collect(), resolution },
+        account: params.account.clone(),
+        source,
+    })
+}
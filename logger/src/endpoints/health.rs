@@ -2,5 +2,5 @@ use actix_web::{get, HttpResponse, Responder};
 
 #[get("/health")]
 pub async fn health_check() -> impl Responder {
-    return HttpResponse::Ok().json("Healthy")
+    HttpResponse::Ok().json("Healthy")
 }
@@ -1,2 +1,3 @@
+pub mod export;
 pub mod post;
 pub mod health;
\ No newline at end of file
@@ -1,7 +1,8 @@
 use crate::{
-    app::{fetch_consumption_for_interval, fetch_production_for_interval}, get_access_token, storage::timescaledb::timescale::{refresh_consumption_views, refresh_production_views}
+    account::{Account, Selection}, state::AppState
 };
 use actix_web::{post, web, HttpResponse, Responder};
+use api::{MeasurementType, ResolutionDuration};
 use serde::Deserialize;
 
 #[derive(Deserialize)]
@@ -19,79 +20,49 @@ fn default_resolution() -> String {
 /// Update metering data `/metering`
 #[post("/metering")]
 pub async fn metering_update(
+    state: web::Data<AppState>,
     params: web::Json<TimeParams>,
 ) -> impl Responder {
-    let mut access_token = dotenv::var("ACCESS_TOKEN").unwrap_or("".to_string());
-    let wattivahti_username = dotenv::var("WATTIVAHTI_USERNAME").unwrap_or("".to_string());
-    let wattivahti_password = dotenv::var("WATTIVAHTI_PASSWORD").unwrap_or("".to_string());
-    let wattivahti_token_endpoint =
-        dotenv::var("WATTIVAHTI_TOKEN_ENDPOINT").unwrap_or("".to_string());
+    let resolution: ResolutionDuration = match params.resolution.parse() {
+        Ok(resolution) => resolution,
+        Err(err) => return HttpResponse::BadRequest().body(err.to_string()),
+    };
 
-    // If credentials provided, use those instead of the given access token (if access token was even given)
-    if !wattivahti_username.is_empty() {
-        let result = get_access_token(
-            &wattivahti_token_endpoint,
-            &wattivahti_username,
-            &wattivahti_password,
-        )
-        .await;
-        if result.is_err() {
-            return HttpResponse::InternalServerError().body(format!(
-                "Error {:?}",
-                "Logging {} - {} failed because of accessToken"
-            ));
-        }
-        let result = result.unwrap();
-        if result.access_token.is_none() {
-            return HttpResponse::InternalServerError().body(format!(
-                "Error {:?}",
-                "Logging {} - {} failed because of accessToken"
-            ));
-        }
-        access_token = result.access_token.unwrap();
-    }
+    let config = state.settings();
+    let client = state.client();
+    let sinks = state.sinks();
 
-    if let Err(err) = fetch_consumption_for_interval(
-        &access_token,
-        &params.start,
-        &params.stop,
-        &params.resolution,
-    )
-    .await
-    {
-        // Handle the error here
-        error!("Error fetching consumptions: {:?}", err);
-        // Return an appropriate response
-        return HttpResponse::InternalServerError().body(err.to_string());
-    }
-
-    if let Err(err) = fetch_production_for_interval(
-        &access_token,
-        &params.start,
-        &params.stop,
-        &params.resolution,
-    )
-    .await
-    {
-        // Handle the error here
-        error!("Error fetching productions: {:?}", err);
-        // Return an appropriate response
-        return HttpResponse::InternalServerError().body(err.to_string());
+    // Update every account even if some of them fail
+    let mut failures = Vec::new();
+    for account_config in config.get_accounts() {
+        let selection = Selection { resolution: Some(resolution), ..Selection::default() };
+        let mut account = Account::from_config(&account_config).with_selection(selection);
+        match account
+            .update(&client, &config, &sinks, &config.wattivahti.retry.policy(), &params.start, &params.stop)
+            .await
+        {
+            Ok(update) if update.failed > 0 => {
+                failures.push(format!("{}: {} of {} fetches failed", account.name, update.failed, update.failed + update.fetched))
+            }
+            Ok(update) if update.store_failed > 0 => {
+                failures.push(format!("{}: {} of {} fetches were not stored", account.name, update.store_failed, update.fetched))
+            }
+            Ok(_) => {}
+            Err(err) => failures.push(format!("{}: {}", account.name, err)),
+        }
     }
 
-    if let Err(err) = refresh_consumption_views().await {
-        // Handle the error here
-        error!("Error refreshing the consumption views: {:?}", err);
-        // Return an appropriate response
-        return HttpResponse::InternalServerError().body(err.to_string());
+    if !failures.is_empty() {
+        error!("Error updating accounts: {:?}", failures);
+        return HttpResponse::InternalServerError().body(failures.join("\n"));
     }
 
-    if let Err(err) = refresh_production_views().await {
-        // Handle the error here
-        error!("Error refreshing the production views: {:?}", err);
-        // Return an appropriate response
-        return HttpResponse::InternalServerError().body(err.to_string());
+    for measurement_type in [MeasurementType::Consumption, MeasurementType::Production] {
+        let report = sinks.refresh(measurement_type).await;
+        if !report.is_ok() {
+            return HttpResponse::InternalServerError().body(report.failure_messages().join("\n"));
+        }
     }
 
-    return HttpResponse::Ok().body("ok");
+    HttpResponse::Ok().body("ok")
 }
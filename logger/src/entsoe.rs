@@ -0,0 +1,396 @@
+//! Day-ahead prices from the ENTSO-E Transparency Platform, parsed from A44 publication documents.
+//!
+//! Prices are in EUR/MWh without VAT like the documents, see [`crate::pricing`] for the units
+//! used elsewhere.
+
+use api::retry::{is_transient_status, retry};
+use api::{ResolutionDuration, RetryPolicy, Retryable};
+use chrono::{DateTime, Duration as ChronoDuration, NaiveDateTime, Utc};
+use reqwest::StatusCode;
+use roxmltree::{Document, Node};
+use thiserror::Error;
+
+use crate::settings::config_model::EntsoeConfig;
+use crate::storage::sink::{SinkRegistry, SinkReport};
+
+/// Document type of day-ahead prices.
+pub const DAY_AHEAD_PRICES: &str = "A44";
+
+/// The API answers one year at most per request.
+const MAX_REQUEST_DAYS: i64 = 365;
+
+/// EIC codes of the bidding zones around Finland.
+const BIDDING_ZONES: &[(&str, &str)] = &[
+    ("FI", "10YFI-1--------U"),
+    ("EE", "10Y1001A1001A39I"),
+    ("LV", "10YLV-1001A00074"),
+    ("LT", "10YLT-1001A0008Q"),
+    ("SE1", "10Y1001A1001A44P"),
+    ("SE2", "10Y1001A1001A45N"),
+    ("SE3", "10Y1001A1001A46L"),
+    ("SE4", "10Y1001A1001A47J"),
+    ("NO1", "10YNO-1--------2"),
+    ("NO2", "10YNO-2--------T"),
+    ("NO3", "10YNO-3--------J"),
+    ("NO4", "10YNO-4--------9"),
+    ("NO5", "10Y1001A1001A48H"),
+    ("DK1", "10YDK-1--------W"),
+    ("DK2", "10YDK-2--------M"),
+];
+
+/// EIC code of a bidding zone name such as FI, or the code itself when it already is one.
+pub fn bidding_zone_eic(zone: &str) -> Option<&str> {
+    if let Some((_, eic)) = BIDDING_ZONES.iter().find(|(name, _)| name.eq_ignore_ascii_case(zone)) {
+        return Some(eic);
+    }
+    if zone.len() == 16 && zone.is_ascii() {
+        return Some(zone);
+    }
+    None
+}
+
+#[derive(Error, Debug)]
+pub enum EntsoeError {
+    #[error("ENTSO-E responded with {status}: {body}")]
+    Server { status: StatusCode, body: String },
+    #[error("ENTSO-E rejected the request: {0}")]
+    Acknowledgement(String),
+    #[error("Failed to parse the A44 document: {0}")]
+    Xml(#[from] roxmltree::Error),
+    #[error("Invalid A44 document: {0}")]
+    Invalid(String),
+    #[error("Request to ENTSO-E failed: {0}")]
+    Transport(#[from] reqwest::Error),
+}
+
+impl Retryable for EntsoeError {
+    fn is_transient(&self) -> bool {
+        match self {
+            EntsoeError::Server { status, .. } => is_transient_status(*status),
+            EntsoeError::Transport(err) => err.is_timeout() || err.is_connect(),
+            _ => false,
+        }
+    }
+}
+
+/// Price of one interval of a curve, with the metadata of its time series.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DayAheadPrice {
+    /// Start of the interval
+    pub time: DateTime<Utc>,
+    pub resolution: ResolutionDuration,
+    /// EUR/MWh without VAT
+    pub price: f32,
+    pub in_domain: String,
+    pub out_domain: String,
+    pub currency: String,
+    pub price_measure: String,
+    /// A01 for a point per interval, A03 when points equal to the previous one are left out
+    pub curve_type: String,
+}
+
+/// Parses an A44 publication document into prices in time order. An acknowledgement that no
+/// data matched is an empty result, any other acknowledgement an error. When the document has
+/// curves of several resolutions, the finest price wins at the times they share.
+pub fn parse_day_ahead_prices(xml: &str) -> Result<Vec<DayAheadPrice>, EntsoeError> {
+    let document = Document::parse(xml)?;
+    let root = document.root_element();
+
+    match root.tag_name().name() {
+        "Publication_MarketDocument" => {}
+        "Acknowledgement_MarketDocument" => {
+            let reason = child(root, "Reason").and_then(|reason| child_text(reason, "text")).unwrap_or_default();
+            if reason.contains("No matching data found") {
+                return Ok(Vec::new());
+            }
+            return Err(EntsoeError::Acknowledgement(reason.to_string()));
+        }
+        name => return Err(EntsoeError::Invalid(format!("unexpected root element {}", name))),
+    }
+
+    let document_type = child_text(root, "type").unwrap_or_default();
+    if document_type != DAY_AHEAD_PRICES {
+        return Err(EntsoeError::Invalid(format!("document type {} instead of {}", document_type, DAY_AHEAD_PRICES)));
+    }
+
+    let mut prices: Vec<DayAheadPrice> = Vec::new();
+    for series in root.children().filter(|node| node.has_tag_name("TimeSeries")) {
+        parse_time_series(series, &mut prices)?;
+    }
+
+    prices.sort_by(|a, b| a.time.cmp(&b.time).then_with(|| a.resolution.minutes().cmp(&b.resolution.minutes())));
+    prices.dedup_by(|later, earlier| later.time == earlier.time);
+    Ok(prices)
+}
+
+fn parse_time_series(series: Node, prices: &mut Vec<DayAheadPrice>) -> Result<(), EntsoeError> {
+    let text = |name: &str| child_text(series, name).map(str::to_string).ok_or_else(|| missing(name));
+    let in_domain = text("in_Domain.mRID")?;
+    let out_domain = text("out_Domain.mRID")?;
+    let currency = text("currency_Unit.name")?;
+    let price_measure = text("price_Measure_Unit.name")?;
+    // A01 is the default of the schema
+    let curve_type = child_text(series, "curveType").unwrap_or("A01").to_string();
+
+    for period in series.children().filter(|node| node.has_tag_name("Period")) {
+        let interval = child(period, "timeInterval").ok_or_else(|| missing("timeInterval"))?;
+        let start = parse_time(child_text(interval, "start").ok_or_else(|| missing("start"))?)?;
+        let end = parse_time(child_text(interval, "end").ok_or_else(|| missing("end"))?)?;
+        let resolution_text = child_text(period, "resolution").ok_or_else(|| missing("resolution"))?;
+        let resolution: ResolutionDuration =
+            resolution_text.parse().map_err(|_| EntsoeError::Invalid(format!("unknown resolution {}", resolution_text)))?;
+        if !resolution.is_fixed() {
+            return Err(EntsoeError::Invalid(format!("resolution {} isn't a fixed step", resolution)));
+        }
+
+        let step = ChronoDuration::minutes(resolution.minutes() as i64);
+        let count = ((end - start).num_minutes() / step.num_minutes()) as usize;
+
+        let mut points = Vec::new();
+        for point in period.children().filter(|node| node.has_tag_name("Point")) {
+            let position = parse_number::<usize>(point, "position")?;
+            if position == 0 || position > count {
+                return Err(EntsoeError::Invalid(format!("position {} outside of the {} intervals of the period", position, count)));
+            }
+            points.push((position, parse_number::<f32>(point, "price.amount")?));
+        }
+        points.sort_by_key(|(position, _)| *position);
+        if curve_type == "A01" && points.len() != count {
+            return Err(EntsoeError::Invalid(format!("{} of the {} points of an A01 curve", points.len(), count)));
+        }
+
+        // A03 curves leave out the points whose price equals the previous one
+        let mut next = points.iter().peekable();
+        let mut price = None;
+        for position in 1..=count {
+            if let Some((_, value)) = next.next_if(|(point_position, _)| *point_position == position) {
+                price = Some(*value);
+            }
+            let price = match price {
+                Some(price) => price,
+                None => return Err(EntsoeError::Invalid(format!("no price for the first position of the period starting at {}", start))),
+            };
+
+            prices.push(DayAheadPrice {
+                time: start + step * (position as i32 - 1),
+                resolution,
+                price,
+                in_domain: in_domain.clone(),
+                out_domain: out_domain.clone(),
+                currency: currency.clone(),
+                price_measure: price_measure.clone(),
+                curve_type: curve_type.clone(),
+            });
+        }
+    }
+    Ok(())
+}
+
+/// Fetches the day-ahead prices of the bidding zone for `start..stop`, a year per request.
+pub async fn fetch_day_ahead_prices(
+    config: &EntsoeConfig,
+    start: DateTime<Utc>,
+    stop: DateTime<Utc>,
+    retry_policy: &RetryPolicy,
+) -> Result<Vec<DayAheadPrice>, EntsoeError> {
+    let zone = bidding_zone_eic(&config.bidding_zone)
+        .ok_or_else(|| EntsoeError::Invalid(format!("unknown bidding zone {}", config.bidding_zone)))?;
+    let token = config.security_token.as_deref().unwrap_or_default();
+
+    let mut prices = Vec::new();
+    let mut window_start = start;
+    while window_start < stop {
+        let window_stop = (window_start + ChronoDuration::days(MAX_REQUEST_DAYS)).min(stop);
+        info!("Fetching the day-ahead prices of {} for {} - {}", config.bidding_zone, window_start, window_stop);
+
+        let xml = retry(retry_policy, "Fetching the day-ahead prices", || {
+            request_day_ahead_prices(&config.api_url, token, zone, window_start, window_stop)
+        })
+        .await?;
+        prices.extend(parse_day_ahead_prices(&xml)?.into_iter().filter(|price| price.time >= start && price.time < stop));
+        window_start = window_stop;
+    }
+    Ok(prices)
+}
+
+/// Fetches the day-ahead prices of `start..stop` and upserts them into every sink that stores
+/// prices.
+pub async fn update_day_ahead_prices(
+    config: &EntsoeConfig,
+    sinks: &SinkRegistry,
+    start: DateTime<Utc>,
+    stop: DateTime<Utc>,
+) -> Result<SinkReport<usize>, EntsoeError> {
+    let prices = fetch_day_ahead_prices(config, start, stop, &RetryPolicy::default()).await?;
+    info!("Fetched {} day-ahead prices for {} - {}", prices.len(), start, stop);
+    Ok(sinks.write_day_ahead_prices(&prices).await)
+}
+
+async fn request_day_ahead_prices(
+    api_url: &str,
+    token: &str,
+    zone: &str,
+    start: DateTime<Utc>,
+    stop: DateTime<Utc>,
+) -> Result<String, EntsoeError> {
+    let res = reqwest::Client::new()
+        .get(api_url)
+        .query(&[
+            ("securityToken", token),
+            ("documentType", DAY_AHEAD_PRICES),
+            ("in_Domain", zone),
+            ("out_Domain", zone),
+            ("periodStart", &start.format("%Y%m%d%H%M").to_string()),
+            ("periodEnd", &stop.format("%Y%m%d%H%M").to_string()),
+        ])
+        .send()
+        .await?;
+
+    let status = res.status();
+    let body = res.text().await?;
+
+    // Rejected requests are explained in an acknowledgement document
+    if status != StatusCode::OK && !body.contains("Acknowledgement_MarketDocument") {
+        return Err(EntsoeError::Server { status, body });
+    }
+    Ok(body)
+}
+
+fn child<'a, 'input>(node: Node<'a, 'input>, name: &str) -> Option<Node<'a, 'input>> {
+    node.children().find(|child| child.has_tag_name(name))
+}
+
+fn child_text<'a>(node: Node<'a, '_>, name: &str) -> Option<&'a str> {
+    child(node, name).and_then(|child| child.text()).map(str::trim)
+}
+
+fn parse_number<T: std::str::FromStr>(node: Node, name: &str) -> Result<T, EntsoeError> {
+    let text = child_text(node, name).ok_or_else(|| missing(name))?;
+    text.parse().map_err(|_| EntsoeError::Invalid(format!("{} {:?} isn't a number", name, text)))
+}
+
+/// Times of the documents are UTC without seconds, e.g. `2023-10-28T22:00Z`.
+fn parse_time(text: &str) -> Result<DateTime<Utc>, EntsoeError> {
+    NaiveDateTime::parse_from_str(text, "%Y-%m-%dT%H:%MZ")
+        .map(|time| DateTime::from_utc(time, Utc))
+        .map_err(|_| EntsoeError::Invalid(format!("invalid time {}", text)))
+}
+
+fn missing(name: &str) -> EntsoeError {
+    EntsoeError::Invalid(format!("missing {}", name))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+    use std::sync::{Arc, Mutex};
+
+    use chrono::TimeZone;
+    use wiremock::matchers::{method, query_param};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    use super::*;
+    use crate::storage::memory::MemoryStore;
+
+    fn fixture(name: &str) -> String {
+        std::fs::read_to_string(Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures").join(name)).unwrap()
+    }
+
+    #[test]
+    fn test_parse_hourly_prices_of_a_dst_change() {
+        let prices = parse_day_ahead_prices(&fixture("entsoe_a44_FI_2023-10-29_PT60M.xml")).unwrap();
+
+        // The local day of the autumn DST change has 25 hours
+        assert_eq!(prices.len(), 25);
+        assert_eq!(prices[0].time, Utc.ymd(2023, 10, 28).and_hms(22, 0, 0));
+        assert_eq!(prices[24].time, Utc.ymd(2023, 10, 29).and_hms(22, 0, 0));
+        assert!(prices.iter().all(|price| price.resolution == ResolutionDuration::PT1H));
+        assert_eq!(prices[0].price, 43.5);
+        assert_eq!(prices[2].price, -1.23);
+        assert_eq!(prices[0].in_domain, "10YFI-1--------U");
+        assert_eq!(prices[0].currency, "EUR");
+        assert_eq!(prices[0].price_measure, "MWH");
+        assert_eq!(prices[0].curve_type, "A01");
+    }
+
+    #[test]
+    fn test_parse_quarter_hour_prices() {
+        let prices = parse_day_ahead_prices(&fixture("entsoe_a44_FI_2025-10-01_PT15M.xml")).unwrap();
+
+        // The quarter hours win over the hourly curve of the same day
+        assert_eq!(prices.len(), 96);
+        assert!(prices.iter().all(|price| price.resolution == ResolutionDuration::PT15M));
+        let start = Utc.ymd(2025, 9, 30).and_hms(21, 0, 0);
+        for (index, price) in prices.iter().enumerate() {
+            assert_eq!(price.time, start + ChronoDuration::minutes(15 * index as i64));
+        }
+        assert_eq!(prices[0].price, 50.0);
+        assert_eq!(prices[3].price, 50.75);
+
+        // Left out points of the A03 curve repeat the previous price
+        assert_eq!(prices[4].price, 50.75);
+        assert_eq!(prices[7].price, 50.75);
+        assert_eq!(prices[8].price, 52.0);
+        assert_eq!(prices[43].price, 59.75);
+        assert_eq!(prices[44].price, 61.0);
+    }
+
+    #[test]
+    fn test_parse_acknowledgements() {
+        assert!(parse_day_ahead_prices(&fixture("entsoe_acknowledgement_no_data.xml")).unwrap().is_empty());
+
+        let rejected = fixture("entsoe_acknowledgement_no_data.xml").replace("No matching data found", "Invalid security token");
+        assert!(matches!(parse_day_ahead_prices(&rejected), Err(EntsoeError::Acknowledgement(_))));
+    }
+
+    #[test]
+    fn test_parse_invalid_documents() {
+        assert!(matches!(parse_day_ahead_prices("<Publication_MarketDocument>"), Err(EntsoeError::Xml(_))));
+
+        // A01 curves must have every point
+        let missing_point = fixture("entsoe_a44_FI_2023-10-29_PT60M.xml").replacen("<position>25</position>", "<position>26</position>", 1);
+        assert!(matches!(parse_day_ahead_prices(&missing_point), Err(EntsoeError::Invalid(_))));
+        let position_zero = fixture("entsoe_a44_FI_2023-10-29_PT60M.xml").replacen("<curveType>A01</curveType>", "", 1).replacen(
+            "<position>1</position>",
+            "<position>0</position>",
+            1,
+        );
+        assert!(matches!(parse_day_ahead_prices(&position_zero), Err(EntsoeError::Invalid(_))));
+    }
+
+    #[test]
+    fn test_bidding_zone_eic() {
+        assert_eq!(bidding_zone_eic("FI"), Some("10YFI-1--------U"));
+        assert_eq!(bidding_zone_eic("se3"), Some("10Y1001A1001A46L"));
+        assert_eq!(bidding_zone_eic("10YFI-1--------U"), Some("10YFI-1--------U"));
+        assert_eq!(bidding_zone_eic("Finland"), None);
+    }
+
+    #[tokio::test]
+    async fn test_update_day_ahead_prices() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(query_param("securityToken", "token"))
+            .and(query_param("documentType", "A44"))
+            .and(query_param("in_Domain", "10YFI-1--------U"))
+            .and(query_param("periodStart", "202310282200"))
+            .and(query_param("periodEnd", "202310292300"))
+            .respond_with(ResponseTemplate::new(200).set_body_string(fixture("entsoe_a44_FI_2023-10-29_PT60M.xml")))
+            .mount(&server)
+            .await;
+
+        let config = EntsoeConfig { api_url: server.uri(), security_token: Some("token".to_string()), ..EntsoeConfig::default() };
+        let store = Arc::new(Mutex::new(MemoryStore::default()));
+        let mut sinks = SinkRegistry::default();
+        sinks.register(store.clone());
+
+        let start = Utc.ymd(2023, 10, 28).and_hms(22, 0, 0);
+        let stop = Utc.ymd(2023, 10, 29).and_hms(23, 0, 0);
+        let report = update_day_ahead_prices(&config, &sinks, start, stop).await.unwrap();
+        assert!(report.is_ok());
+        assert_eq!(report.results[0].1.as_ref().unwrap(), &25);
+
+        let stored = store.lock().unwrap().day_ahead_prices(start, stop);
+        assert_eq!(stored.get(start + ChronoDuration::hours(2)), Some(-1.23));
+    }
+}
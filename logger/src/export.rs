@@ -0,0 +1,373 @@
+//! Priced intervals as CSV or JSON Lines for spreadsheets and accounting, or as Parquet for
+//! archival and analytics.
+
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
+
+use api::{LocalDateTime, MeasurementType, TimeRange};
+use arrow_array::{ArrayRef, BooleanArray, Float32Array, RecordBatch, StringArray, TimestampMicrosecondArray};
+use arrow_schema::{DataType, Field, Schema, TimeUnit};
+use chrono::Datelike;
+use chrono_tz::Tz;
+use parquet::arrow::ArrowWriter;
+use parquet::basic::Compression;
+use parquet::file::properties::WriterProperties;
+use serde::Serialize;
+
+use crate::account::{Account, Selection};
+use crate::app::fetch_priced_intervals;
+use crate::commands::select_accounts;
+use crate::pricing::PricedInterval;
+use crate::settings::time::get_timezone;
+use crate::state::AppState;
+use crate::storage::sink::IntervalQuery;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ExportFormat {
+    Csv,
+    Jsonl,
+    Parquet,
+}
+
+impl ExportFormat {
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            ExportFormat::Csv => "text/csv; charset=utf-8",
+            ExportFormat::Jsonl => "application/x-ndjson",
+            ExportFormat::Parquet => "application/vnd.apache.parquet",
+        }
+    }
+}
+
+impl FromStr for ExportFormat {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.to_lowercase().as_str() {
+            "csv" => Ok(ExportFormat::Csv),
+            "jsonl" | "ndjson" => Ok(ExportFormat::Jsonl),
+            "parquet" => Ok(ExportFormat::Parquet),
+            _ => Err(format!("Unknown export format {}, expected csv, jsonl or parquet", value)),
+        }
+    }
+}
+
+/// Where the exported intervals come from.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ExportSource {
+    /// Read back from the first sink that supports it, TimescaleDB before SQLite
+    Stored,
+    /// Fetched from WattiVahti and priced without storing them
+    Fetch,
+}
+
+impl FromStr for ExportSource {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.to_lowercase().as_str() {
+            "stored" => Ok(ExportSource::Stored),
+            "fetch" => Ok(ExportSource::Fetch),
+            _ => Err(format!("Unknown export source {}, expected stored or fetch", value)),
+        }
+    }
+}
+
+/// Intervals of the range to export, consumption and production of every selected metering point.
+pub struct ExportRequest {
+    pub range: TimeRange,
+    pub selection: Selection,
+    /// Fetch only the named account
+    pub account: Option<String>,
+    pub source: ExportSource,
+}
+
+/// Row of an export. Fees are in c/kWh and basic fees in EUR/month like in the settings.
+#[derive(Debug, Serialize)]
+pub struct ExportRecord<'a> {
+    /// Start of the interval in UTC
+    pub time: String,
+    pub metering_point_code: &'a str,
+    pub measurement_type: &'static str,
+    pub resolution: String,
+    pub unit: &'a str,
+    pub value: f32,
+    pub contract_type: String,
+    pub night: bool,
+    /// c/kWh without VAT
+    pub spot_price: Option<f32>,
+    pub energy_basic_fee: f32,
+    pub energy_fee: Option<f32>,
+    pub energy_margin: f32,
+    pub transfer_basic_fee: f32,
+    pub transfer_fee: f32,
+    pub transfer_tax_fee: f32,
+    pub tax_percentage: f32,
+}
+
+impl<'a> From<&'a PricedInterval> for ExportRecord<'a> {
+    fn from(interval: &'a PricedInterval) -> Self {
+        Self {
+            time: interval.time.format("%Y-%m-%dT%H:%M:%SZ").to_string(),
+            metering_point_code: &interval.metering_point_code,
+            measurement_type: match interval.measurement_type {
+                MeasurementType::Consumption => "consumption",
+                MeasurementType::Production => "production",
+                MeasurementType::Other(_) => "other",
+            },
+            resolution: interval.resolution.to_string(),
+            unit: &interval.unit,
+            value: interval.value,
+            contract_type: interval.contract_type.to_string(),
+            night: interval.night,
+            spot_price: interval.spot_price,
+            energy_basic_fee: interval.energy_basic_fee,
+            energy_fee: interval.energy_fee,
+            energy_margin: interval.energy_margin,
+            transfer_basic_fee: interval.transfer_basic_fee,
+            transfer_fee: interval.transfer_fee,
+            transfer_tax_fee: interval.transfer_tax_fee,
+            tax_percentage: interval.tax_percentage,
+        }
+    }
+}
+
+/// Writes the intervals with a header row for CSV, or as a single Parquet file, returns how many
+/// were written.
+pub fn write_intervals<W: Write + Send>(output: W, format: ExportFormat, intervals: &[PricedInterval]) -> Result<usize, anyhow::Error> {
+    match format {
+        ExportFormat::Csv => {
+            let mut writer = csv::Writer::from_writer(output);
+            for interval in intervals {
+                writer.serialize(ExportRecord::from(interval))?;
+            }
+            writer.flush()?;
+        }
+        ExportFormat::Jsonl => {
+            let mut output = output;
+            for interval in intervals {
+                serde_json::to_writer(&mut output, &ExportRecord::from(interval))?;
+                writeln!(output)?;
+            }
+            output.flush()?;
+        }
+        ExportFormat::Parquet => {
+            let properties = WriterProperties::builder().set_compression(Compression::SNAPPY).build();
+            let mut writer = ArrowWriter::try_new(output, parquet_schema(), Some(properties))?;
+            writer.write(&to_record_batch(intervals)?)?;
+            writer.close()?;
+        }
+    }
+    Ok(intervals.len())
+}
+
+/// Writes a Parquet file per month of local time, `<dir>/month=YYYY-MM/energies.parquet`, so that
+/// DuckDB and Polars can read the directory as a Hive partitioned dataset. Files of the months in
+/// `intervals` are replaced, so export whole months. Returns the written files.
+pub fn write_parquet_partitions(dir: &Path, intervals: &[PricedInterval], tz: &Tz) -> Result<Vec<PathBuf>, anyhow::Error> {
+    let mut months: BTreeMap<(i32, u32), Vec<PricedInterval>> = BTreeMap::new();
+    for interval in intervals {
+        let local = interval.time.with_timezone(tz);
+        months.entry((local.year(), local.month())).or_default().push(interval.clone());
+    }
+
+    let mut paths = Vec::new();
+    for ((year, month), intervals) in months {
+        let partition = dir.join(format!("month={:04}-{:02}", year, month));
+        fs::create_dir_all(&partition)?;
+
+        let path = partition.join("energies.parquet");
+        write_intervals(File::create(&path)?, ExportFormat::Parquet, &intervals)?;
+        debug!("Wrote {} intervals to {}", intervals.len(), path.display());
+        paths.push(path);
+    }
+    Ok(paths)
+}
+
+/// Columns of the Parquet files. Prices and fees are in the same units as in CSV.
+fn parquet_schema() -> Arc<Schema> {
+    let fee = |name: &str, nullable: bool| Field::new(name, DataType::Float32, nullable);
+
+    Arc::new(Schema::new(vec![
+        Field::new("time", DataType::Timestamp(TimeUnit::Microsecond, Some("UTC".to_string())), false),
+        Field::new("metering_point_code", DataType::Utf8, false),
+        Field::new("measurement_type", DataType::Utf8, false),
+        Field::new("resolution", DataType::Utf8, false),
+        Field::new("unit", DataType::Utf8, false),
+        Field::new("value", DataType::Float32, false),
+        Field::new("contract_type", DataType::Utf8, false),
+        Field::new("night", DataType::Boolean, false),
+        fee("spot_price", true),
+        fee("energy_basic_fee", false),
+        fee("energy_fee", true),
+        fee("energy_margin", false),
+        fee("transfer_basic_fee", false),
+        fee("transfer_fee", false),
+        fee("transfer_tax_fee", false),
+        fee("tax_percentage", false),
+    ]))
+}
+
+fn to_record_batch(intervals: &[PricedInterval]) -> Result<RecordBatch, anyhow::Error> {
+    let records: Vec<ExportRecord> = intervals.iter().map(ExportRecord::from).collect();
+    let strings = |column: for<'r> fn(&'r ExportRecord<'_>) -> &'r str| -> ArrayRef {
+        Arc::new(StringArray::from_iter_values(records.iter().map(column)))
+    };
+    let floats = |column: fn(&PricedInterval) -> Option<f32>| -> ArrayRef { Arc::new(intervals.iter().map(column).collect::<Float32Array>()) };
+
+    let columns: Vec<ArrayRef> = vec![
+        Arc::new(TimestampMicrosecondArray::from_iter_values(intervals.iter().map(|interval| interval.time.timestamp_nanos() / 1000)).with_timezone("UTC")),
+        strings(|record| record.metering_point_code),
+        strings(|record| record.measurement_type),
+        strings(|record| &record.resolution),
+        strings(|record| record.unit),
+        floats(|interval| Some(interval.value)),
+        strings(|record| &record.contract_type),
+        Arc::new(intervals.iter().map(|interval| Some(interval.night)).collect::<BooleanArray>()),
+        floats(|interval| interval.spot_price),
+        floats(|interval| Some(interval.energy_basic_fee)),
+        floats(|interval| interval.energy_fee),
+        floats(|interval| Some(interval.energy_margin)),
+        floats(|interval| Some(interval.transfer_basic_fee)),
+        floats(|interval| Some(interval.transfer_fee)),
+        floats(|interval| Some(interval.transfer_tax_fee)),
+        floats(|interval| Some(interval.tax_percentage)),
+    ];
+    Ok(RecordBatch::try_new(parquet_schema(), columns)?)
+}
+
+/// Intervals of the request in time order.
+pub async fn collect_intervals(state: &AppState, request: &ExportRequest) -> Result<Vec<PricedInterval>, anyhow::Error> {
+    let config = state.settings();
+    let sinks = state.sinks();
+
+    let mut intervals = match request.source {
+        ExportSource::Stored => {
+            let tz = get_timezone();
+            let query = IntervalQuery {
+                start: LocalDateTime::new(request.range.start).to_utc_in(&tz),
+                stop: LocalDateTime::new(request.range.stop).to_utc_in(&tz),
+                points: request.selection.points.clone(),
+                resolution: request.selection.resolution,
+                pending_price_only: false,
+            };
+            sinks.read_intervals(&query).await?
+        }
+        ExportSource::Fetch => {
+            let client = state.client();
+            let retry_policy = config.wattivahti.retry.policy();
+            let mut intervals = Vec::new();
+
+            for account_config in select_accounts(&config, request.account.as_deref())? {
+                let mut account = Account::from_config(&account_config).with_selection(request.selection.clone());
+                let (access_token, metering_points) = account.prepare(&client, &config, &retry_policy).await?;
+
+                for metering_point in &metering_points {
+                    for resolution in &metering_point.resolutions {
+                        intervals.extend(
+                            fetch_priced_intervals(&client, &access_token, &config, &sinks, metering_point, &request.range, resolution).await?,
+                        );
+                    }
+                }
+            }
+            intervals
+        }
+    };
+
+    intervals.sort_by(|a, b| a.time.cmp(&b.time).then_with(|| a.metering_point_code.cmp(&b.metering_point_code)));
+    Ok(intervals)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pricing::{price_intervals, time_span, SpotPrices};
+
+    fn priced_intervals() -> Vec<PricedInterval> {
+        let config = crate::settings::config::load_settings("configs/test.yaml").unwrap();
+        let data = serde_json::from_str(&api::mock::fixture("consumption_2022-08-01_PT1H.json")).unwrap();
+        let (start, _) = time_span(&data);
+        let prices: SpotPrices = vec![(start, 123.0)].into_iter().collect();
+        price_intervals(&data, MeasurementType::Consumption, config.get_contracts(MeasurementType::Consumption), &prices)
+    }
+
+    #[test]
+    fn test_write_csv() {
+        let intervals = priced_intervals();
+        let mut output = Vec::new();
+        assert_eq!(write_intervals(&mut output, ExportFormat::Csv, &intervals).unwrap(), 24);
+
+        let output = String::from_utf8(output).unwrap();
+        let lines: Vec<&str> = output.lines().collect();
+        assert_eq!(lines.len(), 25);
+        assert_eq!(
+            lines[0],
+            "time,metering_point_code,measurement_type,resolution,unit,value,contract_type,night,spot_price,energy_basic_fee,\
+             energy_fee,energy_margin,transfer_basic_fee,transfer_fee,transfer_tax_fee,tax_percentage"
+        );
+        assert!(lines[1].starts_with(&format!("{},{},consumption,PT1H,", ExportRecord::from(&intervals[0]).time, intervals[0].metering_point_code)));
+        assert!(lines[1].contains(",12.3,"));
+        // Missing prices are left empty
+        assert!(lines[2].contains(&format!(",{},,", intervals[1].night)));
+    }
+
+    #[test]
+    fn test_write_jsonl() {
+        let intervals = priced_intervals();
+        let mut output = Vec::new();
+        write_intervals(&mut output, ExportFormat::Jsonl, &intervals).unwrap();
+
+        let output = String::from_utf8(output).unwrap();
+        let records: Vec<serde_json::Value> = output.lines().map(|line| serde_json::from_str(line).unwrap()).collect();
+        assert_eq!(records.len(), 24);
+        assert_eq!(records[0]["time"], "2022-07-31T21:00:00Z");
+        assert_eq!(records[0]["measurement_type"], "consumption");
+        assert_eq!(records[0]["contract_type"], intervals[0].contract_type.to_string());
+        assert!(records[1]["spot_price"].is_null());
+    }
+
+    #[test]
+    fn test_write_parquet_partitions() {
+        use arrow_array::Array;
+        use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+
+        let mut intervals = priced_intervals();
+        // The first hour moves to the last day of July in local time
+        intervals[0].time = intervals[0].time - chrono::Duration::days(1);
+
+        let dir = tempfile::tempdir().unwrap();
+        let paths = write_parquet_partitions(dir.path(), &intervals, &chrono_tz::Europe::Helsinki).unwrap();
+        assert_eq!(paths, vec![dir.path().join("month=2022-07/energies.parquet"), dir.path().join("month=2022-08/energies.parquet")]);
+
+        let batches: Vec<RecordBatch> = ParquetRecordBatchReaderBuilder::try_new(File::open(&paths[1]).unwrap())
+            .unwrap()
+            .build()
+            .unwrap()
+            .map(|batch| batch.unwrap())
+            .collect();
+        assert_eq!(batches.iter().map(|batch| batch.num_rows()).sum::<usize>(), 23);
+
+        let batch = &batches[0];
+        assert_eq!(batch.schema(), parquet_schema());
+        let times = batch.column(0).as_any().downcast_ref::<TimestampMicrosecondArray>().unwrap();
+        assert_eq!(times.value(0), intervals[1].time.timestamp_nanos() / 1000);
+        let spot_prices = batch.column(8).as_any().downcast_ref::<Float32Array>().unwrap();
+        assert!(spot_prices.is_null(0));
+        let values = batch.column(5).as_any().downcast_ref::<Float32Array>().unwrap();
+        assert_eq!(values.value(0), intervals[1].value);
+    }
+
+    #[test]
+    fn test_parse_format_and_source() {
+        assert_eq!("CSV".parse::<ExportFormat>().unwrap(), ExportFormat::Csv);
+        assert_eq!("ndjson".parse::<ExportFormat>().unwrap(), ExportFormat::Jsonl);
+        assert_eq!("parquet".parse::<ExportFormat>().unwrap(), ExportFormat::Parquet);
+        assert!("xlsx".parse::<ExportFormat>().is_err());
+        assert_eq!("fetch".parse::<ExportSource>().unwrap(), ExportSource::Fetch);
+        assert!("influx".parse::<ExportSource>().is_err());
+    }
+}
@@ -68,9 +68,14 @@ fn file_format(
     )
 }
 
-pub fn init_logging() {
-    let handle = flexi_logger::Logger::with_env_or_str("info")
-        .print_message()
+/// `print_message` tells where the log file is on standard output, which commands that write
+/// their results there leave out.
+pub fn init_logging(print_message: bool) {
+    let mut logger = flexi_logger::Logger::with_env_or_str("info");
+    if print_message {
+        logger = logger.print_message();
+    }
+    let handle = logger
         .log_to_file()
         .rotate(
             Criterion::Age(Age::Day),
@@ -1,89 +1,113 @@
 #[macro_use]
 extern crate log;
 
+use std::collections::HashSet;
+use std::sync::Arc;
 use std::time::Duration;
 
-use actix_web::{middleware, App, HttpServer};
+use actix_web::{middleware, web, App, HttpServer};
 
+use anyhow::{bail, Context};
+use clap::Parser;
 use dotenv::dotenv;
+use futures_util::stream::{FuturesUnordered, StreamExt};
 use tokio::join;
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver};
 use tokio::time::sleep;
 
-use crate::app::{fetch_consumption_for_interval, fetch_production_for_interval, get_access_token};
-use crate::endpoints::{health, post};
+use crate::account::Account;
+use crate::cli::{Cli, Command};
+use crate::endpoints::{export as export_endpoint, health, post};
 use crate::settings::time::{
     get_next_fetch_milliseconds, get_start_stop, get_time_after_duration, get_timezone,
 };
+use crate::state::{watch_settings, AppState};
 
+mod account;
 mod app;
 pub mod authmodels;
+mod cli;
+mod commands;
 mod endpoints;
+mod entsoe;
+mod export;
 mod logging;
+mod pricing;
 mod settings;
+mod state;
 mod storage;
 
+/// Days back to look for intervals stored without a price.
+const REPRICE_DAYS: i64 = 31;
+
 #[tokio::main]
 async fn main() {
     dotenv().ok();
+    let cli = Cli::parse();
+
+    logging::init_logging(matches!(cli.command, None | Some(Command::Run)));
+
+    if let Err(err) = run_command(&cli).await {
+        error!("{:#}", err);
+        std::process::exit(1);
+    }
+}
 
-    logging::init_logging();
+async fn run_command(cli: &Cli) -> Result<(), anyhow::Error> {
+    let command = cli.command.as_ref().unwrap_or(&Command::Run);
+    if let Command::ValidateConfig = command {
+        return commands::validate_config(&cli.settings_files());
+    }
 
+    let state = AppState::load(cli.settings_files()).context("Failed to load settings file")?;
+    match command {
+        Command::Run => run(Arc::new(state)).await,
+        Command::Fetch(args) => commands::fetch(&state, args).await,
+        Command::Backfill(args) => commands::backfill(&state, args).await,
+        Command::ValidateConfig => unreachable!("validated without loading the state"),
+        Command::Price(args) => commands::price(&state.settings(), args),
+        Command::FetchPrices(args) => commands::fetch_prices(&state, args).await,
+        Command::Unpriced(args) => commands::unpriced(&state, args).await,
+        Command::Export(args) => commands::export(&state, args).await,
+        Command::Token(args) => commands::token(&state, args).await,
+    }
+}
+
+/// Serves the REST API and updates the accounts daily, as enabled in the settings. Fails when a
+/// storage backend can't be reached at startup.
+async fn run(state: Arc<AppState>) -> Result<(), anyhow::Error> {
     info!("WattiVahti Logger starting");
-    info!("Using time zone: {}", get_timezone().name());
 
-    let config = settings::config::load_settings(format!("configs/{}.yaml", "production"))
-        .expect("Failed to load settings file.");
+    let config = state.settings();
+
+    info!("Using time zone: {}", get_timezone().name());
+    info!("Using WattiVahti API: {}", state.client().base_url());
 
-    if let Err(err) = config.validate() {
-        panic!("Validation error: {}", err);
+    // The sinks keep their connections for the whole run, check them before the first update
+    let health = state.sinks().health_check().await;
+    if !health.is_ok() {
+        bail!("Storage backends can't be reached: {}", health.failure_messages().join(", "));
     }
 
-    let interval: u64 = dotenv::var("INTERVAL")
-        .map(|var| var.parse::<u64>())
-        .unwrap_or(Ok(3_600_000))
-        .unwrap();
-
-    let fetch_pt1h = dotenv::var("FETCH_PT1H_RESOLUTION")
-        .map(|var| var.parse::<bool>())
-        .unwrap_or(Ok(false))
-        .unwrap();
-
-    let fetch_pt15m = dotenv::var("FETCH_PT15M_RESOLUTION")
-        .map(|var| var.parse::<bool>())
-        .unwrap_or(Ok(false))
-        .unwrap();
-
-    let mut access_token = dotenv::var("ACCESS_TOKEN").unwrap_or("".to_string());
-    let wattivahti_username = dotenv::var("WATTIVAHTI_USERNAME").unwrap_or("".to_string());
-    let wattivahti_password = dotenv::var("WATTIVAHTI_PASSWORD").unwrap_or("".to_string());
-    let wattivahti_token_endpoint =
-        dotenv::var("WATTIVAHTI_TOKEN_ENDPOINT").unwrap_or("".to_string());
-    let start = dotenv::var("START").unwrap();
-    let stop = dotenv::var("STOP").unwrap();
-
-    let run_server: bool = dotenv::var("ENABLE_REST_API")
-        .unwrap_or_else(|_| String::from("false"))
-        .parse()
-        .unwrap_or(true);
-
-    let run_update: bool = dotenv::var("ENABLE_AUTO_UPDATE")
-        .unwrap_or_else(|_| String::from("false"))
-        .parse()
-        .unwrap_or(true);
+    let run_server = config.server.enabled;
+    let run_update = config.schedule.enabled;
+    let bind_address = config.server.bind_address.clone();
 
+    let server_state = web::Data::from(state.clone());
     let server_task = async {
         let server = match HttpServer::new(move || {
             App::new()
                 .wrap(middleware::Compress::default())
-                // .app_data(web::Data::new(server_client.clone()))
+                .app_data(server_state.clone())
                 // register HTTP requests handlers
                 .service(health::health_check)
                 .service(post::metering_update)
+                .service(export_endpoint::export_intervals)
         })
-        .bind("0.0.0.0:9090")
+        .bind(&bind_address)
         {
             Ok(value) => {
-                info!("REST API started at 0.0.0.0:9090");
+                info!("REST API started at {}", bind_address);
                 value
             }
             Err(error) => panic!("Error binding to socket:{:?}", error),
@@ -91,78 +115,17 @@ async fn main() {
         let _ = server.run().await;
     };
 
+    let (added_sender, added) = unbounded_channel();
     let update_task = async {
-        loop {
-            // If credentials provided, use those instead of the given access token (if access token was even given)
-            if !wattivahti_username.is_empty() {
-                let result = get_access_token(
-                    &wattivahti_token_endpoint,
-                    &wattivahti_username,
-                    &wattivahti_password,
-                )
-                .await;
-                if result.is_err() {
-                    warn!("Logging {} - {} failed because of accessToken, waiting for the next fetch at {} ...", start, stop, get_time_after_duration(interval));
-                    sleep(Duration::from_millis(interval)).await;
-                    continue;
-                }
-                let result = result.unwrap();
-                if result.access_token.is_none() {
-                    warn!("Logging {} - {} failed because of accessToken, waiting for the next fetch at {} ...", start, stop, get_time_after_duration(interval));
-                    sleep(Duration::from_millis(interval)).await;
-                    continue;
-                }
-                access_token = result.access_token.unwrap();
-            }
-
-            let start_stop = get_start_stop();
-
-            if fetch_pt1h {
-                let _ = fetch_consumption_for_interval(
-                    &access_token,
-                    &start_stop.0,
-                    &start_stop.1,
-                    "PT1H",
-                )
-                .await;
-
-                let _ = fetch_production_for_interval(
-                    &access_token,
-                    &start_stop.0,
-                    &start_stop.1,
-                    "PT1H",
-                )
-                .await;
-            }
+        join!(run_all_account_updates(&state, added), run_price_updates(&state));
+    };
 
-            if fetch_pt15m {
-                let _ = fetch_consumption_for_interval(
-                    &access_token,
-                    &start_stop.0,
-                    &start_stop.1,
-                    "PT15MIN",
-                )
-                .await;
-
-                let _ = fetch_production_for_interval(
-                    &access_token,
-                    &start_stop.0,
-                    &start_stop.1,
-                    "PT15MIN",
-                )
-                .await;
-            }
+    if !run_server && !run_update {
+        warn!("Not running server or update. Enable at least one of them with server.enabled or schedule.enabled in the settings, or ENABLE_REST_API or ENABLE_AUTO_UPDATE in .env file.");
+        return Ok(());
+    }
 
-            let next_fetch_interval = get_next_fetch_milliseconds() as u64;
-            info!(
-                "Logging {} - {} done, waiting for the next fetch at {} ...",
-                start_stop.0,
-                start_stop.1,
-                get_time_after_duration(next_fetch_interval)
-            );
-            sleep(Duration::from_millis(next_fetch_interval)).await;
-        }
-    };
+    tokio::spawn(watch_settings(state.clone(), added_sender));
 
     if run_server && run_update {
         info!("Running server and auto update");
@@ -170,11 +133,138 @@ async fn main() {
     } else if run_server {
         info!("Running server");
         server_task.await;
-    } else if run_update {
+    } else {
         info!("Running auto update");
         update_task.await;
-    } else {
-        warn!("Not running server or update. Enable at least one of them in .env file with ENABLE_REST_API or ENABLE_AUTO_UPDATE.");
+    }
+    Ok(())
+}
+
+/// Updates every account of the settings, and the accounts a settings reload adds. Accounts are
+/// updated independently so that a failing token only delays its own account.
+async fn run_all_account_updates(state: &AppState, mut added: UnboundedReceiver<String>) {
+    let mut running = HashSet::new();
+    let mut updates = FuturesUnordered::new();
+    for account in state.settings().get_accounts() {
+        running.insert(account.name.clone());
+        updates.push(run_account_updates(state, account.name));
+    }
+
+    loop {
+        tokio::select! {
+            Some(name) = updates.next(), if !updates.is_empty() => {
+                running.remove(&name);
+            }
+            name = added.recv() => match name {
+                Some(name) => {
+                    // An account removed and added back between two rounds is still running
+                    if running.insert(name.clone()) {
+                        info!("Account {} | Added to the settings, starting updates", name);
+                        updates.push(run_account_updates(state, name));
+                    }
+                }
+                None => {
+                    // Settings aren't reloaded anymore, finish the running accounts
+                    while updates.next().await.is_some() {}
+                    return;
+                }
+            },
+        }
+    }
+}
+
+/// Fetches the metering points of the account once a day with the current settings. When the
+/// access token can't be fetched or is rejected, the account is retried after
+/// `schedule.interval_ms` instead. Stops when the account is removed from the settings and
+/// returns its name.
+async fn run_account_updates(state: &AppState, name: String) -> String {
+    let mut account: Option<Account> = None;
+
+    loop {
+        let config = state.settings();
+        let account_config = match config.get_accounts().into_iter().find(|account| account.name == name) {
+            Some(account_config) => account_config,
+            None => {
+                info!("Account {} | Removed from the settings, stopping updates", name);
+                return name;
+            }
+        };
+        // Changed credentials or metering points start over with a new token
+        let account = match &mut account {
+            Some(account) if account.config() == &account_config => account,
+            account => account.insert(Account::from_config(&account_config)),
+        };
+
+        let start_stop = get_start_stop();
+
+        let token_failed = match account
+            .update(&state.client(), &config, &state.sinks(), &config.wattivahti.retry.policy(), &start_stop.0, &start_stop.1)
+            .await
+        {
+            Ok(update) => {
+                if update.token_rejected && !account.can_refresh_token() {
+                    warn!("Account {} | The access token was rejected, update it or provide a username and password", account.name);
+                }
+                update.token_rejected
+            }
+            Err(err) => {
+                warn!("Account {} | Failed to get an access token: {}", account.name, err);
+                true
+            }
+        };
+
+        // A rejected token is refreshed on the next round when credentials are
+        // available, so try again after the interval instead of waiting for tomorrow
+        if token_failed {
+            warn!("Account {} | Logging {} - {} failed because of accessToken, waiting for the next fetch at {} ...", account.name, start_stop.0, start_stop.1, get_time_after_duration(config.schedule.interval_ms));
+            sleep(Duration::from_millis(config.schedule.interval_ms)).await;
+            continue;
+        }
+
+        let next_fetch_interval = get_next_fetch_milliseconds(&config.schedule) as u64;
+        info!(
+            "Account {} | Logging {} - {} done, waiting for the next fetch at {} ...",
+            account.name,
+            start_stop.0,
+            start_stop.1,
+            get_time_after_duration(next_fetch_interval)
+        );
+        sleep(Duration::from_millis(next_fetch_interval)).await;
+    }
+}
+
+/// Fetches the day-ahead prices from yesterday to the end of tomorrow every
+/// `entsoe.interval_ms`, so that tomorrow's prices are stored soon after they are published, and
+/// reprices the intervals of the last `REPRICE_DAYS` days that were stored without a price. When
+/// the price fetch is disabled in the settings, only reprices at the daily fetch time with the
+/// prices stored by other means, such as the `fetch-prices` command.
+async fn run_price_updates(state: &AppState) {
+    loop {
+        let config = state.settings();
+
+        let stop = commands::start_of_day_after(2).to_utc();
+        if config.entsoe.enabled {
+            let start = commands::start_of_day_after(-1).to_utc();
+            match entsoe::update_day_ahead_prices(&config.entsoe, &state.sinks(), start, stop).await {
+                Ok(report) if report.is_ok() => {}
+                Ok(report) => warn!("Storing the day-ahead prices failed: {}", report.failure_messages().join(", ")),
+                Err(err) => warn!("Fetching the day-ahead prices failed: {}", err),
+            }
+        }
+
+        let reprice_start = commands::start_of_day_after(-REPRICE_DAYS).to_utc();
+        match app::reprice_pending_intervals(&config, &state.sinks(), reprice_start, stop).await {
+            Ok(report) if report.failures.is_empty() => {}
+            Ok(report) => warn!("Storing the repriced intervals failed: {}", report.failures.join(", ")),
+            Err(err) => warn!("Repricing the intervals without a price failed: {:#}", err),
+        }
+
+        let next_update = if config.entsoe.enabled {
+            config.entsoe.interval_ms
+        } else {
+            get_next_fetch_milliseconds(&config.schedule) as u64
+        };
+        sleep(Duration::from_millis(next_update)).await;
     }
 }
 
@@ -183,6 +273,7 @@ mod tests {
     use chrono::{DateTime, NaiveDateTime, Utc};
 
     use super::*;
+    use crate::settings::config_model::ScheduleConfig;
 
     #[tokio::test]
     async fn test_get_start_stop() {
@@ -198,7 +289,7 @@ mod tests {
 
     #[tokio::test]
     async fn test_get_next_fetch_milliseconds() {
-        let data = get_next_fetch_milliseconds();
+        let data = get_next_fetch_milliseconds(&ScheduleConfig::default());
         info!("Result: {}", data);
     }
 
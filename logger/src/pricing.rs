@@ -0,0 +1,352 @@
+//! Turns fetched meter data into priced intervals that the storage backends persist as they are.
+//!
+//! Units of the stored prices:
+//! - day-ahead prices are published in EUR/MWh without VAT
+//! - `spot_price` is the day-ahead price in c/kWh without VAT, like in the `energies` table
+//! - energy, margin, transfer and tax fees are in c/kWh like in the settings, basic fees in EUR/month
+
+use std::collections::BTreeMap;
+use std::iter::FromIterator;
+
+use api::{ConsumptionsResult, LocalDateTime, MeasurementType, ResolutionDuration};
+use chrono::{DateTime, Duration as ChronoDuration, DurationRound, Utc};
+use chrono_tz::Tz;
+
+use crate::settings::config_model::{ContractType, ContractsConfig};
+
+/// Day-ahead prices by the start of their interval, in EUR/MWh.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct SpotPrices {
+    prices: BTreeMap<DateTime<Utc>, f32>,
+}
+
+impl SpotPrices {
+    pub fn is_empty(&self) -> bool {
+        self.prices.is_empty()
+    }
+
+    /// Adds the prices of the times that don't have a price yet.
+    pub fn fill_missing(&mut self, other: SpotPrices) {
+        for (time, price) in other.prices {
+            self.prices.entry(time).or_insert(price);
+        }
+    }
+
+    /// Price of the interval starting at `time`. Quarter hours without their own price use the
+    /// price of their hour.
+    pub fn get(&self, time: DateTime<Utc>) -> Option<f32> {
+        self.prices
+            .get(&time)
+            .or_else(|| {
+                let hour = time.duration_trunc(ChronoDuration::hours(1)).ok()?;
+                self.prices.get(&hour)
+            })
+            .copied()
+    }
+}
+
+impl FromIterator<(DateTime<Utc>, f32)> for SpotPrices {
+    fn from_iter<I: IntoIterator<Item = (DateTime<Utc>, f32)>>(iter: I) -> Self {
+        Self { prices: iter.into_iter().collect() }
+    }
+}
+
+/// Converts a day-ahead price from EUR/MWh to c/kWh.
+pub fn eur_per_mwh_to_cents_per_kwh(price: f32) -> f32 {
+    price / 10.0
+}
+
+/// One metered interval with everything needed to calculate its cost.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PricedInterval {
+    /// Start of the interval
+    pub time: DateTime<Utc>,
+    pub metering_point_code: String,
+    pub measurement_type: MeasurementType,
+    pub resolution: ResolutionDuration,
+    /// Unit of `value`, e.g. kWh
+    pub unit: String,
+    pub value: f32,
+    pub contract_type: ContractType,
+    /// Whether the night fees of the contract apply
+    pub night: bool,
+    /// Day-ahead price in c/kWh without VAT, `None` when the price isn't known
+    pub spot_price: Option<f32>,
+    /// EUR/month
+    pub energy_basic_fee: f32,
+    /// c/kWh with VAT. For spot contracts the spot price with VAT plus the margin, `None` while the
+    /// spot price isn't known
+    pub energy_fee: Option<f32>,
+    /// c/kWh
+    pub energy_margin: f32,
+    /// EUR/month
+    pub transfer_basic_fee: f32,
+    /// c/kWh
+    pub transfer_fee: f32,
+    /// c/kWh
+    pub transfer_tax_fee: f32,
+    /// VAT in percent
+    pub tax_percentage: f32,
+}
+
+impl PricedInterval {
+    /// Whether the interval is on a spot contract and was priced before its day-ahead price was
+    /// known. Its spot price and energy fee stay empty until [`reprice_intervals`] fills them in.
+    /// Intervals on other contracts have their final energy fee without the day-ahead price, and
+    /// rows stored with a spot price before the energy fee was stored aren't pending either.
+    pub fn is_price_pending(&self) -> bool {
+        self.contract_type == ContractType::Spot && self.spot_price.is_none()
+    }
+}
+
+/// Time span covered by the time series of the data, for looking up their prices.
+pub fn time_span(data: &ConsumptionsResult) -> (DateTime<Utc>, DateTime<Utc>) {
+    let timeseries = &data.getconsumptionsresult.consumptiondata.timeseries;
+    (timeseries.start.to_utc(), timeseries.stop.to_utc())
+}
+
+/// Prices every value of the data with the contract in effect at its time. Values without a
+/// time, quantity or contract are skipped.
+pub fn price_intervals(
+    data: &ConsumptionsResult,
+    measurement_type: MeasurementType,
+    contracts: &ContractsConfig,
+    spot_prices: &SpotPrices,
+) -> Vec<PricedInterval> {
+    let consumption_data = &data.getconsumptionsresult.consumptiondata;
+    let resolution = consumption_data.timeseries.resolution;
+    let mut intervals = Vec::new();
+
+    for (pos, tsv) in consumption_data.timeseries.values.tsv.iter().enumerate() {
+        let time = match tsv.get_timestamp_utc_calculated(pos, &resolution) {
+            Some(time) => time,
+            None => {
+                warn!("Skipping a {} value because its time couldn't be parsed", measurement_type);
+                continue;
+            }
+        };
+
+        let value = match tsv.quantity {
+            Some(value) => value,
+            None => continue,
+        };
+
+        let contract = match contracts.get_contract(time) {
+            Some(contract) => contract,
+            None => {
+                warn!("Skipping the {} value of {} because no contract was found", measurement_type, time);
+                continue;
+            }
+        };
+
+        let price = spot_prices.get(time);
+        let energy_fee = match contract.contract_type {
+            ContractType::None => Some(0.0),
+            ContractType::Fixed | ContractType::Hybrid => Some(contract.get_energy_fee_fixed(time)),
+            ContractType::Spot => price.map(|price| contract.get_energy_fee_spot(price)),
+        };
+
+        intervals.push(PricedInterval {
+            time,
+            metering_point_code: consumption_data.meteringpointcode.to_string(),
+            measurement_type,
+            resolution,
+            unit: consumption_data.sum.unit.to_string(),
+            value,
+            contract_type: contract.contract_type.clone(),
+            night: contract.get_is_night(time),
+            spot_price: price.map(eur_per_mwh_to_cents_per_kwh),
+            energy_basic_fee: contract.get_energy_basic_fee(),
+            energy_fee,
+            energy_margin: contract.get_energy_margin(),
+            transfer_basic_fee: contract.get_transfer_basic_fee(),
+            transfer_fee: contract.get_transfer_fee(time),
+            transfer_tax_fee: contract.get_transfer_tax_fee(),
+            tax_percentage: contract.get_tax_percentage(),
+        });
+    }
+
+    intervals
+}
+
+/// Fills in the spot price and the energy fee of the pending intervals that now have a price, with
+/// the contracts `contracts` gives for each interval. Returns only the intervals that got a price.
+pub fn reprice_intervals<'a>(
+    intervals: &[PricedInterval],
+    spot_prices: &SpotPrices,
+    contracts: impl Fn(&PricedInterval) -> &'a ContractsConfig,
+) -> Vec<PricedInterval> {
+    let mut repriced = Vec::new();
+
+    for interval in intervals.iter().filter(|interval| interval.is_price_pending()) {
+        let price = match spot_prices.get(interval.time) {
+            Some(price) => price,
+            None => continue,
+        };
+
+        let contract = match contracts(interval).get_contract(interval.time) {
+            Some(contract) => contract,
+            None => {
+                warn!("No contract for the {} value of {}, its price stays pending", interval.measurement_type, interval.time);
+                continue;
+            }
+        };
+
+        let mut interval = interval.clone();
+        interval.spot_price = Some(eur_per_mwh_to_cents_per_kwh(price));
+        interval.energy_fee = Some(contract.get_energy_fee_spot(price));
+        repriced.push(interval);
+    }
+
+    repriced
+}
+
+/// Consecutive intervals of one metering point and resolution whose price is still pending.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PendingPriceRun {
+    pub metering_point_code: String,
+    pub measurement_type: MeasurementType,
+    pub resolution: ResolutionDuration,
+    /// Start of the first interval
+    pub start: DateTime<Utc>,
+    /// End of the last interval
+    pub stop: DateTime<Utc>,
+    pub count: usize,
+}
+
+/// Groups the intervals whose price is still pending into runs of consecutive intervals.
+pub fn pending_price_runs(intervals: &[PricedInterval], tz: &Tz) -> Vec<PendingPriceRun> {
+    let mut pending: Vec<&PricedInterval> = intervals.iter().filter(|interval| interval.is_price_pending()).collect();
+    pending.sort_by(|a, b| {
+        (&a.metering_point_code, a.measurement_type.code(), a.resolution.to_string(), a.time).cmp(&(
+            &b.metering_point_code,
+            b.measurement_type.code(),
+            b.resolution.to_string(),
+            b.time,
+        ))
+    });
+
+    let mut runs: Vec<PendingPriceRun> = Vec::new();
+    for interval in pending {
+        let stop = interval_end(interval, tz);
+        match runs.last_mut() {
+            Some(run)
+                if run.metering_point_code == interval.metering_point_code
+                    && run.measurement_type == interval.measurement_type
+                    && run.resolution == interval.resolution
+                    && run.stop == interval.time =>
+            {
+                run.stop = stop;
+                run.count += 1;
+            }
+            _ => runs.push(PendingPriceRun {
+                metering_point_code: interval.metering_point_code.clone(),
+                measurement_type: interval.measurement_type,
+                resolution: interval.resolution,
+                start: interval.time,
+                stop,
+                count: 1,
+            }),
+        }
+    }
+
+    runs
+}
+
+fn interval_end(interval: &PricedInterval, tz: &Tz) -> DateTime<Utc> {
+    if interval.resolution.is_fixed() {
+        return interval.time + ChronoDuration::minutes(interval.resolution.minutes() as i64);
+    }
+    interval.resolution.step_from(LocalDateTime::new(interval.time.with_timezone(tz).naive_local()), 1, tz)
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+
+    use super::*;
+
+    fn fixture(name: &str) -> ConsumptionsResult {
+        serde_json::from_str(&api::mock::fixture(name)).unwrap()
+    }
+
+    #[test]
+    fn test_quarter_hours_fall_back_to_the_hourly_price() {
+        let hour = Utc.ymd(2023, 10, 1).and_hms(12, 0, 0);
+        let prices: SpotPrices = vec![(hour, 50.0), (hour + ChronoDuration::minutes(15), 60.0)].into_iter().collect();
+
+        assert_eq!(prices.get(hour), Some(50.0));
+        assert_eq!(prices.get(hour + ChronoDuration::minutes(15)), Some(60.0));
+        assert_eq!(prices.get(hour + ChronoDuration::minutes(30)), Some(50.0));
+        assert_eq!(prices.get(hour + ChronoDuration::hours(1)), None);
+    }
+
+    #[test]
+    fn test_pending_price_runs() {
+        let config = crate::settings::config::load_settings("configs/test.yaml").unwrap();
+        let data = fixture("consumption_2022-08-01_PT1H.json");
+        let (start, _) = time_span(&data);
+
+        // Hours 3-5 and from 20 on have no price
+        let prices: SpotPrices = (0..20).filter(|hour| !(3..6).contains(hour)).map(|hour| (start + ChronoDuration::hours(hour), 100.0)).collect();
+        let mut intervals = price_intervals(&data, MeasurementType::Consumption, config.get_contracts(MeasurementType::Consumption), &prices);
+        let mut other_point = intervals[4].clone();
+        other_point.metering_point_code = "4242".to_string();
+        intervals.push(other_point);
+        // A fixed price is final without the day-ahead price
+        let mut fixed = intervals[21].clone();
+        fixed.metering_point_code = "5151".to_string();
+        fixed.contract_type = ContractType::Fixed;
+        fixed.energy_fee = Some(8.5);
+        assert!(!fixed.is_price_pending());
+        intervals.push(fixed);
+        // So is a spot price stored before the energy fee was
+        let mut historical = intervals[21].clone();
+        historical.metering_point_code = "6161".to_string();
+        historical.spot_price = Some(5.0);
+        assert!(!historical.is_price_pending());
+        intervals.push(historical);
+
+        let runs = pending_price_runs(&intervals, &chrono_tz::Europe::Helsinki);
+        assert_eq!(runs.len(), 3);
+        assert_eq!((runs[0].start, runs[0].stop, runs[0].count), (start + ChronoDuration::hours(3), start + ChronoDuration::hours(6), 3));
+        assert_eq!((runs[1].start, runs[1].stop, runs[1].count), (start + ChronoDuration::hours(20), start + ChronoDuration::hours(24), 4));
+        assert_eq!(runs[2].metering_point_code, "4242");
+        assert_eq!(runs[2].count, 1);
+    }
+
+    #[test]
+    fn test_price_intervals() {
+        let config = crate::settings::config::load_settings("configs/test.yaml").unwrap();
+        let contracts = config.get_contracts(MeasurementType::Consumption);
+        let data = fixture("consumption_2022-08-01_PT1H.json");
+
+        let (start, stop) = time_span(&data);
+        assert_eq!(stop - start, ChronoDuration::hours(24));
+
+        // Every hour except the last one has a price
+        let prices: SpotPrices = (0..23).map(|hour| (start + ChronoDuration::hours(hour), 100.0 + hour as f32)).collect();
+        let intervals = price_intervals(&data, MeasurementType::Consumption, contracts, &prices);
+        assert_eq!(intervals.len(), 24);
+
+        for (hour, interval) in intervals.iter().enumerate() {
+            let contract = contracts.get_contract(interval.time).unwrap();
+            assert_eq!(interval.time, start + ChronoDuration::hours(hour as i64));
+            assert_eq!(interval.resolution, ResolutionDuration::PT1H);
+            assert_eq!(interval.contract_type, contract.contract_type);
+
+            if hour < 23 {
+                let price = 100.0 + hour as f32;
+                assert_eq!(interval.spot_price, Some(price / 10.0));
+                if contract.contract_type == ContractType::Spot {
+                    assert_eq!(interval.energy_fee, Some(contract.get_energy_fee_spot(price)));
+                }
+            } else {
+                assert_eq!(interval.spot_price, None);
+                if contract.contract_type == ContractType::Spot {
+                    assert_eq!(interval.energy_fee, None);
+                }
+            }
+        }
+    }
+}
@@ -1,31 +1,174 @@
 use std::fs::File;
 use std::io::Read;
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+use serde_yaml::Value;
 use thiserror::Error;
 use crate::settings::config_model::SettingsConfig;
+use crate::settings::env::apply_env_overrides;
 
 #[derive(Error, Debug)]
 pub enum ConfigError {
-    #[error("Failed to deserialize config.")]
+    #[error("Failed to deserialize config: {0}")]
     Serde(#[from] serde_yaml::Error),
-    #[error("Failed to open config file")]
-    Io(#[from] std::io::Error),
+    #[error("Failed to open config file {path}")]
+    Io { path: PathBuf, source: std::io::Error },
+    #[error("Invalid settings:\n{}", .0.join("\n"))]
+    Invalid(Vec<String>),
 }
 
+/// Settings file and the optional local override merged on top of it, e.g.
+/// `configs/production.yaml` and `configs/production.local.yaml`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SettingsFiles {
+    pub base: PathBuf,
+    pub local: PathBuf,
+}
+
+impl SettingsFiles {
+    pub fn new(base: impl Into<PathBuf>) -> Self {
+        let base = base.into();
+        let stem = base.file_stem().unwrap_or_default().to_string_lossy();
+        let local = base.with_file_name(format!("{}.local.yaml", stem));
+        Self { base, local }
+    }
+
+    /// `configs/<profile>.yaml`
+    pub fn for_profile(profile: &str) -> Self {
+        Self::new(Path::new("configs").join(format!("{}.yaml", profile)))
+    }
+
+    /// Files to load in order, the local override only when it exists.
+    pub fn layers(&self) -> Vec<&Path> {
+        let mut layers = vec![self.base.as_path()];
+        if self.local.is_file() {
+            layers.push(self.local.as_path());
+        }
+        layers
+    }
+
+    pub fn load(&self) -> Result<SettingsConfig, ConfigError> {
+        load_settings_with_env(&self.layers(), std::env::vars())
+    }
+
+    /// Modification times of the files, used to notice when they change.
+    pub fn modified(&self) -> Vec<Option<SystemTime>> {
+        [&self.base, &self.local]
+            .iter()
+            .map(|path| path.metadata().and_then(|metadata| metadata.modified()).ok())
+            .collect()
+    }
+}
+
+/// Loads a single settings file with the env var overrides of the process, see
+/// [`crate::settings::env`], and validates the result.
+#[cfg(test)]
 pub fn load_settings(path: impl AsRef<Path>) -> Result<SettingsConfig, ConfigError> {
-    info!("Loading {}", path.as_ref().to_string_lossy());
-    let mut file = File::open(path)?;
-    let mut s = String::new();
-    file.read_to_string(&mut s)?;
-    let t: SettingsConfig = serde_yaml::from_str(&s)?;
+    load_settings_with_env(&[path], std::env::vars())
+}
+
+/// Merges the files in order on top of the defaults, then applies the env vars. Values of the
+/// wrong type are reported together with the invalid ones instead of failing on the first.
+pub fn load_settings_with_env(
+    paths: &[impl AsRef<Path>],
+    vars: impl IntoIterator<Item = (String, String)>,
+) -> Result<SettingsConfig, ConfigError> {
+    // Start from the defaults so that the files and env vars can tell which keys exist and their types
+    let mut settings = serde_yaml::to_value(SettingsConfig::default())?;
+    let mut errors = Vec::new();
+    for path in paths {
+        let path = path.as_ref();
+        merge(&mut settings, read_file(path)?, &path.display().to_string(), "", &mut errors);
+    }
+    errors.extend(apply_env_overrides(&mut settings, vars));
+
+    // Values the defaults can't check, e.g. in list items, are only caught here
+    let t: SettingsConfig = match serde_yaml::from_value(settings) {
+        Ok(t) => t,
+        Err(err) => {
+            errors.push(err.to_string());
+            return Err(ConfigError::Invalid(errors));
+        }
+    };
+    if let Err(ConfigError::Invalid(invalid)) = t.validate() {
+        errors.extend(invalid);
+    }
+    if !errors.is_empty() {
+        return Err(ConfigError::Invalid(errors));
+    }
 
     Ok(t)
 }
 
+fn read_file(path: &Path) -> Result<Value, ConfigError> {
+    info!("Loading {}", path.to_string_lossy());
+    let io_error = |source| ConfigError::Io { path: path.to_path_buf(), source };
+    let mut file = File::open(path).map_err(io_error)?;
+    let mut s = String::new();
+    file.read_to_string(&mut s).map_err(io_error)?;
+
+    Ok(serde_yaml::from_str(&s)?)
+}
+
+/// Merges sections recursively, other values of `overrides` replace the ones in `settings`. A
+/// value of another type than the one it replaces is reported with the `file` and `key` and
+/// leaves the current value as it was.
+fn merge(settings: &mut Value, overrides: Value, file: &str, key: &str, errors: &mut Vec<String>) {
+    match (settings, overrides) {
+        (Value::Mapping(settings), Value::Mapping(overrides)) => {
+            for (name, value) in overrides {
+                let path = match name.as_str() {
+                    Some(name) if key.is_empty() => name.to_string(),
+                    Some(name) => format!("{}.{}", key, name),
+                    None => key.to_string(),
+                };
+                match settings.get_mut(&name) {
+                    Some(current) => merge(current, value, file, &path, errors),
+                    None => {
+                        settings.insert(name, value);
+                    }
+                }
+            }
+        }
+        (_, Value::Null) => {}
+        (settings, overrides) => match expected_type(settings, &overrides) {
+            Some(expected) => errors.push(format!("{}: {}: expected {}, got {}", file, key, expected, type_name(&overrides))),
+            None => *settings = overrides,
+        },
+    }
+}
+
+/// What the value replacing `current` should be, when it isn't. Keys without a default, i.e.
+/// `null`, take any value.
+fn expected_type(current: &Value, value: &Value) -> Option<&'static str> {
+    match (current, value) {
+        (Value::Bool(_), Value::Bool(_))
+        | (Value::Number(_), Value::Number(_))
+        | (Value::String(_), Value::String(_) | Value::Number(_) | Value::Bool(_))
+        | (Value::Sequence(_), Value::Sequence(_))
+        | (Value::Null | Value::Tagged(_), _) => None,
+        (current, _) => Some(type_name(current)),
+    }
+}
+
+fn type_name(value: &Value) -> &'static str {
+    match value {
+        Value::Null => "nothing",
+        Value::Bool(_) => "true or false",
+        Value::Number(_) => "a number",
+        Value::String(_) => "text",
+        Value::Sequence(_) => "a list",
+        Value::Mapping(_) => "a section",
+        Value::Tagged(_) => "a tagged value",
+    }
+}
+
 #[cfg(test)]
 mod tests {
+    use api::{MeasurementType, ResolutionDuration};
     use chrono::{DateTime, NaiveDateTime, Utc};
     use super::*;
+    use crate::settings::config_model::{AccountConfig, MeteringPointConfig};
 
     #[tokio::test]
     async fn test_load_settings() {
@@ -43,4 +186,151 @@ mod tests {
 
         info!("Contract {:#?}", contract);
     }
+
+    #[test]
+    fn test_metering_points() {
+        let settings = load_settings("configs/test.yaml").expect("Failed to load settings file.");
+        let points = &settings.metering_points;
+        assert_eq!(points.len(), 3);
+
+        assert_eq!(points[0].to_string(), "consumption 1337 (House)");
+        assert_eq!(points[0].resolutions, vec![ResolutionDuration::PT1H, ResolutionDuration::PT15M]);
+        assert_eq!(points[1].measurement_type(), Some(MeasurementType::Production));
+        assert_eq!(points[1].resolutions, vec![ResolutionDuration::PT1H]);
+
+        // The cottage has its own contracts, the others fall back to the top level ones
+        let dt = DateTime::<Utc>::from_utc(NaiveDateTime::parse_from_str("2022-08-01T12:00:00", "%Y-%m-%dT%H:%M:%S").unwrap(), Utc);
+        let house = settings.get_metering_point_contracts(&points[0]).get_contract(dt).unwrap();
+        let cottage = settings.get_metering_point_contracts(&points[2]).get_contract(dt).unwrap();
+        assert_eq!(house.get_energy_basic_fee(), 5.50);
+        assert_eq!(cottage.get_energy_basic_fee(), 3.00);
+
+        let mut duplicate = points[0].clone();
+        duplicate.resolutions = vec![ResolutionDuration::P1D];
+        let settings = SettingsConfig { metering_points: vec![points[0].clone(), duplicate], ..settings };
+        assert!(settings.validate().is_err());
+    }
+
+    #[test]
+    fn test_accounts() {
+        let settings = load_settings("configs/test.yaml").expect("Failed to load settings file.");
+        let accounts = settings.get_accounts();
+        assert_eq!(accounts.len(), 1);
+        assert!(accounts[0].is_default());
+        assert_eq!(accounts[0].metering_points.len(), 3);
+
+        let account = |name: &str, code: &str| AccountConfig {
+            name: name.to_string(),
+            username: None,
+            password: None,
+            token_endpoint: None,
+            access_token: Some("token".to_string()),
+            metering_points: vec![MeteringPointConfig::new(code, MeasurementType::Consumption, vec![ResolutionDuration::PT1H])],
+        };
+        let settings = SettingsConfig { metering_points: Vec::new(), accounts: vec![account("home", "1337"), account("cottage", "4242")], ..settings };
+        assert!(settings.validate().is_ok());
+        assert_eq!(settings.get_accounts().len(), 2);
+
+        // The same metering point can't be fetched by two accounts
+        let settings = SettingsConfig { accounts: vec![account("home", "1337"), account("cottage", "1337")], ..settings };
+        assert!(settings.validate().is_err());
+    }
+
+    fn vars(vars: &[(&str, &str)]) -> Vec<(String, String)> {
+        vars.iter().map(|(name, value)| (name.to_string(), value.to_string())).collect()
+    }
+
+    #[test]
+    fn test_env_overrides() {
+        let settings = load_settings_with_env(&["configs/test.yaml"], Vec::new()).unwrap();
+        assert_eq!(settings.schedule.fetch_hour, 6);
+        assert_eq!(settings.timezone, "Europe/Helsinki");
+        assert!(!settings.influxdb.enabled);
+
+        let settings = load_settings_with_env(
+            &["configs/test.yaml"],
+            vars(&[
+                ("FETCH_HOUR", "7"),
+                ("INFLUXDB_ENABLED", "true"),
+                ("DATABASE_NAME", "wattivahti"),
+                ("ACCESS_TOKEN", "1234"),
+                ("WATTIVAHTI_USERNAME", ""),
+                ("FETCH_PT15M_RESOLUTION", "true"),
+                ("LOGGER__SCHEDULE__FETCH_HOUR", "8"),
+                ("LOGGER__WATTIVAHTI__RETRY__MAX_ATTEMPTS", "5"),
+                ("LOGGER__METERING_POINTS__0__NAME", "Home"),
+            ]),
+        )
+        .unwrap();
+        // The prefixed vars win over the legacy ones
+        assert_eq!(settings.schedule.fetch_hour, 8);
+        assert!(settings.influxdb.enabled);
+        assert_eq!(settings.influxdb.database, "wattivahti");
+        assert_eq!(settings.wattivahti.access_token.as_deref(), Some("1234"));
+        assert_eq!(settings.wattivahti.username, None);
+        assert_eq!(settings.wattivahti.fetch_resolutions, vec![ResolutionDuration::PT15M]);
+        assert_eq!(settings.wattivahti.retry.max_attempts, 5);
+        assert_eq!(settings.metering_points[0].name.as_deref(), Some("Home"));
+    }
+
+    #[test]
+    fn test_invalid_settings_are_all_reported() {
+        let result = load_settings_with_env(
+            &["configs/test.yaml"],
+            vars(&[
+                ("INTERVAL", "hourly"),
+                ("ENABLE_REST_API", "yes"),
+                ("FETCH_MINUTES", "75"),
+                ("CHRONO_TIMEZONE", "Europe/Pori"),
+                ("LOGGER__SCHEDULE__FETCH_DAY", "1"),
+                ("LOGGER__TIMESCALEDB__ENABLED", "true"),
+                ("LOGGER__TIMESCALEDB__POOL_SIZE", "0"),
+            ]),
+        );
+        let errors = match result {
+            Err(ConfigError::Invalid(errors)) => errors,
+            other => panic!("Expected invalid settings, got {:?}", other),
+        };
+        assert_eq!(errors.len(), 6, "{:#?}", errors);
+        assert!(errors.iter().any(|err| err.starts_with("INTERVAL: expected a number")));
+        assert!(errors.iter().any(|err| err.starts_with("ENABLE_REST_API: expected true or false")));
+        assert!(errors.iter().any(|err| err.starts_with("LOGGER__SCHEDULE__FETCH_DAY: unknown setting")));
+        assert!(errors.iter().any(|err| err.starts_with("schedule.fetch_minutes")));
+        assert!(errors.iter().any(|err| err.starts_with("timezone")));
+        assert!(errors.iter().any(|err| err.starts_with("timescaledb.pool_size")));
+    }
+
+    #[test]
+    fn test_file_values_of_the_wrong_type_are_all_reported() {
+        let dir = tempfile::tempdir().unwrap();
+        let local = dir.path().join("test.local.yaml");
+        std::fs::write(&local, "schedule:\n  fetch_hour: seven\n  enabled: 1\nserver: true\nwattivahti:\n  fetch_resolutions: PT1H\n").unwrap();
+
+        let result = load_settings_with_env(&[Path::new("configs/test.yaml"), &local], vars(&[("CHRONO_TIMEZONE", "Europe/Pori")]));
+        let errors = match result {
+            Err(ConfigError::Invalid(errors)) => errors,
+            other => panic!("Expected invalid settings, got {:?}", other),
+        };
+        let file = local.display();
+        assert_eq!(
+            errors,
+            vec![
+                format!("{}: schedule.fetch_hour: expected a number, got text", file),
+                format!("{}: schedule.enabled: expected true or false, got a number", file),
+                format!("{}: server: expected a section, got true or false", file),
+                format!("{}: wattivahti.fetch_resolutions: expected a list, got text", file),
+                "timezone: 'Europe/Pori' is not a valid timezone".to_string(),
+            ]
+        );
+
+        // List items have no defaults to check against, so serde reports them with the rest
+        std::fs::write(&local, "schedule:\n  fetch_hour: seven\nmetering_points:\n  - code: 1337\n    resolutions: PT1H\n").unwrap();
+        let result = load_settings_with_env(&[Path::new("configs/test.yaml"), &local], Vec::new());
+        let errors = match result {
+            Err(ConfigError::Invalid(errors)) => errors,
+            other => panic!("Expected invalid settings, got {:?}", other),
+        };
+        assert_eq!(errors.len(), 2, "{:#?}", errors);
+        assert!(errors[1].contains("`1337`, expected a string"), "{:#?}", errors);
+    }
 }
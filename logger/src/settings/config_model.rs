@@ -1,9 +1,19 @@
-use chrono::{DateTime, NaiveDateTime, TimeZone, Timelike, Utc};
+use std::net::SocketAddr;
+use std::path::Path;
+use std::convert::TryFrom;
+use std::str::FromStr;
+use std::time::Duration;
+
+use api::{LocalDateTime, MeasurementType, MeteringPointType, ResolutionDuration, RetryPolicy};
+use chrono::{DateTime, Timelike, Utc};
+use chrono_tz::Tz;
 use serde::{Deserialize, Serialize};
+use thiserror::Error;
 
+use super::config::ConfigError;
 use super::time::get_timezone;
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
 pub enum ContractType {
     #[serde(rename = "none")]
     None,
@@ -15,14 +25,31 @@ pub enum ContractType {
     Hybrid,
 }
 
-impl From<i16> for ContractType {
-    fn from(item: i16) -> Self {
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+#[error("Invalid contract type {0}")]
+pub struct InvalidContractType(pub i16);
+
+impl TryFrom<i16> for ContractType {
+    type Error = InvalidContractType;
+
+    fn try_from(item: i16) -> Result<Self, Self::Error> {
         match item {
-            1 => ContractType::None,
-            2 => ContractType::Fixed,
-            3 => ContractType::Spot,
-            4 => ContractType::Hybrid,
-            _ => panic!("Invalid value for ContractType"),
+            1 => Ok(ContractType::None),
+            2 => Ok(ContractType::Fixed),
+            3 => Ok(ContractType::Spot),
+            4 => Ok(ContractType::Hybrid),
+            _ => Err(InvalidContractType(item)),
+        }
+    }
+}
+
+impl std::fmt::Display for ContractType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ContractType::None => write!(f, "none"),
+            ContractType::Fixed => write!(f, "fixed"),
+            ContractType::Spot => write!(f, "spot"),
+            ContractType::Hybrid => write!(f, "hybrid"),
         }
     }
 }
@@ -38,10 +65,10 @@ impl From<ContractType> for i16 {
     }
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
 pub struct ContractConfig {
-    start_time: String,
-    end_time: Option<String>,
+    start_time: LocalDateTime,
+    end_time: Option<LocalDateTime>,
     pub contract_type: ContractType,
     energy: EnergyConfig,
     transfer: TransferConfig,
@@ -49,8 +76,12 @@ pub struct ContractConfig {
 }
 
 impl ContractConfig {
-    pub fn get_spot_margin(&self) -> Option<f32> {
-        self.energy.margin
+    pub fn get_start_time(&self) -> LocalDateTime {
+        self.start_time
+    }
+
+    pub fn get_end_time(&self) -> Option<LocalDateTime> {
+        self.end_time
     }
 
     pub fn get_tax_percentage(&self) -> f32 {
@@ -69,11 +100,7 @@ impl ContractConfig {
 
         let local = time.with_timezone(&get_timezone());
         let hour = local.hour();
-        return if hour < time_end || hour >= time_start {
-            true
-        } else {
-            false
-        };
+        hour < time_end || hour >= time_start
     }
 
     pub fn get_transfer_fee(&self, time: DateTime<Utc>) -> f32 {
@@ -84,11 +111,11 @@ impl ContractConfig {
 
         let local = time.with_timezone(&get_timezone());
         let hour = local.hour();
-        return if hour < time_end || hour >= time_start {
+        if hour < time_end || hour >= time_start {
             transfer_config.night_fee
         } else {
             transfer_config.day_fee
-        };
+        }
     }
 
     pub fn get_transfer_tax_fee(&self) -> f32 {
@@ -119,11 +146,11 @@ impl ContractConfig {
 
         let local = time.with_timezone(&get_timezone());
         let hour = local.hour();
-        return if hour < time_end || hour >= time_start {
+        if hour < time_end || hour >= time_start {
             energy_config.night_fee.unwrap_or(0.0)
         } else {
             energy_config.day_fee.unwrap_or(0.0)
-        };
+        }
     }
 
     pub fn get_energy_fee_spot(&self, spot_price: f32) -> f32 {
@@ -137,21 +164,19 @@ impl ContractConfig {
             return (spot_price / 10.0) + margin;
         }
 
-        return (spot_price / 10.0 * (tax_percentage / 100.0 + 1.0)) + margin;
+        (spot_price / 10.0 * (tax_percentage / 100.0 + 1.0)) + margin
     }
 
     pub fn validate_energy(&self) -> Result<(), &'static str> {
         match self.contract_type {
-            ContractType::Fixed | ContractType::Hybrid => {
-                if self.energy.day_fee.is_none() || self.energy.night_fee.is_none() {
+            ContractType::Fixed | ContractType::Hybrid
+                if (self.energy.day_fee.is_none() || self.energy.night_fee.is_none()) => {
                     return Err("For Fixed contract type, day_fee and night_fee are required");
                 }
-            }
-            ContractType::Spot => {
-                if self.energy.margin.is_none() {
+            ContractType::Spot
+                if self.energy.margin.is_none() => {
                     return Err("For Spot contract type, margin is required");
                 }
-            }
             _ => {}
         }
 
@@ -159,70 +184,27 @@ impl ContractConfig {
     }
 
     pub fn is_match(&self, time: DateTime<Utc>) -> bool {
-        let start_time = &self.get_start_time_utc();
-        if start_time.is_none() {
-            return false;
-        }
-        let start_time = start_time.unwrap();
-
         // If time is before start_time
-        if time < start_time {
+        if time < self.get_start_time_utc() {
             return false;
         }
 
-        let end_time = &self.get_end_time_utc();
-        if end_time.is_none() {
-            return true;
+        match self.get_end_time_utc() {
+            Some(end_time) => time <= end_time,
+            None => true,
         }
-        let end_time = end_time.unwrap();
-
-        time <= end_time
     }
 
-    fn get_start_time_utc(&self) -> Option<DateTime<Utc>> {
-        let naive_time = NaiveDateTime::parse_from_str(&self.start_time, "%Y-%m-%dT%H:%M:%S");
-        if naive_time.is_err() {
-            return None;
-        }
-        debug!("Start Time Local {}", naive_time.unwrap());
-
-        Some(
-            Utc.from_utc_datetime(
-                &get_timezone()
-                    .from_local_datetime(&naive_time.unwrap())
-                    .unwrap()
-                    .naive_utc(),
-            ),
-        )
+    fn get_start_time_utc(&self) -> DateTime<Utc> {
+        self.start_time.to_utc_in(&get_timezone())
     }
 
     fn get_end_time_utc(&self) -> Option<DateTime<Utc>> {
-        if self.end_time.is_none() {
-            return None;
-        }
-
-        let naive_time =
-            NaiveDateTime::parse_from_str(&self.end_time.as_ref().unwrap(), "%Y-%m-%dT%H:%M:%S");
-        if naive_time.is_err() {
-            panic!(
-                "Failed to parse end time {}",
-                self.end_time.as_ref().unwrap()
-            )
-        }
-        debug!("End Time Local {}", naive_time.unwrap());
-
-        Some(
-            Utc.from_utc_datetime(
-                &get_timezone()
-                    .from_local_datetime(&naive_time.unwrap())
-                    .unwrap()
-                    .naive_utc(),
-            ),
-        )
+        self.end_time.map(|end_time| end_time.to_utc_in(&get_timezone()))
     }
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
 pub struct EnergyConfig {
     night_start_hour: Option<u32>,
     night_end_hour: Option<u32>,
@@ -233,7 +215,7 @@ pub struct EnergyConfig {
     negative_no_tax: Option<bool>,
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
 pub struct TransferConfig {
     night_start_hour: Option<u32>,
     night_end_hour: Option<u32>,
@@ -244,7 +226,7 @@ pub struct TransferConfig {
     negative_no_tax: Option<bool>,
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq)]
 pub struct ContractsConfig {
     contracts: Vec<ContractConfig>,
 }
@@ -283,14 +265,17 @@ impl ContractsConfig {
 
     fn validate_contract_times(&self) -> Result<(), &'static str> {
         let mut contracts = self.contracts.clone();
-        contracts.sort_by(|a, b| a.start_time.cmp(&b.start_time));
+        contracts.sort_by_key(|contract| contract.start_time);
 
         for windows in contracts.windows(2) {
             let first = &windows[0];
             let second = &windows[1];
 
-            let start_time = second.get_start_time_utc().unwrap();
-            let end_time = first.get_end_time_utc().unwrap();
+            let start_time = second.get_start_time_utc();
+            let end_time = match first.get_end_time_utc() {
+                Some(end_time) => end_time,
+                None => return Err("Overlapping contracts detected"),
+            };
 
             if end_time >= start_time {
                 return Err("Overlapping contracts detected");
@@ -305,17 +290,537 @@ impl ContractsConfig {
     }
 }
 
+/// A metering point fetched by the logger.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct MeteringPointConfig {
+    pub code: String,
+    /// Human readable name used in the logs, e.g. `Summer cottage`
+    pub name: Option<String>,
+    /// `consumption` or `production`
+    pub kind: MeteringPointType,
+    #[serde(default = "default_resolutions")]
+    pub resolutions: Vec<ResolutionDuration>,
+    /// Contracts of this metering point, defaults to the top level contracts of its kind
+    pub contracts: Option<ContractsConfig>,
+}
+
+fn default_resolutions() -> Vec<ResolutionDuration> {
+    vec![ResolutionDuration::PT1H]
+}
+
+impl MeteringPointConfig {
+    pub fn new(code: impl Into<String>, measurement_type: MeasurementType, resolutions: Vec<ResolutionDuration>) -> Self {
+        Self {
+            code: code.into(),
+            name: None,
+            kind: if measurement_type.is_production() { MeteringPointType::Production } else { MeteringPointType::Consumption },
+            resolutions,
+            contracts: None,
+        }
+    }
+
+    /// `None` for kinds that the logger doesn't know how to fetch.
+    pub fn measurement_type(&self) -> Option<MeasurementType> {
+        self.kind.measurement_type()
+    }
+
+    pub fn validate(&self) -> Result<(), &'static str> {
+        if self.code.is_empty() {
+            return Err("Metering point code is empty");
+        }
+        if self.measurement_type().is_none() {
+            return Err("Metering point kind must be consumption or production");
+        }
+        if let Some(contracts) = &self.contracts {
+            contracts.validate()?;
+        }
+
+        Ok(())
+    }
+}
+
+impl std::fmt::Display for MeteringPointConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.name {
+            Some(name) => write!(f, "{} {} ({})", self.kind, self.code, name),
+            None => write!(f, "{} {}", self.kind, self.code),
+        }
+    }
+}
+
+/// WattiVahti account with its own credentials and metering points.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct AccountConfig {
+    pub name: String,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    pub token_endpoint: Option<String>,
+    /// Access token copied from the browser, used when no username is given
+    pub access_token: Option<String>,
+    /// Metering points to fetch
+    #[serde(default)]
+    pub metering_points: Vec<MeteringPointConfig>,
+}
+
+impl AccountConfig {
+    /// Account from the credentials of the `wattivahti` section.
+    pub fn from_settings(wattivahti: &WattiVahtiConfig, metering_points: Vec<MeteringPointConfig>) -> Self {
+        Self {
+            name: "default".to_string(),
+            username: wattivahti.username.clone(),
+            password: wattivahti.password.clone(),
+            token_endpoint: wattivahti.token_endpoint.clone(),
+            access_token: wattivahti.access_token.clone(),
+            metering_points,
+        }
+    }
+
+    /// The account from the `wattivahti` section picks up metering point codes from it as well.
+    pub fn is_default(&self) -> bool {
+        self.name == "default"
+    }
+
+    pub fn validate(&self) -> Result<(), &'static str> {
+        if self.name.is_empty() {
+            return Err("Account name is empty");
+        }
+        if self.username.is_some() && (self.password.is_none() || self.token_endpoint.is_none()) {
+            return Err("Account with a username needs a password and a token_endpoint");
+        }
+
+        for metering_point in &self.metering_points {
+            metering_point.validate()?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Daily fetch of the previous day.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct ScheduleConfig {
+    /// `ENABLE_AUTO_UPDATE`
+    pub enabled: bool,
+    /// Local time of the daily fetch, `FETCH_HOUR` and `FETCH_MINUTES`
+    pub fetch_hour: u32,
+    pub fetch_minutes: u32,
+    /// Wait before retrying an account whose access token failed, `INTERVAL`
+    pub interval_ms: u64,
+}
+
+impl Default for ScheduleConfig {
+    fn default() -> Self {
+        Self { enabled: false, fetch_hour: 6, fetch_minutes: 0, interval_ms: 3_600_000 }
+    }
+}
+
+impl ScheduleConfig {
+    fn validate(&self, errors: &mut Vec<String>) {
+        if self.fetch_hour > 23 {
+            errors.push(format!("schedule.fetch_hour: {} is not an hour of the day", self.fetch_hour));
+        }
+        if self.fetch_minutes > 59 {
+            errors.push(format!("schedule.fetch_minutes: {} is not a minute of the hour", self.fetch_minutes));
+        }
+        if self.interval_ms == 0 {
+            errors.push("schedule.interval_ms: must be greater than zero".to_string());
+        }
+    }
+}
+
+/// REST API for triggering fetches.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct ServerConfig {
+    /// `ENABLE_REST_API`
+    pub enabled: bool,
+    pub bind_address: String,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self { enabled: false, bind_address: "0.0.0.0:9090".to_string() }
+    }
+}
+
+impl ServerConfig {
+    fn validate(&self, errors: &mut Vec<String>) {
+        if self.bind_address.parse::<SocketAddr>().is_err() {
+            errors.push(format!("server.bind_address: {:?} is not an address and port", self.bind_address));
+        }
+    }
+}
+
+/// Retries of WattiVahti and token endpoint calls, `RETRY_MAX_ATTEMPTS`, `RETRY_BASE_DELAY_MS`
+/// and `RETRY_MAX_DELAY_MS`.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct RetryConfig {
+    pub max_attempts: u32,
+    pub base_delay_ms: u64,
+    pub max_delay_ms: u64,
+}
+
+impl Default for RetryConfig {
+    fn default() -> Self {
+        let policy = RetryPolicy::default();
+        Self {
+            max_attempts: policy.max_attempts,
+            base_delay_ms: policy.base_delay.as_millis() as u64,
+            max_delay_ms: policy.max_delay.as_millis() as u64,
+        }
+    }
+}
+
+impl RetryConfig {
+    pub fn policy(&self) -> RetryPolicy {
+        RetryPolicy::new(self.max_attempts, Duration::from_millis(self.base_delay_ms), Duration::from_millis(self.max_delay_ms))
+    }
+
+    fn validate(&self, errors: &mut Vec<String>) {
+        if self.max_attempts == 0 {
+            errors.push("wattivahti.retry.max_attempts: must be at least 1".to_string());
+        }
+        if self.base_delay_ms > self.max_delay_ms {
+            errors.push("wattivahti.retry.base_delay_ms: must not exceed max_delay_ms".to_string());
+        }
+    }
+}
+
+/// WattiVahti API and the default account, used when no `accounts` are listed.
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+#[serde(default)]
+pub struct WattiVahtiConfig {
+    /// `WATTIVAHTI_API_URL`, the public API when not set
+    pub api_url: Option<String>,
+    /// `WATTIVAHTI_USERNAME`, `WATTIVAHTI_PASSWORD` and `WATTIVAHTI_TOKEN_ENDPOINT`
+    pub username: Option<String>,
+    pub password: Option<String>,
+    pub token_endpoint: Option<String>,
+    /// `ACCESS_TOKEN`, used when no username is given
+    pub access_token: Option<String>,
+    /// `CONSUMPTION_METERING_POINT_CODE` and `PRODUCTION_METERING_POINT_CODE`
+    pub consumption_metering_point_code: Option<String>,
+    pub production_metering_point_code: Option<String>,
+    /// Resolutions fetched for metering points that aren't listed in the settings,
+    /// `FETCH_PT1H_RESOLUTION` and `FETCH_PT15M_RESOLUTION`
+    pub fetch_resolutions: Vec<ResolutionDuration>,
+    pub retry: RetryConfig,
+}
+
+impl WattiVahtiConfig {
+    fn validate(&self, errors: &mut Vec<String>) {
+        if let Some(api_url) = &self.api_url {
+            if reqwest::Url::parse(api_url).is_err() {
+                errors.push(format!("wattivahti.api_url: {:?} is not a URL", api_url));
+            }
+        }
+        if self.username.is_some() && (self.password.is_none() || self.token_endpoint.is_none()) {
+            errors.push("wattivahti.username: needs a password and a token_endpoint".to_string());
+        }
+        self.retry.validate(errors);
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct InfluxDbConfig {
+    /// `INFLUXDB_ENABLED`
+    pub enabled: bool,
+    /// `DATABASE_URL` and `DATABASE_NAME`
+    pub url: String,
+    pub database: String,
+    /// Only store hourly values, `INFLUXDB_SKIP_PT15M`
+    pub skip_pt15m: bool,
+}
+
+impl Default for InfluxDbConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            url: "http://localhost:8086".to_string(),
+            database: "entsoe".to_string(),
+            skip_pt15m: false,
+        }
+    }
+}
+
+impl InfluxDbConfig {
+    fn validate(&self, errors: &mut Vec<String>) {
+        if self.enabled && reqwest::Url::parse(&self.url).is_err() {
+            errors.push(format!("influxdb.url: {:?} is not a URL", self.url));
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct TimescaleDbConfig {
+    /// `TIMESCALEDB_ENABLED`
+    pub enabled: bool,
+    /// `TIMESCALEDB_CONNECTION_STRING`. Connections are unencrypted unless it has `sslmode=require`,
+    /// e.g. for managed Postgres, or `ca_certificate` is set.
+    pub connection_string: String,
+    /// Connections kept open and shared by the updates and the REST API
+    pub pool_size: usize,
+    /// PEM file of the CA certificates to trust in addition to the public web roots, enables TLS
+    pub ca_certificate: Option<String>,
+}
+
+impl Default for TimescaleDbConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            connection_string: "host=localhost user=myuser password=mysecretpassword dbname=electricity".to_string(),
+            pool_size: 4,
+            ca_certificate: None,
+        }
+    }
+}
+
+impl TimescaleDbConfig {
+    fn validate(&self, errors: &mut Vec<String>) {
+        if self.enabled {
+            if let Err(err) = tokio_postgres::Config::from_str(&self.connection_string) {
+                errors.push(format!("timescaledb.connection_string: {}", err));
+            }
+            if self.pool_size == 0 {
+                errors.push("timescaledb.pool_size: must be at least 1".to_string());
+            }
+            if let Some(path) = &self.ca_certificate {
+                if !Path::new(path).is_file() {
+                    errors.push(format!("timescaledb.ca_certificate: {} is not a file", path));
+                }
+            }
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct SqliteConfig {
+    pub enabled: bool,
+    /// Database file, created when missing
+    pub path: String,
+}
+
+impl Default for SqliteConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            path: "energies.sqlite3".to_string(),
+        }
+    }
+}
+
+impl SqliteConfig {
+    fn validate(&self, errors: &mut Vec<String>) {
+        if self.enabled && self.path.trim().is_empty() {
+            errors.push("sqlite.path: must not be empty".to_string());
+        }
+    }
+}
+
+/// Day-ahead prices from the ENTSO-E Transparency Platform.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct EntsoeConfig {
+    /// Fetch the prices every `interval_ms` while the schedule is enabled
+    pub enabled: bool,
+    pub api_url: String,
+    /// Web API security token of the Transparency Platform account
+    pub security_token: Option<String>,
+    /// Bidding zone such as FI or SE3, or its EIC code
+    pub bidding_zone: String,
+    pub interval_ms: u64,
+}
+
+impl Default for EntsoeConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            api_url: "https://web-api.tp.entsoe.eu/api".to_string(),
+            security_token: None,
+            bidding_zone: "FI".to_string(),
+            interval_ms: 3_600_000,
+        }
+    }
+}
+
+impl EntsoeConfig {
+    fn validate(&self, errors: &mut Vec<String>) {
+        if reqwest::Url::parse(&self.api_url).is_err() {
+            errors.push(format!("entsoe.api_url: {:?} is not a URL", self.api_url));
+        }
+        if crate::entsoe::bidding_zone_eic(&self.bidding_zone).is_none() {
+            errors.push(format!("entsoe.bidding_zone: {:?} is not a known bidding zone or an EIC code", self.bidding_zone));
+        }
+        if self.enabled && self.security_token.is_none() {
+            errors.push("entsoe.security_token: needed when entsoe is enabled".to_string());
+        }
+        if self.interval_ms == 0 {
+            errors.push("entsoe.interval_ms: must be greater than zero".to_string());
+        }
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct SettingsConfig {
+    /// Time zone of the local times in the settings and the API, `CHRONO_TIMEZONE`
+    #[serde(default = "default_timezone")]
+    pub timezone: String,
+    #[serde(default)]
+    pub schedule: ScheduleConfig,
+    #[serde(default)]
+    pub server: ServerConfig,
+    #[serde(default)]
+    pub wattivahti: WattiVahtiConfig,
+    #[serde(default)]
+    pub influxdb: InfluxDbConfig,
+    #[serde(default)]
+    pub timescaledb: TimescaleDbConfig,
+    #[serde(default)]
+    pub sqlite: SqliteConfig,
+    #[serde(default)]
+    pub entsoe: EntsoeConfig,
+    #[serde(default)]
     pub consumption: ContractsConfig,
+    #[serde(default)]
     pub production: ContractsConfig,
+    /// Metering points to fetch. When empty, the metering points come from the environment.
+    #[serde(default)]
+    pub metering_points: Vec<MeteringPointConfig>,
+    /// Accounts to fetch. When empty, the credentials come from the environment and the
+    /// metering points from `metering_points`.
+    #[serde(default)]
+    pub accounts: Vec<AccountConfig>,
+}
+
+fn default_timezone() -> String {
+    "Europe/Helsinki".to_string()
+}
+
+impl Default for SettingsConfig {
+    fn default() -> Self {
+        Self {
+            timezone: default_timezone(),
+            schedule: ScheduleConfig::default(),
+            server: ServerConfig::default(),
+            wattivahti: WattiVahtiConfig::default(),
+            influxdb: InfluxDbConfig::default(),
+            timescaledb: TimescaleDbConfig::default(),
+            sqlite: SqliteConfig::default(),
+            entsoe: EntsoeConfig::default(),
+            consumption: ContractsConfig::default(),
+            production: ContractsConfig::default(),
+            metering_points: Vec::new(),
+            accounts: Vec::new(),
+        }
+    }
 }
 
 impl SettingsConfig {
-    pub fn validate(&self) -> Result<(), &'static str> {
-        self.consumption.validate()?;
-        self.production.validate()?;
+    pub fn get_timezone(&self) -> Result<Tz, String> {
+        self.timezone.parse()
+    }
 
-        Ok(())
+    /// Production contracts price produced energy, every other measurement type uses the consumption contracts.
+    pub fn get_contracts(&self, measurement_type: MeasurementType) -> &ContractsConfig {
+        if measurement_type.is_production() {
+            &self.production
+        } else {
+            &self.consumption
+        }
+    }
+
+    /// Contracts of the metering point, or the top level contracts of its kind.
+    pub fn get_metering_point_contracts<'a>(&'a self, metering_point: &'a MeteringPointConfig) -> &'a ContractsConfig {
+        match (&metering_point.contracts, metering_point.measurement_type()) {
+            (Some(contracts), _) => contracts,
+            (None, Some(measurement_type)) => self.get_contracts(measurement_type),
+            (None, None) => &self.consumption,
+        }
+    }
+
+    /// Contracts of the metering point with the code, or the top level contracts of the
+    /// measurement type when the metering point isn't listed.
+    pub fn get_contracts_by_code(&self, code: &str, measurement_type: MeasurementType) -> ContractsConfig {
+        self.get_accounts()
+            .into_iter()
+            .flat_map(|account| account.metering_points)
+            .find(|metering_point| metering_point.code == code)
+            .map(|metering_point| self.get_metering_point_contracts(&metering_point).clone())
+            .unwrap_or_else(|| self.get_contracts(measurement_type).clone())
+    }
+
+    /// Configured accounts, or the default account of the `wattivahti` section.
+    pub fn get_accounts(&self) -> Vec<AccountConfig> {
+        if self.accounts.is_empty() {
+            return vec![AccountConfig::from_settings(&self.wattivahti, self.metering_points.clone())];
+        }
+
+        self.accounts.clone()
+    }
+
+    /// Checks every setting and reports all invalid ones at once.
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        let mut errors = Vec::new();
+
+        if let Err(err) = self.get_timezone() {
+            errors.push(format!("timezone: {}", err));
+        }
+        self.schedule.validate(&mut errors);
+        self.server.validate(&mut errors);
+        self.wattivahti.validate(&mut errors);
+        self.influxdb.validate(&mut errors);
+        self.timescaledb.validate(&mut errors);
+        self.sqlite.validate(&mut errors);
+        self.entsoe.validate(&mut errors);
+
+        if let Err(err) = self.consumption.validate() {
+            errors.push(format!("consumption: {}", err));
+        }
+        if let Err(err) = self.production.validate() {
+            errors.push(format!("production: {}", err));
+        }
+
+        if !self.accounts.is_empty() && !self.metering_points.is_empty() {
+            errors.push("metering_points: List the metering points under their accounts when accounts are used".to_string());
+        }
+
+        for (index, account) in self.accounts.iter().enumerate() {
+            if let Err(err) = account.validate() {
+                errors.push(format!("accounts.{}: {}", index, err));
+            }
+
+            if self.accounts[..index].iter().any(|other| other.name == account.name) {
+                errors.push(format!("accounts.{}: Duplicate account name {} detected", index, account.name));
+            }
+        }
+
+        let metering_points: Vec<&MeteringPointConfig> = self
+            .metering_points
+            .iter()
+            .chain(self.accounts.iter().flat_map(|account| account.metering_points.iter()))
+            .collect();
+        for (index, metering_point) in metering_points.iter().enumerate() {
+            if let Err(err) = metering_point.validate() {
+                errors.push(format!("metering point {}: {}", metering_point, err));
+            }
+
+            let duplicate = metering_points[..index]
+                .iter()
+                .any(|other| other.code == metering_point.code && other.kind == metering_point.kind);
+            if duplicate {
+                errors.push(format!("metering point {}: Duplicate metering point detected", metering_point));
+            }
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(ConfigError::Invalid(errors))
+        }
     }
 }
@@ -0,0 +1,135 @@
+//! Env var overrides of the settings file.
+
+use std::collections::BTreeMap;
+
+use api::ResolutionDuration;
+use serde_yaml::Value;
+
+/// Prefix of env vars that set any key of the settings, e.g. `LOGGER__SCHEDULE__FETCH_HOUR=7`
+/// sets `schedule.fetch_hour` and `LOGGER__ACCOUNTS__0__PASSWORD` the password of the first account.
+pub const ENV_PREFIX: &str = "LOGGER__";
+
+/// Env vars from before the settings file covered the runtime settings, and the keys they set.
+const LEGACY_VARS: &[(&str, &str)] = &[
+    ("CHRONO_TIMEZONE", "timezone"),
+    ("ENABLE_AUTO_UPDATE", "schedule.enabled"),
+    ("FETCH_HOUR", "schedule.fetch_hour"),
+    ("FETCH_MINUTES", "schedule.fetch_minutes"),
+    ("INTERVAL", "schedule.interval_ms"),
+    ("ENABLE_REST_API", "server.enabled"),
+    ("WATTIVAHTI_API_URL", "wattivahti.api_url"),
+    ("WATTIVAHTI_USERNAME", "wattivahti.username"),
+    ("WATTIVAHTI_PASSWORD", "wattivahti.password"),
+    ("WATTIVAHTI_TOKEN_ENDPOINT", "wattivahti.token_endpoint"),
+    ("ACCESS_TOKEN", "wattivahti.access_token"),
+    ("CONSUMPTION_METERING_POINT_CODE", "wattivahti.consumption_metering_point_code"),
+    ("PRODUCTION_METERING_POINT_CODE", "wattivahti.production_metering_point_code"),
+    ("RETRY_MAX_ATTEMPTS", "wattivahti.retry.max_attempts"),
+    ("RETRY_BASE_DELAY_MS", "wattivahti.retry.base_delay_ms"),
+    ("RETRY_MAX_DELAY_MS", "wattivahti.retry.max_delay_ms"),
+    ("INFLUXDB_ENABLED", "influxdb.enabled"),
+    ("DATABASE_URL", "influxdb.url"),
+    ("DATABASE_NAME", "influxdb.database"),
+    ("INFLUXDB_SKIP_PT15M", "influxdb.skip_pt15m"),
+    ("TIMESCALEDB_ENABLED", "timescaledb.enabled"),
+    ("TIMESCALEDB_CONNECTION_STRING", "timescaledb.connection_string"),
+];
+
+/// Legacy flags that together make up `wattivahti.fetch_resolutions`.
+const LEGACY_FETCH_RESOLUTIONS: &[(&str, ResolutionDuration)] = &[
+    ("FETCH_PT1H_RESOLUTION", ResolutionDuration::PT1H),
+    ("FETCH_PT15M_RESOLUTION", ResolutionDuration::PT15M),
+];
+
+/// Applies the env vars to the settings, which must already contain every known key with its
+/// default value. The legacy names are applied first so that the prefixed ones win. Empty vars
+/// are ignored. Returns an error for each var that couldn't be applied, leaving its key as it was.
+pub fn apply_env_overrides(settings: &mut Value, vars: impl IntoIterator<Item = (String, String)>) -> Vec<String> {
+    let vars: BTreeMap<String, String> = vars.into_iter().filter(|(_, value)| !value.is_empty()).collect();
+    let mut errors = Vec::new();
+
+    for (var, key) in LEGACY_VARS {
+        if let Some(value) = vars.get(*var) {
+            let path: Vec<&str> = key.split('.').collect();
+            if let Err(err) = set_key(settings, &path, value) {
+                errors.push(format!("{}: {}", var, err));
+            }
+        }
+    }
+    apply_legacy_fetch_resolutions(settings, &vars, &mut errors);
+
+    for (var, value) in &vars {
+        if let Some(key) = var.strip_prefix(ENV_PREFIX) {
+            let key = key.to_lowercase();
+            let path: Vec<&str> = key.split("__").collect();
+            if let Err(err) = set_key(settings, &path, value) {
+                errors.push(format!("{}: {}", var, err));
+            }
+        }
+    }
+
+    errors
+}
+
+fn apply_legacy_fetch_resolutions(settings: &mut Value, vars: &BTreeMap<String, String>, errors: &mut Vec<String>) {
+    if !LEGACY_FETCH_RESOLUTIONS.iter().any(|(var, _)| vars.contains_key(*var)) {
+        return;
+    }
+
+    let mut resolutions = Vec::new();
+    for (var, resolution) in LEGACY_FETCH_RESOLUTIONS {
+        match vars.get(*var).map(|value| value.parse::<bool>()) {
+            Some(Ok(true)) => resolutions.push(Value::String(resolution.to_string())),
+            Some(Err(_)) => errors.push(format!("{}: expected true or false", var)),
+            _ => {}
+        }
+    }
+
+    if let Some(slot) = find_key(settings, &["wattivahti", "fetch_resolutions"]) {
+        *slot = Value::Sequence(resolutions);
+    }
+}
+
+/// Sets a key to the value of an env var, parsed according to the value it replaces.
+fn set_key(settings: &mut Value, path: &[&str], raw: &str) -> Result<(), String> {
+    let slot = find_key(settings, path).ok_or_else(|| format!("unknown setting {}", path.join(".")))?;
+    *slot = parse_as(slot, raw)?;
+    Ok(())
+}
+
+/// Key at the path. Keys of list items may be missing from the file, so they are added.
+fn find_key<'a>(settings: &'a mut Value, path: &[&str]) -> Option<&'a mut Value> {
+    let mut node = settings;
+    let mut in_list = false;
+    for segment in path {
+        node = match node {
+            Value::Mapping(mapping) => {
+                if in_list && !mapping.contains_key(*segment) {
+                    mapping.insert(Value::String(segment.to_string()), Value::Null);
+                }
+                mapping.get_mut(*segment)?
+            }
+            Value::Sequence(items) => {
+                in_list = true;
+                items.get_mut(segment.parse::<usize>().ok()?)?
+            }
+            _ => return None,
+        };
+    }
+    Some(node)
+}
+
+fn parse_as(current: &Value, raw: &str) -> Result<Value, String> {
+    match current {
+        Value::Bool(_) => raw
+            .parse()
+            .map(Value::Bool)
+            .map_err(|_| format!("expected true or false, got {:?}", raw)),
+        Value::Number(_) => match serde_yaml::from_str(raw) {
+            Ok(Value::Number(number)) => Ok(Value::Number(number)),
+            _ => Err(format!("expected a number, got {:?}", raw)),
+        },
+        Value::Sequence(_) | Value::Mapping(_) => serde_yaml::from_str(raw).map_err(|err| err.to_string()),
+        _ => Ok(Value::String(raw.to_string())),
+    }
+}
@@ -1,3 +1,4 @@
 pub mod config;
 pub mod config_model;
+pub mod env;
 pub mod time;
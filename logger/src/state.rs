@@ -0,0 +1,208 @@
+//! Settings and clients shared by the REST API and the update loops.
+
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use anyhow::bail;
+use api::WattiVahtiClient;
+use tokio::sync::mpsc::UnboundedSender;
+use tokio::time::sleep;
+
+use crate::app::build_wattivahti_client;
+use crate::settings::config::SettingsFiles;
+use crate::settings::config_model::SettingsConfig;
+use crate::storage::sink::SinkRegistry;
+use crate::storage::SINKS;
+
+/// How often the settings files are checked for changes.
+const WATCH_INTERVAL: Duration = Duration::from_secs(5);
+
+pub struct AppState {
+    files: SettingsFiles,
+    settings: RwLock<Arc<SettingsConfig>>,
+    client: RwLock<WattiVahtiClient>,
+    sinks: RwLock<Arc<SinkRegistry>>,
+}
+
+impl AppState {
+    pub fn load(files: SettingsFiles) -> Result<Self, anyhow::Error> {
+        let settings = files.load()?;
+        let client = build_wattivahti_client(&settings.wattivahti)?;
+        let sinks = SinkRegistry::from_config(&settings, SINKS)?;
+        apply(&settings);
+
+        Ok(Self {
+            files,
+            settings: RwLock::new(Arc::new(settings)),
+            client: RwLock::new(client),
+            sinks: RwLock::new(Arc::new(sinks)),
+        })
+    }
+
+    pub fn files(&self) -> &SettingsFiles {
+        &self.files
+    }
+
+    /// Settings as of the last successful load.
+    pub fn settings(&self) -> Arc<SettingsConfig> {
+        self.settings.read().unwrap().clone()
+    }
+
+    pub fn client(&self) -> WattiVahtiClient {
+        self.client.read().unwrap().clone()
+    }
+
+    /// Sinks of the backends enabled in the current settings. They hold the pooled database
+    /// connections, so every update and request shares them.
+    pub fn sinks(&self) -> Arc<SinkRegistry> {
+        self.sinks.read().unwrap().clone()
+    }
+
+    /// Loads the settings files again. Invalid settings, or sinks that can't be created with them
+    /// or reached, leave the current settings and sinks in place. Returns the names of the accounts
+    /// the reload added.
+    pub async fn reload(&self) -> Result<Vec<String>, anyhow::Error> {
+        let settings = self.files.load()?;
+        let sinks = SinkRegistry::from_config(&settings, SINKS)?;
+        let client = build_wattivahti_client(&settings.wattivahti)?;
+
+        let health = sinks.health_check().await;
+        if !health.is_ok() {
+            bail!("Storage backends can't be reached: {}", health.failure_messages().join(", "));
+        }
+
+        let previous = self.settings();
+        let added: Vec<String> = settings
+            .get_accounts()
+            .into_iter()
+            .filter(|account| !previous.get_accounts().iter().any(|other| other.name == account.name))
+            .map(|account| account.name)
+            .collect();
+
+        apply(&settings);
+        *self.settings.write().unwrap() = Arc::new(settings);
+        *self.client.write().unwrap() = client;
+        *self.sinks.write().unwrap() = Arc::new(sinks);
+        info!("Settings reloaded");
+        Ok(added)
+    }
+}
+
+/// Applies the process wide settings, once nothing else about them can fail.
+fn apply(settings: &SettingsConfig) {
+    // Validated while loading
+    api::set_timezone(settings.get_timezone().unwrap());
+}
+
+/// Reloads the settings on SIGHUP and when one of the settings files changes. The names of the
+/// accounts a reload adds are sent to `added` so their updates can be started.
+pub async fn watch_settings(state: Arc<AppState>, added: UnboundedSender<String>) {
+    let mut modified = state.files().modified();
+
+    #[cfg(unix)]
+    let mut hangup = match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup()) {
+        Ok(signal) => Some(signal),
+        Err(err) => {
+            warn!("Failed to listen for SIGHUP, settings are only reloaded on file changes: {}", err);
+            None
+        }
+    };
+
+    loop {
+        #[cfg(unix)]
+        let reason = match hangup.as_mut() {
+            Some(hangup) => tokio::select! {
+                _ = hangup.recv() => "SIGHUP",
+                _ = sleep(WATCH_INTERVAL) => "file change",
+            },
+            None => {
+                sleep(WATCH_INTERVAL).await;
+                "file change"
+            }
+        };
+        #[cfg(not(unix))]
+        let reason = {
+            sleep(WATCH_INTERVAL).await;
+            "file change"
+        };
+
+        let current = state.files().modified();
+        if reason == "file change" && current == modified {
+            continue;
+        }
+        modified = current;
+
+        info!("Reloading settings because of {}", reason);
+        match state.reload().await {
+            Ok(accounts) => {
+                for name in accounts {
+                    // Updates may not be running at all
+                    let _ = added.send(name);
+                }
+            }
+            Err(err) => error!("Failed to reload settings, keeping the current ones: {:#}", err),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+
+    const BASE: &str = "schedule:\n  fetch_hour: 7\ninfluxdb:\n  database: wattivahti\n";
+
+    #[tokio::test]
+    async fn test_layered_settings_reload() {
+        let dir = tempfile::tempdir().unwrap();
+        let files = SettingsFiles::new(dir.path().join("home.yaml"));
+        assert_eq!(files.local, dir.path().join("home.local.yaml"));
+        fs::write(&files.base, BASE).unwrap();
+
+        let state = AppState::load(files.clone()).unwrap();
+        assert_eq!(state.settings().schedule.fetch_hour, 7);
+        let before = state.files().modified();
+
+        // The local file overrides single keys of the base file
+        fs::write(&files.local, "schedule:\n  fetch_hour: 9\n").unwrap();
+        assert_ne!(state.files().modified(), before);
+        assert!(state.reload().await.unwrap().is_empty());
+        assert_eq!(state.settings().schedule.fetch_hour, 9);
+        assert_eq!(state.settings().influxdb.database, "wattivahti");
+
+        // Accounts the reload adds are reported so their updates can be started
+        let accounts = "schedule:\n  fetch_hour: 9\naccounts:\n  - name: default\n  - name: cottage\n    access_token: token\n";
+        fs::write(&files.local, accounts).unwrap();
+        assert_eq!(state.reload().await.unwrap(), vec!["cottage".to_string()]);
+        fs::write(&files.local, "schedule:\n  fetch_hour: 9\n").unwrap();
+        assert!(state.reload().await.unwrap().is_empty());
+
+        // Sinks that can't be created keep the previous settings and sinks
+        let ca = dir.path().join("ca.pem");
+        fs::write(&ca, "not a certificate").unwrap();
+        fs::write(&files.local, format!("timescaledb:\n  enabled: true\n  ca_certificate: {}\n", ca.display())).unwrap();
+        assert!(state.reload().await.is_err());
+        assert!(!state.settings().timescaledb.enabled);
+        assert!(state.sinks().names().is_empty());
+
+        // So do sinks that can't be reached, without changing the time zone either
+        let unreachable = "timezone: Europe/Stockholm\ntimescaledb:\n  enabled: true\n  connection_string: host=127.0.0.1 port=1 user=nobody\n";
+        fs::write(&files.local, unreachable).unwrap();
+        let err = state.reload().await.unwrap_err();
+        assert!(err.to_string().starts_with("Storage backends can't be reached"), "{:#}", err);
+        assert!(!state.settings().timescaledb.enabled);
+        assert!(state.sinks().names().is_empty());
+        assert_eq!(api::get_timezone(), chrono_tz::Europe::Helsinki);
+        fs::write(&files.local, "schedule:\n  fetch_hour: 9\n").unwrap();
+
+        // Invalid settings keep the previous ones
+        fs::write(&files.local, "schedule:\n  fetch_hour: 25\n").unwrap();
+        assert!(state.reload().await.is_err());
+        assert_eq!(state.settings().schedule.fetch_hour, 9);
+
+        fs::remove_file(&files.base).unwrap();
+        assert!(state.reload().await.is_err());
+        assert!(AppState::load(files).is_err());
+    }
+}
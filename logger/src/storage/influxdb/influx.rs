@@ -1,258 +1,269 @@
-use api::{ConsumptionsResult, ResolutionDuration};
+use std::sync::Arc;
+
+use anyhow::bail;
+use api::{MeasurementType, ResolutionDuration};
+use async_trait::async_trait;
 use chrono::{DateTime, Utc};
-use influxdb::{Client, InfluxDbWriteable, ReadQuery};
+use influxdb::{Client, InfluxDbWriteable, ReadQuery, WriteQuery};
 
-use crate::{settings::config_model::SettingsConfig, storage::influxdb::time_series_value::TimeSeriesValue};
+use crate::{
+    entsoe::{DayAheadPrice, DAY_AHEAD_PRICES},
+    pricing::{PricedInterval, SpotPrices},
+    settings::config_model::{InfluxDbConfig, SettingsConfig},
+    storage::influxdb::time_series_value::TimeSeriesValue,
+    storage::sink::{MeterDataBatch, MeterDataSink},
+};
 
 use super::price_data::PriceData;
 
-pub fn is_enabled() -> bool {
-    dotenv::var("INFLUXDB_ENABLED")
-        .map(|var| var.parse::<bool>())
-        .unwrap_or(Ok(false))
-        .unwrap()
+pub struct InfluxDbSink {
+    /// Shared by every write and read, the client keeps its HTTP connections open
+    client: Client,
+    skip_pt15m: bool,
 }
 
-pub async fn upsert_productions_into_influxdb(
-    data: &ConsumptionsResult,
-    config: &SettingsConfig,
-) -> Result<(), anyhow::Error> {
-    if !is_enabled() {
-        return Ok(());
+impl InfluxDbSink {
+    pub fn from_config(config: &SettingsConfig) -> Result<Option<Arc<dyn MeterDataSink>>, anyhow::Error> {
+        if !config.influxdb.enabled {
+            return Ok(None);
+        }
+        Ok(Some(Arc::new(Self { client: connect_to_db(&config.influxdb), skip_pt15m: config.influxdb.skip_pt15m })))
     }
+}
 
-    let resolution = &data
-        .getconsumptionsresult
-        .consumptiondata
-        .timeseries
-        .resolution;
-
-    let resolution_duration = ResolutionDuration::from_str(resolution);
-
-    let skip_pt15m: bool = dotenv::var("INFLUXDB_SKIP_PT15M")
-        .unwrap_or_else(|_| String::from("false"))
-        .parse()
-        .unwrap_or(false);
-
-    if skip_pt15m && resolution_duration.eq(&ResolutionDuration::PT15M) {
-        return Ok(());
+#[async_trait]
+impl MeterDataSink for InfluxDbSink {
+    fn name(&self) -> &'static str {
+        "InfluxDB"
     }
 
-    let mut messages = Vec::new();
-
-    let client = connect_to_db().await;
-    for (pos, tsv) in data
-        .getconsumptionsresult
-        .consumptiondata
-        .timeseries
-        .values
-        .tsv
-        .iter()
-        .enumerate()
-    {
-        let time = &tsv.get_timestamp_utc_calculated(pos, &resolution_duration);
-        if time.is_none() {
-            warn!("InfluxDB | Skipping production logging because time couldn't be parsed");
-            continue;
+    async fn write(&self, batch: &MeterDataBatch<'_>) -> Result<usize, anyhow::Error> {
+        if self.skip_pt15m && batch.resolution == ResolutionDuration::PT15M {
+            return Ok(0);
         }
+        upsert_intervals_into_influxdb(&self.client, batch.intervals).await
+    }
 
-        if tsv.quantity.is_none() {
-            // warn!("InfluxDB | Skipping production logging because quantity was null");
-            continue;
-        }
+    async fn day_ahead_prices(&self, start: DateTime<Utc>, stop: DateTime<Utc>) -> Result<Option<SpotPrices>, anyhow::Error> {
+        Ok(Some(get_day_ahead_prices(&self.client, start, stop).await?))
+    }
 
-        let time = time.unwrap();
-        let price = get_day_ahead_price(&client, &time).await;
-        let contract = config.production.get_contract(time);
-        if contract.is_none() {
-            warn!("InfluxDB | Skipping production logging because contract couldn't be found");
-            continue;
-        }
-        let contract = contract.unwrap();
-        let transfer_fee = contract.get_transfer_fee(time);
-
-        let meteringpointcode = &data.getconsumptionsresult.consumptiondata.meteringpointcode;
-        let measurementtype = "6";
-        let unit = &data.getconsumptionsresult.consumptiondata.sum.unit;
-        let value = tsv.quantity.unwrap();
-        let tax_percentage = contract.get_tax_percentage();
-
-        let transfer_basic_fee = contract.get_transfer_basic_fee();
-        let transfer_tax_fee = contract.get_transfer_tax_fee();
-        let energy_basic_fee = contract.get_energy_basic_fee();
-        let energy_fee = contract.get_energy_fee(price, time);
-
-        let current_data = TimeSeriesValue {
-            time: time,
-            meteringpointcode_tag: meteringpointcode.to_string(),
-            measurementtype_tag: measurementtype.to_string(),
-            resolution_duration_tag: if resolution_duration.eq(&ResolutionDuration::PT1H) { None } else { Some(resolution.to_string()) },
-            meteringpointcode: meteringpointcode.to_string(),
-            measurementtype: measurementtype.to_string(),
-            resolution_duration: if resolution_duration.eq(&ResolutionDuration::PT1H) { None } else { Some(resolution.to_string()) },
-            unit: unit.to_string(),
-            timestamp: time.format("%Y-%m-%dT%H:%M:%S").to_string(),
-            value: value,
-            price: price / 1000.0,
-
-            transfer_basic_fee: Some(transfer_basic_fee),
-            transfer_fee: Some(transfer_fee),
-            tax_fee: Some(transfer_tax_fee),
-            basic_fee: Some(energy_basic_fee),
-            energy_fee: Some(energy_fee),
-
-            contract_type: contract.contract_type.clone().into(),
-            spot_margin: None,
-            tax_percentage: Some(tax_percentage),
-        };
-
-        let write_result = client.query(&current_data.into_query("productions")).await;
-        if let Err(err) = write_result {
-            error!("Error writing to db: {}", err)
-        }
+    async fn write_day_ahead_prices(&self, prices: &[DayAheadPrice]) -> Result<Option<usize>, anyhow::Error> {
+        Ok(Some(upsert_day_ahead_prices_into_influxdb(&self.client, prices).await?))
+    }
 
-        messages.push(format!("InfluxDB | Production {} - {:.2}", time, value));
+    async fn health_check(&self) -> Result<(), anyhow::Error> {
+        self.client.ping().await?;
+        Ok(())
     }
+}
 
-    let all_messages = messages.join("\n");
-    info!("{}", all_messages);
+/// Points per write request, the batch size InfluxDB recommends.
+const WRITE_BATCH_SIZE: usize = 5000;
 
-    Ok(())
+/// Outcome of writing points in batches.
+#[derive(Debug, Default, PartialEq)]
+pub struct WriteReport {
+    pub written: usize,
+    pub failed: usize,
 }
 
-pub async fn upsert_consumptions_into_influxdb(
-    data: &ConsumptionsResult,
-    config: &SettingsConfig,
-) -> Result<(), anyhow::Error> {
-    if !is_enabled() {
-        return Ok(());
+impl WriteReport {
+    /// Written points, or an error with both counts when any batch failed.
+    fn into_result(self) -> Result<usize, anyhow::Error> {
+        if self.failed > 0 {
+            bail!("{} of {} points failed to write", self.failed, self.written + self.failed);
+        }
+        Ok(self.written)
     }
+}
 
-    let resolution = &data
-        .getconsumptionsresult
-        .consumptiondata
-        .timeseries
-        .resolution;
-    let resolution_duration = ResolutionDuration::from_str(resolution);
-
-    let skip_pt15m: bool = dotenv::var("INFLUXDB_SKIP_PT15M")
-        .unwrap_or_else(|_| String::from("false"))
-        .parse()
-        .unwrap_or(false);
+/// Upserts the intervals in batches of line protocol, returns how many were written.
+pub async fn upsert_intervals_into_influxdb(client: &Client, intervals: &[PricedInterval]) -> Result<usize, anyhow::Error> {
+    let points = intervals
+        .iter()
+        .map(|interval| to_time_series_value(interval).into_query(get_measurement_name(interval.measurement_type)))
+        .collect();
 
-    if skip_pt15m && resolution_duration.eq(&ResolutionDuration::PT15M) {
-        return Ok(());
-    }
+    let report = write_points(client, points, WRITE_BATCH_SIZE).await;
+    info!("InfluxDB | Wrote {} intervals, {} failed", report.written, report.failed);
+    report.into_result()
+}
 
-    let mut messages = Vec::new();
+/// Writes the points `batch_size` at a time. A failed batch counts all of its points as failed
+/// and doesn't stop the batches after it.
+pub async fn write_points(client: &Client, points: Vec<WriteQuery>, batch_size: usize) -> WriteReport {
+    let mut report = WriteReport::default();
+    let mut points = points.into_iter();
 
-    let client = connect_to_db().await;
-    for (pos, tsv) in data
-        .getconsumptionsresult
-        .consumptiondata
-        .timeseries
-        .values
-        .tsv
-        .iter()
-        .enumerate()
-    {
-        let time = &tsv.get_timestamp_utc_calculated(pos, &resolution_duration);
-        if time.is_none() {
-            warn!("InfluxDB | Skipping consumption logging because time couldn't be parsed");
-            continue;
+    loop {
+        let batch: Vec<WriteQuery> = points.by_ref().take(batch_size).collect();
+        if batch.is_empty() {
+            return report;
         }
 
-        if tsv.quantity.is_none() {
-            // warn!("InfluxDB | Skipping consumption logging because quantity was null");
-            continue;
+        let count = batch.len();
+        match client.query(batch).await {
+            Ok(_) => report.written += count,
+            Err(err) => {
+                error!("InfluxDB | Writing a batch of {} points failed: {}", count, err);
+                report.failed += count;
+            }
         }
+    }
+}
 
-        let time = time.unwrap();
-        let price = get_day_ahead_price(&client, &time).await;
-        let contract = config.consumption.get_contract(time);
-        if contract.is_none() {
-            warn!("InfluxDB | Skipping consumption logging because contract couldn't be found");
-            continue;
-        }
-        let contract = contract.unwrap();
-        let transfer_basic_fee = contract.get_transfer_basic_fee();
-        let transfer_fee = contract.get_transfer_fee(time);
-        let tax_fee = contract.get_transfer_tax_fee();
-        let basic_fee = contract.get_energy_basic_fee();
-        let energy_fee = contract.get_energy_fee(price, time);
-
-        let meteringpointcode = &data.getconsumptionsresult.consumptiondata.meteringpointcode;
-        let measurementtype = "1";
-        let unit = &data.getconsumptionsresult.consumptiondata.sum.unit;
-        let value = tsv.quantity.unwrap();
-
-        let current_data = TimeSeriesValue {
-            time: time,
-            meteringpointcode_tag: meteringpointcode.to_string(),
-            measurementtype_tag: measurementtype.to_string(),
-            resolution_duration_tag: if resolution_duration.eq(&ResolutionDuration::PT1H) { None } else { Some(resolution.to_string()) },
-            meteringpointcode: meteringpointcode.to_string(),
-            measurementtype: measurementtype.to_string(),
-            resolution_duration: if resolution_duration.eq(&ResolutionDuration::PT1H) { None } else { Some(resolution.to_string()) },
-            unit: unit.to_string(),
-            timestamp: time.format("%Y-%m-%dT%H:%M:%S").to_string(),
-            value: value,
-            price: price / 1000.0,
-
-            transfer_basic_fee: Some(transfer_basic_fee),
-            transfer_fee: Some(transfer_fee),
-            tax_fee: Some(tax_fee),
-            basic_fee: Some(basic_fee),
-            energy_fee: Some(energy_fee),
-
-            contract_type: contract.contract_type.clone().into(),
-            spot_margin: contract.get_spot_margin(),
-            tax_percentage: Some(contract.get_tax_percentage()),
-        };
-
-        let write_result = client.query(&current_data.into_query("consumptions")).await;
-        if let Err(err) = write_result {
-            error!("Error writing to db: {}", err)
-        }
+/// Row of a single priced interval. PT1H rows have no resolution tag, like before other
+/// resolutions were stored. The price keeps the EUR/kWh of the existing rows, so the intervals'
+/// c/kWh is converted here.
+pub fn to_time_series_value(interval: &PricedInterval) -> TimeSeriesValue {
+    let resolution_duration =
+        if interval.resolution == ResolutionDuration::PT1H { None } else { Some(interval.resolution.to_string()) };
+    let measurementtype = interval.measurement_type.code().to_string();
+
+    TimeSeriesValue {
+        time: interval.time,
+        meteringpointcode_tag: interval.metering_point_code.clone(),
+        measurementtype_tag: measurementtype.clone(),
+        resolution_duration_tag: resolution_duration.clone(),
+        meteringpointcode: interval.metering_point_code.clone(),
+        measurementtype,
+        resolution_duration,
+        unit: interval.unit.clone(),
+        timestamp: interval.time.format("%Y-%m-%dT%H:%M:%S").to_string(),
+        value: interval.value,
+        price: interval.spot_price.map(cents_to_eur),
+
+        transfer_basic_fee: Some(interval.transfer_basic_fee),
+        transfer_fee: Some(interval.transfer_fee),
+        tax_fee: Some(interval.transfer_tax_fee),
+        basic_fee: Some(interval.energy_basic_fee),
+        energy_fee: interval.energy_fee,
+
+        contract_type: interval.contract_type.clone().into(),
+        spot_margin: Some(interval.energy_margin),
+        tax_percentage: Some(interval.tax_percentage),
+    }
+}
 
-        messages.push(format!("InfluxDB | Consumption {} - {:.2}", time, value));
+fn cents_to_eur(price: f32) -> f32 {
+    price / 100.0
+}
+
+/// Consumption and production keep their own measurements, everything else is
+/// stored in `measurements` and told apart by the `measurementtype_tag`.
+pub fn get_measurement_name(measurement_type: MeasurementType) -> &'static str {
+    match measurement_type {
+        MeasurementType::Consumption => "consumptions",
+        MeasurementType::Production => "productions",
+        MeasurementType::Other(_) => "measurements",
     }
+}
 
-    let all_messages = messages.join("\n");
-    info!("{}", all_messages);
+/// Upserts the prices into `dayAheadPrices` in batches, returns how many were written.
+pub async fn upsert_day_ahead_prices_into_influxdb(client: &Client, prices: &[DayAheadPrice]) -> Result<usize, anyhow::Error> {
+    let points = prices.iter().map(|price| to_price_data(price).into_query("dayAheadPrices")).collect();
+    write_points(client, points, WRITE_BATCH_SIZE).await.into_result()
+}
 
-    Ok(())
+/// Row of a day-ahead price, tagged like the rows of the other tools filling `dayAheadPrices`.
+pub fn to_price_data(price: &DayAheadPrice) -> PriceData {
+    PriceData {
+        time: price.time,
+        type_tag: DAY_AHEAD_PRICES.to_string(),
+        in_domain_tag: price.in_domain.clone(),
+        out_domain_tag: price.out_domain.clone(),
+        document_type: DAY_AHEAD_PRICES.to_string(),
+        in_domain: price.in_domain.clone(),
+        out_domain: price.out_domain.clone(),
+        currency: price.currency.clone(),
+        price_measure: price.price_measure.clone(),
+        curve_type: price.curve_type.clone(),
+        timestamp: price.time.format("%Y-%m-%dT%H:%M:%S").to_string(),
+        price: price.price,
+        dirty: None,
+    }
 }
 
-async fn get_day_ahead_price(client: &Client, time: &DateTime<Utc>) -> f32 {
+/// Day-ahead prices of `start..stop` in EUR/MWh.
+async fn get_day_ahead_prices(client: &Client, start: DateTime<Utc>, stop: DateTime<Utc>) -> Result<SpotPrices, influxdb::Error> {
     let read_query = ReadQuery::new(format!(
-        "SELECT * FROM dayAheadPrices WHERE type_tag='A44' AND time='{}' LIMIT 1",
-        time.to_rfc3339()
+        "SELECT * FROM dayAheadPrices WHERE type_tag='A44' AND time >= '{}' AND time < '{}'",
+        start.to_rfc3339(),
+        stop.to_rfc3339()
     ));
 
-    let read_result = client
-        .json_query(read_query)
-        .await
-        .and_then(|mut db_result| db_result.deserialize_next::<PriceData>());
+    let mut db_result = client.json_query(read_query).await?;
+    let result = db_result.deserialize_next::<PriceData>()?;
 
-    match read_result {
-        Ok(result) => {
-            if result.series.len() > 0 && result.series[0].values.len() > 0 {
-                let data = &result.series[0].values[0];
-                return data.price;
-            }
-        }
-        Err(err) => {
-            error!("Error reading dayAheadPrices from the db: {}", err);
-        }
-    }
+    Ok(result.series.into_iter().flat_map(|series| series.values).map(|data| (data.time, data.price)).collect())
+}
 
-    0.0
+fn connect_to_db(settings: &InfluxDbConfig) -> Client {
+    Client::new(&settings.url, &settings.database)
 }
 
-async fn connect_to_db() -> Client {
-    let database_url = dotenv::var("DATABASE_URL").unwrap_or("http://localhost:8086".to_string());
-    let database_name = dotenv::var("DATABASE_NAME").unwrap_or("entsoe".to_string());
+#[cfg(test)]
+mod tests {
+    use api::ConsumptionsResult;
+    use wiremock::matchers::{method, path};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
 
-    Client::new(&database_url, &database_name)
+    use super::*;
+    use crate::pricing::price_intervals;
+
+    fn intervals(spot_prices: &SpotPrices) -> Vec<PricedInterval> {
+        let config = crate::settings::config::load_settings("configs/test.yaml").unwrap();
+        let data: ConsumptionsResult = serde_json::from_str(&api::mock::fixture("consumption_2022-08-01_PT1H.json")).unwrap();
+        let contracts = config.get_contracts(MeasurementType::Consumption);
+        price_intervals(&data, MeasurementType::Consumption, contracts, spot_prices)
+    }
+
+    fn points() -> Vec<WriteQuery> {
+        intervals(&SpotPrices::default())
+            .iter()
+            .map(|interval| to_time_series_value(interval).into_query("consumptions"))
+            .collect()
+    }
+
+    #[test]
+    fn test_price_is_stored_in_eur_per_kwh() {
+        let times: Vec<DateTime<Utc>> = intervals(&SpotPrices::default()).iter().map(|interval| interval.time).collect();
+        let spot_prices: SpotPrices = times.into_iter().map(|time| (time, 123.4)).collect();
+
+        let interval = &intervals(&spot_prices)[0];
+        assert!((interval.spot_price.unwrap() - 12.34).abs() < 1e-4);
+        assert!((to_time_series_value(interval).price.unwrap() - 0.1234).abs() < 1e-6);
+    }
+
+    #[tokio::test]
+    async fn test_write_points_in_batches() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST")).and(path("/write")).respond_with(ResponseTemplate::new(204)).mount(&server).await;
+
+        let report = write_points(&Client::new(server.uri(), "test"), points(), 10).await;
+        assert_eq!(report, WriteReport { written: 24, failed: 0 });
+
+        let requests = server.received_requests().await.unwrap();
+        let lines: Vec<usize> = requests.iter().map(|request| String::from_utf8_lossy(&request.body).lines().count()).collect();
+        assert_eq!(lines, vec![10, 10, 4]);
+    }
+
+    #[tokio::test]
+    async fn test_failed_batches_are_counted() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/write"))
+            // The client only fails on the error body InfluxDB sends, not on the status
+            .respond_with(ResponseTemplate::new(500).set_body_string(r#"{"error":"timeout"}"#))
+            .up_to_n_times(1)
+            .with_priority(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("POST")).and(path("/write")).respond_with(ResponseTemplate::new(204)).mount(&server).await;
+
+        let report = write_points(&Client::new(server.uri(), "test"), points(), 10).await;
+        assert_eq!(report, WriteReport { written: 14, failed: 10 });
+        assert_eq!(report.into_result().unwrap_err().to_string(), "10 of 24 points failed to write");
+    }
 }
@@ -19,7 +19,8 @@ pub struct TimeSeriesValue {
     pub unit: String,
     pub timestamp: String,
     pub value: f32,
-    pub price: f32,
+    /// Day-ahead price in EUR/kWh without VAT
+    pub price: Option<f32>,
 
     pub transfer_basic_fee: Option<f32>,
     pub transfer_fee: Option<f32>,
@@ -0,0 +1,78 @@
+//! In-memory stand-in for the databases, used to test the fetch → price → store pipeline offline.
+
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+
+use api::MeasurementType;
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+
+use crate::entsoe::DayAheadPrice;
+use crate::pricing::{PricedInterval, SpotPrices};
+use crate::storage::sink::{IntervalQuery, MeterDataBatch, MeterDataSink};
+
+/// Intervals are keyed like the `energies` table, by time, metering point, measurement type and resolution.
+type IntervalKey = (DateTime<Utc>, String, i32, String);
+
+#[derive(Debug, Default)]
+pub struct MemoryStore {
+    day_ahead_prices: BTreeMap<DateTime<Utc>, f32>,
+    intervals: BTreeMap<IntervalKey, PricedInterval>,
+}
+
+impl MemoryStore {
+    /// Day-ahead price in EUR/MWh for the interval starting at `time`.
+    pub fn insert_day_ahead_price(&mut self, time: DateTime<Utc>, price: f32) {
+        self.day_ahead_prices.insert(time, price);
+    }
+
+    /// Stored day-ahead prices of `start..stop`.
+    pub fn day_ahead_prices(&self, start: DateTime<Utc>, stop: DateTime<Utc>) -> SpotPrices {
+        self.day_ahead_prices.range(start..stop).map(|(time, price)| (*time, *price)).collect()
+    }
+
+    /// Upserts the intervals, returns how many were written.
+    pub fn upsert_intervals(&mut self, intervals: &[PricedInterval]) -> usize {
+        for interval in intervals {
+            let key = (interval.time, interval.metering_point_code.clone(), interval.measurement_type.code(), interval.resolution.to_string());
+            self.intervals.insert(key, interval.clone());
+        }
+
+        intervals.len()
+    }
+
+    /// Stored intervals of the measurement type in time order.
+    pub fn values(&self, measurement_type: MeasurementType) -> Vec<&PricedInterval> {
+        self.intervals.values().filter(|interval| interval.measurement_type == measurement_type).collect()
+    }
+}
+
+#[async_trait]
+impl MeterDataSink for Mutex<MemoryStore> {
+    fn name(&self) -> &'static str {
+        "Memory"
+    }
+
+    async fn write(&self, batch: &MeterDataBatch<'_>) -> Result<usize, anyhow::Error> {
+        Ok(self.lock().unwrap().upsert_intervals(batch.intervals))
+    }
+
+    async fn read_intervals(&self, query: &IntervalQuery) -> Result<Option<Vec<PricedInterval>>, anyhow::Error> {
+        let store = self.lock().unwrap();
+        let mut intervals: Vec<PricedInterval> = store.intervals.values().filter(|interval| query.matches(interval)).cloned().collect();
+        intervals.sort_by_key(|interval| interval.time);
+        Ok(Some(intervals))
+    }
+
+    async fn day_ahead_prices(&self, start: DateTime<Utc>, stop: DateTime<Utc>) -> Result<Option<SpotPrices>, anyhow::Error> {
+        Ok(Some(self.lock().unwrap().day_ahead_prices(start, stop)))
+    }
+
+    async fn write_day_ahead_prices(&self, prices: &[DayAheadPrice]) -> Result<Option<usize>, anyhow::Error> {
+        let mut store = self.lock().unwrap();
+        for price in prices {
+            store.insert_day_ahead_price(price.time, price.price);
+        }
+        Ok(Some(prices.len()))
+    }
+}
@@ -1,2 +1,15 @@
+use crate::storage::sink::SinkFactory;
+
 pub mod influxdb;
-pub mod timescaledb;
\ No newline at end of file
+#[cfg(test)]
+pub mod memory;
+pub mod sink;
+pub mod sqlitedb;
+pub mod timescaledb;
+
+/// Backends that can be enabled in the settings, in the order they are written to.
+pub const SINKS: &[SinkFactory] = &[
+    influxdb::influx::InfluxDbSink::from_config,
+    timescaledb::timescale::TimescaleDbSink::from_config,
+    sqlitedb::sqlite::SqliteSink::from_config,
+];
@@ -0,0 +1,337 @@
+//! Storage backends behind a common interface, built from the settings.
+
+use std::sync::Arc;
+
+use anyhow::bail;
+use api::{MeasurementType, ResolutionDuration};
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+
+use crate::entsoe::DayAheadPrice;
+use crate::pricing::{PricedInterval, SpotPrices};
+use crate::settings::config_model::{MeteringPointConfig, SettingsConfig};
+
+/// Stored intervals of `start..stop` to read back.
+#[derive(Debug, Clone)]
+pub struct IntervalQuery {
+    pub start: DateTime<Utc>,
+    pub stop: DateTime<Utc>,
+    /// Metering point codes, every metering point when empty
+    pub points: Vec<String>,
+    /// Every resolution when `None`
+    pub resolution: Option<ResolutionDuration>,
+    /// Only the intervals whose spot price is still pending
+    pub pending_price_only: bool,
+}
+
+impl IntervalQuery {
+    pub fn matches(&self, interval: &PricedInterval) -> bool {
+        interval.time >= self.start
+            && interval.time < self.stop
+            && (self.points.is_empty() || self.points.contains(&interval.metering_point_code))
+            && self.resolution.is_none_or(|resolution| resolution == interval.resolution)
+            && (!self.pending_price_only || interval.is_price_pending())
+    }
+}
+
+/// Priced meter data of one metering point and resolution.
+pub struct MeterDataBatch<'a> {
+    pub metering_point: &'a MeteringPointConfig,
+    pub resolution: ResolutionDuration,
+    pub intervals: &'a [PricedInterval],
+}
+
+#[async_trait]
+pub trait MeterDataSink: Send + Sync {
+    /// Name used in logs and reports
+    fn name(&self) -> &'static str;
+
+    /// Upserts the batch, returns how many values were written.
+    async fn write(&self, batch: &MeterDataBatch<'_>) -> Result<usize, anyhow::Error>;
+
+    /// Updates whatever is derived from the stored values of the measurement type, e.g.
+    /// aggregates. Called after each write and after the REST API has fetched a range.
+    async fn refresh(&self, _measurement_type: MeasurementType) -> Result<(), anyhow::Error> {
+        Ok(())
+    }
+
+    /// Stored intervals in time order, `None` when the backend can't be read back.
+    async fn read_intervals(&self, _query: &IntervalQuery) -> Result<Option<Vec<PricedInterval>>, anyhow::Error> {
+        Ok(None)
+    }
+
+    /// Day-ahead prices of the time span, `None` when the backend doesn't store prices.
+    async fn day_ahead_prices(&self, _start: DateTime<Utc>, _stop: DateTime<Utc>) -> Result<Option<SpotPrices>, anyhow::Error> {
+        Ok(None)
+    }
+
+    /// Upserts day-ahead prices, returns how many were written or `None` when the backend
+    /// doesn't store prices.
+    async fn write_day_ahead_prices(&self, _prices: &[DayAheadPrice]) -> Result<Option<usize>, anyhow::Error> {
+        Ok(None)
+    }
+
+    /// Checks that the backend can be reached with the shared connections.
+    async fn health_check(&self) -> Result<(), anyhow::Error> {
+        Ok(())
+    }
+}
+
+/// Builds the sink of a backend, `None` when the backend is disabled. Fails when the backend is
+/// enabled but its clients can't be created, e.g. because of an unreadable CA certificate.
+pub type SinkFactory = fn(&SettingsConfig) -> Result<Option<Arc<dyn MeterDataSink>>, anyhow::Error>;
+
+/// Outcome of an operation on every sink, in registration order.
+#[derive(Debug, Default)]
+pub struct SinkReport<T> {
+    pub results: Vec<(&'static str, Result<T, anyhow::Error>)>,
+}
+
+impl<T> SinkReport<T> {
+    pub fn failures(&self) -> impl Iterator<Item = (&'static str, &anyhow::Error)> {
+        self.results.iter().filter_map(|(name, result)| result.as_ref().err().map(|err| (*name, err)))
+    }
+
+    pub fn is_ok(&self) -> bool {
+        self.failures().next().is_none()
+    }
+
+    /// Failures as `sink: error` lines.
+    pub fn failure_messages(&self) -> Vec<String> {
+        self.failures().map(|(name, err)| format!("{}: {:#}", name, err)).collect()
+    }
+}
+
+#[derive(Default, Clone)]
+pub struct SinkRegistry {
+    sinks: Vec<Arc<dyn MeterDataSink>>,
+}
+
+impl SinkRegistry {
+    /// Sinks of the enabled backends, or the first error creating one of them.
+    pub fn from_config(config: &SettingsConfig, factories: &[SinkFactory]) -> Result<Self, anyhow::Error> {
+        let mut sinks = Vec::new();
+        for factory in factories {
+            sinks.extend(factory(config)?);
+        }
+        Ok(Self { sinks })
+    }
+
+    #[cfg(test)]
+    pub fn register(&mut self, sink: Arc<dyn MeterDataSink>) {
+        self.sinks.push(sink);
+    }
+
+    pub fn names(&self) -> Vec<&'static str> {
+        self.sinks.iter().map(|sink| sink.name()).collect()
+    }
+
+    /// Writes the batch to every sink, a failing sink doesn't stop the others.
+    pub async fn write(&self, batch: &MeterDataBatch<'_>) -> SinkReport<usize> {
+        let writes = self.sinks.iter().map(|sink| async move { (sink.name(), sink.write(batch).await) });
+        let report = SinkReport { results: futures_util::future::join_all(writes).await };
+
+        for (name, result) in &report.results {
+            match result {
+                Ok(written) => info!("{} | Stored {} values of {}", name, written, batch.metering_point),
+                Err(err) => error!("{} | Storing {} failed: {:#}", name, batch.metering_point, err),
+            }
+        }
+        report
+    }
+
+    /// Day-ahead prices of the time span merged from every sink that stores prices, so that a
+    /// sink with only part of the span doesn't hide the prices of the others. Where sinks disagree
+    /// the one registered first wins. Without prices every spot priced interval is stored without
+    /// its spot price.
+    pub async fn day_ahead_prices(&self, start: DateTime<Utc>, stop: DateTime<Utc>) -> SpotPrices {
+        let reads = self.sinks.iter().map(|sink| async move { (sink.name(), sink.day_ahead_prices(start, stop).await) });
+        let mut merged = SpotPrices::default();
+
+        for (name, result) in futures_util::future::join_all(reads).await {
+            match result {
+                Ok(Some(prices)) => merged.fill_missing(prices),
+                Ok(None) => {}
+                Err(err) => error!("{} | Reading the day-ahead prices failed: {:#}", name, err),
+            }
+        }
+
+        if merged.is_empty() {
+            warn!("No day-ahead prices for {} - {}", start, stop);
+        }
+        merged
+    }
+
+    /// Writes the day-ahead prices to every sink that stores prices.
+    pub async fn write_day_ahead_prices(&self, prices: &[DayAheadPrice]) -> SinkReport<usize> {
+        let writes = self.sinks.iter().map(|sink| async move { (sink.name(), sink.write_day_ahead_prices(prices).await) });
+        let mut report = SinkReport::default();
+
+        for (name, result) in futures_util::future::join_all(writes).await {
+            match result {
+                Ok(None) => continue,
+                Ok(Some(written)) => {
+                    info!("{} | Stored {} day-ahead prices", name, written);
+                    report.results.push((name, Ok(written)));
+                }
+                Err(err) => {
+                    error!("{} | Storing the day-ahead prices failed: {:#}", name, err);
+                    report.results.push((name, Err(err)));
+                }
+            }
+        }
+        report
+    }
+
+    /// Stored intervals from the first sink that can be read back.
+    pub async fn read_intervals(&self, query: &IntervalQuery) -> Result<Vec<PricedInterval>, anyhow::Error> {
+        match self.try_read_intervals(query).await? {
+            Some(intervals) => Ok(intervals),
+            None => bail!("None of the enabled sinks ({}) can be read back", self.names().join(", ")),
+        }
+    }
+
+    /// Stored intervals from the first sink that can be read back, `None` when no sink can.
+    pub async fn try_read_intervals(&self, query: &IntervalQuery) -> Result<Option<Vec<PricedInterval>>, anyhow::Error> {
+        let mut errors = Vec::new();
+        for sink in &self.sinks {
+            match sink.read_intervals(query).await {
+                Ok(Some(intervals)) => return Ok(Some(intervals)),
+                Ok(None) => {}
+                Err(err) => {
+                    error!("{} | Reading the stored intervals failed: {:#}", sink.name(), err);
+                    errors.push(format!("{}: {:#}", sink.name(), err));
+                }
+            }
+        }
+
+        if errors.is_empty() {
+            return Ok(None);
+        }
+        bail!("Reading the stored intervals failed: {}", errors.join(", "))
+    }
+
+    /// Checks every sink, a failing sink is logged but stays registered.
+    pub async fn health_check(&self) -> SinkReport<()> {
+        let checks = self.sinks.iter().map(|sink| async move { (sink.name(), sink.health_check().await) });
+        let report = SinkReport { results: futures_util::future::join_all(checks).await };
+
+        for (name, result) in &report.results {
+            match result {
+                Ok(()) => info!("{} | Reachable", name),
+                Err(err) => error!("{} | Health check failed: {:#}", name, err),
+            }
+        }
+        report
+    }
+
+    pub async fn refresh(&self, measurement_type: MeasurementType) -> SinkReport<()> {
+        let refreshes = self.sinks.iter().map(|sink| async move { (sink.name(), sink.refresh(measurement_type).await) });
+        let report = SinkReport { results: futures_util::future::join_all(refreshes).await };
+
+        for (name, err) in report.failures() {
+            error!("{} | Refreshing the {} data failed: {:#}", name, measurement_type, err);
+        }
+        report
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use anyhow::anyhow;
+    use api::ConsumptionsResult;
+    use chrono::TimeZone;
+
+    use super::*;
+    use crate::pricing::price_intervals;
+    use crate::storage::memory::MemoryStore;
+
+    struct FailingSink;
+
+    #[async_trait]
+    impl MeterDataSink for FailingSink {
+        fn name(&self) -> &'static str {
+            "Failing"
+        }
+
+        async fn write(&self, _batch: &MeterDataBatch<'_>) -> Result<usize, anyhow::Error> {
+            Err(anyhow!("connection refused"))
+        }
+
+        async fn health_check(&self) -> Result<(), anyhow::Error> {
+            Err(anyhow!("connection refused"))
+        }
+    }
+
+    #[test]
+    fn test_disabled_backends_are_left_out() {
+        let mut config = crate::settings::config::load_settings("configs/test.yaml").unwrap();
+        config.influxdb.enabled = false;
+        config.timescaledb.enabled = false;
+        assert!(SinkRegistry::from_config(&config, crate::storage::SINKS).unwrap().names().is_empty());
+
+        config.timescaledb.enabled = true;
+        assert_eq!(SinkRegistry::from_config(&config, crate::storage::SINKS).unwrap().names(), vec!["TimescaleDB"]);
+
+        // An enabled backend that can't be set up fails the whole registry
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("ca.pem");
+        std::fs::write(&path, "not a certificate").unwrap();
+        config.timescaledb.ca_certificate = Some(path.to_string_lossy().to_string());
+        let err = SinkRegistry::from_config(&config, crate::storage::SINKS).err().unwrap();
+        assert!(format!("{:#}", err).contains("No CA certificates"), "{:#}", err);
+    }
+
+    #[tokio::test]
+    async fn test_failing_sink_does_not_stop_the_others() {
+        let config = crate::settings::config::load_settings("configs/test.yaml").unwrap();
+        let data: ConsumptionsResult = serde_json::from_str(&api::mock::fixture("consumption_2022-08-01_PT1H.json")).unwrap();
+        let metering_point = MeteringPointConfig::new("1337", MeasurementType::Consumption, vec![ResolutionDuration::PT1H]);
+
+        let store = Arc::new(Mutex::new(MemoryStore::default()));
+        let mut sinks = SinkRegistry::default();
+        sinks.register(Arc::new(FailingSink));
+        sinks.register(store.clone());
+
+        let contracts = config.get_metering_point_contracts(&metering_point);
+        let intervals = price_intervals(&data, MeasurementType::Consumption, contracts, &SpotPrices::default());
+        let batch = MeterDataBatch {
+            metering_point: &metering_point,
+            resolution: ResolutionDuration::PT1H,
+            intervals: &intervals,
+        };
+        let report = sinks.write(&batch).await;
+        assert!(!report.is_ok());
+        assert_eq!(report.failure_messages(), vec!["Failing: connection refused"]);
+        assert_eq!(report.results[1].1.as_ref().unwrap(), &24);
+        assert_eq!(store.lock().unwrap().values(MeasurementType::Consumption).len(), 24);
+        assert!(sinks.refresh(MeasurementType::Consumption).await.is_ok());
+        assert_eq!(sinks.health_check().await.failure_messages(), vec!["Failing: connection refused"]);
+    }
+
+    #[tokio::test]
+    async fn test_day_ahead_prices_are_merged_across_sinks() {
+        let start = Utc.ymd(2023, 1, 1).and_hms(0, 0, 0);
+        let hour = chrono::Duration::hours(1);
+
+        // The first sink only has the first hour, and a price the second one disagrees with
+        let partial = Arc::new(Mutex::new(MemoryStore::default()));
+        partial.lock().unwrap().insert_day_ahead_price(start, 10.0);
+        let complete = Arc::new(Mutex::new(MemoryStore::default()));
+        for (offset, price) in [(0, 11.0), (1, 20.0), (2, 30.0)] {
+            complete.lock().unwrap().insert_day_ahead_price(start + hour * offset, price);
+        }
+
+        let mut sinks = SinkRegistry::default();
+        sinks.register(Arc::new(FailingSink));
+        sinks.register(partial);
+        sinks.register(complete);
+
+        let prices = sinks.day_ahead_prices(start, start + hour * 3).await;
+        assert_eq!(prices.get(start), Some(10.0));
+        assert_eq!(prices.get(start + hour), Some(20.0));
+        assert_eq!(prices.get(start + hour * 2), Some(30.0));
+    }
+}
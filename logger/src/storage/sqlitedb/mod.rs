@@ -0,0 +1,2 @@
+pub mod rollups;
+pub mod sqlite;
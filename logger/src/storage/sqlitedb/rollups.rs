@@ -0,0 +1,264 @@
+//! Hourly, daily and monthly sums of the stored intervals, standing in for the TimescaleDB
+//! continuous aggregates. Costs are in cents, energies in the unit of the values.
+
+use std::collections::{BTreeMap, HashMap};
+
+use api::{LocalDateTime, ResolutionDuration};
+use chrono::{DateTime, Datelike, Duration as ChronoDuration, DurationRound, NaiveDate, Utc};
+use chrono_tz::Tz;
+
+/// Measure type code of produced energy.
+const PRODUCTION: i32 = 6;
+/// Contract type code of spot contracts.
+const SPOT: i16 = 3;
+
+/// Rollups are grouped by period start, metering point, measure type and contract type.
+type RollupKey = (DateTime<Utc>, String, i32, i16);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RollupPeriod {
+    Hour,
+    Day,
+    Month,
+}
+
+impl RollupPeriod {
+    pub const ALL: [RollupPeriod; 3] = [RollupPeriod::Hour, RollupPeriod::Day, RollupPeriod::Month];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            RollupPeriod::Hour => "hour",
+            RollupPeriod::Day => "day",
+            RollupPeriod::Month => "month",
+        }
+    }
+
+    /// Start of the period containing `time`. Days and months start at local midnight.
+    pub fn start(&self, time: DateTime<Utc>, tz: &Tz) -> DateTime<Utc> {
+        let local = time.with_timezone(tz).naive_local();
+        let date = match self {
+            RollupPeriod::Hour => return time.duration_trunc(ChronoDuration::hours(1)).unwrap_or(time),
+            RollupPeriod::Day => local.date(),
+            RollupPeriod::Month => NaiveDate::from_ymd(local.year(), local.month(), 1),
+        };
+        LocalDateTime::new(date.and_hms(0, 0, 0)).to_utc_in(tz)
+    }
+
+    /// Start of the period after the one containing `time`.
+    pub fn next(&self, time: DateTime<Utc>, tz: &Tz) -> DateTime<Utc> {
+        let start = self.start(time, tz);
+        let local = start.with_timezone(tz).naive_local();
+        let date = match self {
+            RollupPeriod::Hour => return start + ChronoDuration::hours(1),
+            RollupPeriod::Day => local.date().succ(),
+            RollupPeriod::Month if local.month() == 12 => NaiveDate::from_ymd(local.year() + 1, 1, 1),
+            RollupPeriod::Month => NaiveDate::from_ymd(local.year(), local.month() + 1, 1),
+        };
+        LocalDateTime::new(date.and_hms(0, 0, 0)).to_utc_in(tz)
+    }
+}
+
+/// Row of the `energies` table.
+#[derive(Debug, Clone, PartialEq)]
+pub struct EnergyRow {
+    pub time: DateTime<Utc>,
+    pub metering_point_code: String,
+    pub measure_type: i32,
+    pub contract_type: i16,
+    pub resolution_duration: String,
+    pub value: f32,
+    /// c/kWh with VAT
+    pub energy_fee: Option<f32>,
+    /// c/kWh without VAT
+    pub spot_price: Option<f32>,
+    pub energy_margin: f32,
+    pub transfer_fee: f32,
+    pub transfer_tax_fee: f32,
+    pub tax_percentage: f32,
+}
+
+impl EnergyRow {
+    /// Cost of the interval in cents. Production is worth the spot price with VAT less the
+    /// margin and transfer fees, and only spot contracts have a price.
+    fn price(&self) -> Option<f64> {
+        let price = if self.measure_type == PRODUCTION {
+            if self.contract_type != SPOT {
+                return None;
+            }
+            self.spot_price? * (self.tax_percentage / 100.0 + 1.0) - self.energy_margin - self.transfer_fee - self.transfer_tax_fee
+        } else {
+            self.energy_fee? + self.transfer_fee + self.transfer_tax_fee
+        };
+        Some(price as f64 * self.value as f64)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Rollup {
+    pub period: RollupPeriod,
+    pub time: DateTime<Utc>,
+    pub metering_point_code: String,
+    pub measure_type: i32,
+    pub contract_type: i16,
+    pub energy: f64,
+    pub energy_fee: f64,
+    pub transfer_fee: f64,
+    pub transfer_tax_fee: f64,
+    pub price: f64,
+    /// Average in c/kWh without VAT
+    pub spot_price: Option<f64>,
+    /// Intervals left out of `energy_fee` and `price` because their spot price isn't known
+    pub unpriced: u32,
+}
+
+/// Sums the rows by period, metering point, measure type and contract type. When a metering
+/// point has both hourly and quarter-hourly values for an hour only the finer ones are used, so
+/// refetching a day with another resolution doesn't count it twice.
+pub fn compute_rollups(rows: &[EnergyRow], period: RollupPeriod, tz: &Tz) -> Vec<Rollup> {
+    let hour_of = |row: &EnergyRow| RollupPeriod::Hour.start(row.time, tz);
+
+    let mut finest: HashMap<(&str, i32, DateTime<Utc>), u32> = HashMap::new();
+    let minutes: Vec<Option<u32>> = rows.iter().map(|row| interval_minutes(&row.resolution_duration)).collect();
+    for (row, minutes) in rows.iter().zip(&minutes) {
+        if let Some(minutes) = *minutes {
+            let finest = finest.entry((&row.metering_point_code, row.measure_type, hour_of(row))).or_insert(minutes);
+            *finest = (*finest).min(minutes);
+        }
+    }
+
+    let mut rollups: BTreeMap<RollupKey, (Rollup, f64, u32)> = BTreeMap::new();
+    for (row, minutes) in rows.iter().zip(minutes) {
+        let key = (row.metering_point_code.as_str(), row.measure_type, hour_of(row));
+        if minutes.is_none() || minutes != finest.get(&key).copied() {
+            continue;
+        }
+
+        let time = period.start(row.time, tz);
+        let (rollup, spot_sum, spot_count) = rollups
+            .entry((time, row.metering_point_code.clone(), row.measure_type, row.contract_type))
+            .or_insert_with(|| {
+                let rollup = Rollup {
+                    period,
+                    time,
+                    metering_point_code: row.metering_point_code.clone(),
+                    measure_type: row.measure_type,
+                    contract_type: row.contract_type,
+                    energy: 0.0,
+                    energy_fee: 0.0,
+                    transfer_fee: 0.0,
+                    transfer_tax_fee: 0.0,
+                    price: 0.0,
+                    spot_price: None,
+                    unpriced: 0,
+                };
+                (rollup, 0.0, 0)
+            });
+
+        let value = row.value as f64;
+        rollup.energy += value;
+        rollup.transfer_fee += row.transfer_fee as f64 * value;
+        rollup.transfer_tax_fee += row.transfer_tax_fee as f64 * value;
+        match row.energy_fee {
+            Some(energy_fee) => rollup.energy_fee += energy_fee as f64 * value,
+            None => rollup.unpriced += 1,
+        }
+        if let Some(price) = row.price() {
+            rollup.price += price;
+        }
+        if let Some(spot_price) = row.spot_price {
+            *spot_sum += spot_price as f64;
+            *spot_count += 1;
+        }
+    }
+
+    rollups
+        .into_iter()
+        .map(|(_, (mut rollup, spot_sum, spot_count))| {
+            if spot_count > 0 {
+                rollup.spot_price = Some(spot_sum / spot_count as f64);
+            }
+            rollup
+        })
+        .collect()
+}
+
+/// Length of an interval of the resolution, `None` for day and month resolutions that aren't rolled up.
+fn interval_minutes(resolution_duration: &str) -> Option<u32> {
+    let resolution: ResolutionDuration = resolution_duration.parse().ok()?;
+    if resolution.months() > 0 || resolution.days() > 0 || resolution.minutes() == 0 {
+        return None;
+    }
+    Some(resolution.minutes())
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+
+    use super::*;
+
+    fn row(time: DateTime<Utc>, resolution_duration: &str, value: f32, energy_fee: Option<f32>) -> EnergyRow {
+        EnergyRow {
+            time,
+            metering_point_code: "1337".to_string(),
+            measure_type: 1,
+            contract_type: SPOT,
+            resolution_duration: resolution_duration.to_string(),
+            value,
+            energy_fee,
+            spot_price: energy_fee.map(|_| 10.0),
+            energy_margin: 0.5,
+            transfer_fee: 3.0,
+            transfer_tax_fee: 2.0,
+            tax_percentage: 24.0,
+        }
+    }
+
+    #[test]
+    fn test_periods_follow_local_time() {
+        let tz = chrono_tz::Europe::Helsinki;
+        // 00:30 on the 1st of November in Helsinki
+        let time = Utc.ymd(2023, 10, 31).and_hms(22, 30, 0);
+
+        assert_eq!(RollupPeriod::Hour.start(time, &tz), Utc.ymd(2023, 10, 31).and_hms(22, 0, 0));
+        assert_eq!(RollupPeriod::Day.start(time, &tz), Utc.ymd(2023, 10, 31).and_hms(22, 0, 0));
+        assert_eq!(RollupPeriod::Month.start(time, &tz), Utc.ymd(2023, 10, 31).and_hms(22, 0, 0));
+        // October is an hour longer because of the end of daylight saving time
+        assert_eq!(RollupPeriod::Month.start(time - ChronoDuration::hours(1), &tz), Utc.ymd(2023, 9, 30).and_hms(21, 0, 0));
+        assert_eq!(RollupPeriod::Month.next(time, &tz), Utc.ymd(2023, 11, 30).and_hms(22, 0, 0));
+        assert_eq!(RollupPeriod::Day.next(time, &tz), Utc.ymd(2023, 11, 1).and_hms(22, 0, 0));
+    }
+
+    #[test]
+    fn test_compute_rollups() {
+        let tz = chrono_tz::Europe::Helsinki;
+        let hour = Utc.ymd(2023, 10, 31).and_hms(22, 0, 0);
+        let rows = vec![
+            // The hourly value of the first hour is replaced by its quarter hours
+            row(hour, "PT1H", 4.0, Some(12.0)),
+            row(hour, "PT15M", 1.0, Some(12.0)),
+            row(hour + ChronoDuration::minutes(15), "PT15M", 1.0, Some(12.0)),
+            row(hour + ChronoDuration::minutes(30), "PT15M", 1.0, Some(12.0)),
+            row(hour + ChronoDuration::minutes(45), "PT15M", 2.0, Some(12.0)),
+            row(hour + ChronoDuration::hours(1), "PT1H", 2.0, None),
+            row(hour + ChronoDuration::hours(1), "P1D", 100.0, None),
+        ];
+
+        let hourly = compute_rollups(&rows, RollupPeriod::Hour, &tz);
+        assert_eq!(hourly.len(), 2);
+        assert_eq!(hourly[0].energy, 5.0);
+        assert_eq!(hourly[0].energy_fee, 60.0);
+        assert_eq!(hourly[0].price, 85.0);
+        assert_eq!(hourly[0].spot_price, Some(10.0));
+        assert_eq!(hourly[1].unpriced, 1);
+        assert_eq!(hourly[1].spot_price, None);
+
+        let daily = compute_rollups(&rows, RollupPeriod::Day, &tz);
+        assert_eq!(daily.len(), 1);
+        assert_eq!(daily[0].time, hour);
+        assert_eq!(daily[0].energy, 7.0);
+        assert_eq!(daily[0].transfer_fee, 21.0);
+        assert_eq!(daily[0].price, 85.0);
+        assert_eq!(daily[0].unpriced, 1);
+    }
+}
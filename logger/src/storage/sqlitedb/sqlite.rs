@@ -0,0 +1,415 @@
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::sync::{Arc, Mutex};
+
+use api::MeasurementType;
+use async_trait::async_trait;
+use chrono::{DateTime, SecondsFormat, Utc};
+use chrono_tz::Tz;
+use rusqlite::{params, Connection, Transaction};
+
+use crate::entsoe::DayAheadPrice;
+use crate::pricing::{PricedInterval, SpotPrices};
+use crate::settings::config_model::{ContractType, SettingsConfig, SqliteConfig};
+use crate::settings::time::get_timezone;
+use crate::storage::sink::{IntervalQuery, MeterDataBatch, MeterDataSink};
+use crate::storage::sqlitedb::rollups::{compute_rollups, EnergyRow, Rollup, RollupPeriod};
+
+/// The `energies` table of `scripts/create_tables.sql` with times as RFC 3339 UTC text, plus the
+/// day-ahead prices and the rollups that replace the continuous aggregates.
+const SCHEMA: &str = r#"
+CREATE TABLE IF NOT EXISTS energies (
+    time TEXT NOT NULL,
+    metering_point_code TEXT NOT NULL,
+    measure_type INTEGER NOT NULL,
+    contract_type INTEGER NOT NULL,
+    source TEXT NULL DEFAULT NULL,
+    measure_unit TEXT NOT NULL,
+    value REAL NULL DEFAULT NULL,
+    energy_basic_fee REAL NULL DEFAULT NULL,
+    energy_fee REAL NULL DEFAULT NULL,
+    energy_margin REAL NULL DEFAULT NULL,
+    transfer_basic_fee REAL NULL DEFAULT NULL,
+    transfer_fee REAL NULL DEFAULT NULL,
+    transfer_tax_fee REAL NULL DEFAULT NULL,
+    tax_percentage REAL NOT NULL DEFAULT 24,
+    night INTEGER NOT NULL DEFAULT 0,
+    resolution_duration TEXT NOT NULL,
+    spot_price REAL NULL DEFAULT NULL,
+    UNIQUE (time, metering_point_code, measure_type, resolution_duration)
+);
+
+CREATE TABLE IF NOT EXISTS day_ahead_prices (
+    time TEXT NOT NULL PRIMARY KEY,
+    price REAL NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS energy_rollups (
+    period TEXT NOT NULL,
+    time TEXT NOT NULL,
+    metering_point_code TEXT NOT NULL,
+    measure_type INTEGER NOT NULL,
+    contract_type INTEGER NOT NULL,
+    energy REAL NOT NULL,
+    energy_fee REAL NOT NULL,
+    transfer_fee REAL NOT NULL,
+    transfer_tax_fee REAL NOT NULL,
+    price REAL NOT NULL,
+    spot_price REAL NULL,
+    unpriced INTEGER NOT NULL,
+    PRIMARY KEY (period, time, metering_point_code, measure_type, contract_type)
+);
+"#;
+
+/// First and last interval start.
+type TimeSpan = (DateTime<Utc>, DateTime<Utc>);
+
+pub struct SqliteSink {
+    settings: SqliteConfig,
+    /// Written time span of each measure type, rolled up on the next refresh
+    pending_rollups: Mutex<HashMap<i32, TimeSpan>>,
+}
+
+impl SqliteSink {
+    pub fn from_config(config: &SettingsConfig) -> Result<Option<Arc<dyn MeterDataSink>>, anyhow::Error> {
+        if !config.sqlite.enabled {
+            return Ok(None);
+        }
+        Ok(Some(Arc::new(Self::new(config.sqlite.clone()))))
+    }
+
+    fn new(settings: SqliteConfig) -> Self {
+        Self { settings, pending_rollups: Mutex::new(HashMap::new()) }
+    }
+}
+
+#[async_trait]
+impl MeterDataSink for SqliteSink {
+    fn name(&self) -> &'static str {
+        "SQLite"
+    }
+
+    async fn write(&self, batch: &MeterDataBatch<'_>) -> Result<usize, anyhow::Error> {
+        let path = self.settings.path.clone();
+        let intervals = batch.intervals.to_vec();
+        let written = tokio::task::spawn_blocking(move || upsert_intervals_into_sqlite(&mut connect_to_db(&path)?, &intervals)).await??;
+
+        let mut pending = self.pending_rollups.lock().unwrap();
+        for interval in batch.intervals {
+            let span = pending.entry(interval.measurement_type.code()).or_insert((interval.time, interval.time));
+            span.0 = span.0.min(interval.time);
+            span.1 = span.1.max(interval.time);
+        }
+        Ok(written)
+    }
+
+    async fn refresh(&self, measurement_type: MeasurementType) -> Result<(), anyhow::Error> {
+        let span = match self.pending_rollups.lock().unwrap().remove(&measurement_type.code()) {
+            Some(span) => span,
+            None => return Ok(()),
+        };
+
+        let path = self.settings.path.clone();
+        let tz = get_timezone();
+        let result = tokio::task::spawn_blocking(move || {
+            refresh_rollups(&mut connect_to_db(&path)?, measurement_type.code(), span.0, span.1, &tz)
+        })
+        .await?;
+
+        // Try again on the next refresh
+        if result.is_err() {
+            let mut pending = self.pending_rollups.lock().unwrap();
+            let retry = pending.entry(measurement_type.code()).or_insert(span);
+            *retry = (retry.0.min(span.0), retry.1.max(span.1));
+        }
+        Ok(result?)
+    }
+
+    async fn read_intervals(&self, query: &IntervalQuery) -> Result<Option<Vec<PricedInterval>>, anyhow::Error> {
+        let path = self.settings.path.clone();
+        let query = query.clone();
+        let intervals = tokio::task::spawn_blocking(move || get_intervals(&connect_to_db(&path)?, &query)).await??;
+        Ok(Some(intervals))
+    }
+
+    async fn day_ahead_prices(&self, start: DateTime<Utc>, stop: DateTime<Utc>) -> Result<Option<SpotPrices>, anyhow::Error> {
+        let path = self.settings.path.clone();
+        let prices = tokio::task::spawn_blocking(move || get_day_ahead_prices(&connect_to_db(&path)?, start, stop)).await??;
+        Ok(Some(prices))
+    }
+
+    async fn write_day_ahead_prices(&self, prices: &[DayAheadPrice]) -> Result<Option<usize>, anyhow::Error> {
+        let path = self.settings.path.clone();
+        let prices = prices.to_vec();
+        let written = tokio::task::spawn_blocking(move || upsert_day_ahead_prices(&mut connect_to_db(&path)?, &prices)).await??;
+        Ok(Some(written))
+    }
+
+    async fn health_check(&self) -> Result<(), anyhow::Error> {
+        let path = self.settings.path.clone();
+        tokio::task::spawn_blocking(move || connect_to_db(&path).map(drop)).await??;
+        Ok(())
+    }
+}
+
+/// Upserts the intervals in one transaction, returns how many were written.
+pub fn upsert_intervals_into_sqlite(connection: &mut Connection, intervals: &[PricedInterval]) -> Result<usize, rusqlite::Error> {
+    let trans = connection.transaction()?;
+    {
+        let mut statement = trans.prepare(
+            "INSERT INTO energies (time, metering_point_code, measure_type, contract_type, source, measure_unit, value, energy_basic_fee, energy_fee, energy_margin, transfer_basic_fee, transfer_fee, transfer_tax_fee, tax_percentage, night, spot_price, resolution_duration)
+                VALUES (?1, ?2, ?3, ?4, 'wattivahti', ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15, ?16)
+                ON CONFLICT (time, metering_point_code, measure_type, resolution_duration) DO UPDATE
+                    SET contract_type = ?4, source = 'wattivahti', measure_unit = ?5, value = ?6, energy_basic_fee = ?7, energy_fee = ?8, energy_margin = ?9, transfer_basic_fee = ?10, transfer_fee = ?11, transfer_tax_fee = ?12, tax_percentage = ?13, night = ?14, spot_price = ?15",
+        )?;
+
+        for interval in intervals {
+            let contract_type: i16 = interval.contract_type.clone().into();
+            statement.execute(params![
+                to_sql_time(&interval.time),
+                interval.metering_point_code,
+                interval.measurement_type.code(),
+                contract_type,
+                interval.unit,
+                interval.value,
+                interval.energy_basic_fee,
+                interval.energy_fee,
+                interval.energy_margin,
+                interval.transfer_basic_fee,
+                interval.transfer_fee,
+                interval.transfer_tax_fee,
+                interval.tax_percentage,
+                interval.night,
+                interval.spot_price,
+                interval.resolution.to_string(),
+            ])?;
+        }
+    }
+    trans.commit()?;
+
+    info!("SQLite | Stored {} intervals", intervals.len());
+    Ok(intervals.len())
+}
+
+/// Recomputes the rollups of the months touching `start..=stop`.
+pub fn refresh_rollups(connection: &mut Connection, measure_type: i32, start: DateTime<Utc>, stop: DateTime<Utc>, tz: &Tz) -> Result<(), rusqlite::Error> {
+    let start = RollupPeriod::Month.start(start, tz);
+    let stop = RollupPeriod::Month.next(stop, tz);
+
+    let trans = connection.transaction()?;
+    let rows = get_energy_rows(&trans, measure_type, start, stop)?;
+    trans.execute(
+        "DELETE FROM energy_rollups WHERE measure_type = ?1 AND time >= ?2 AND time < ?3",
+        params![measure_type, to_sql_time(&start), to_sql_time(&stop)],
+    )?;
+
+    let mut written = 0;
+    for period in RollupPeriod::ALL.iter() {
+        for rollup in compute_rollups(&rows, *period, tz) {
+            insert_rollup(&trans, &rollup)?;
+            written += 1;
+        }
+    }
+    trans.commit()?;
+
+    info!("SQLite | Rolled up {} rows of measure type {} into {} rollups", rows.len(), measure_type, written);
+    Ok(())
+}
+
+fn get_energy_rows(trans: &Transaction, measure_type: i32, start: DateTime<Utc>, stop: DateTime<Utc>) -> Result<Vec<EnergyRow>, rusqlite::Error> {
+    let mut statement = trans.prepare(
+        "SELECT time, metering_point_code, measure_type, contract_type, resolution_duration, value, energy_fee, spot_price, energy_margin, transfer_fee, transfer_tax_fee, tax_percentage
+            FROM energies WHERE measure_type = ?1 AND time >= ?2 AND time < ?3 AND value IS NOT NULL ORDER BY time",
+    )?;
+    let rows = statement.query_map(params![measure_type, to_sql_time(&start), to_sql_time(&stop)], |row| {
+        Ok(EnergyRow {
+            time: from_sql_time(row.get(0)?)?,
+            metering_point_code: row.get(1)?,
+            measure_type: row.get(2)?,
+            contract_type: row.get(3)?,
+            resolution_duration: row.get(4)?,
+            value: row.get(5)?,
+            energy_fee: row.get(6)?,
+            spot_price: row.get(7)?,
+            energy_margin: row.get::<_, Option<f32>>(8)?.unwrap_or(0.0),
+            transfer_fee: row.get::<_, Option<f32>>(9)?.unwrap_or(0.0),
+            transfer_tax_fee: row.get::<_, Option<f32>>(10)?.unwrap_or(0.0),
+            tax_percentage: row.get(11)?,
+        })
+    })?;
+    rows.collect()
+}
+
+fn insert_rollup(trans: &Transaction, rollup: &Rollup) -> Result<(), rusqlite::Error> {
+    trans.execute(
+        "INSERT INTO energy_rollups (period, time, metering_point_code, measure_type, contract_type, energy, energy_fee, transfer_fee, transfer_tax_fee, price, spot_price, unpriced)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12)",
+        params![
+            rollup.period.name(),
+            to_sql_time(&rollup.time),
+            rollup.metering_point_code,
+            rollup.measure_type,
+            rollup.contract_type,
+            rollup.energy,
+            rollup.energy_fee,
+            rollup.transfer_fee,
+            rollup.transfer_tax_fee,
+            rollup.price,
+            rollup.spot_price,
+            rollup.unpriced,
+        ],
+    )?;
+    Ok(())
+}
+
+/// Stored intervals matching the query in time order.
+fn get_intervals(connection: &Connection, query: &IntervalQuery) -> Result<Vec<PricedInterval>, rusqlite::Error> {
+    let mut statement = connection.prepare(
+        "SELECT time, metering_point_code, measure_type, resolution_duration, measure_unit, value, contract_type, night, spot_price, energy_basic_fee, energy_fee, energy_margin, transfer_basic_fee, transfer_fee, transfer_tax_fee, tax_percentage
+            FROM energies WHERE time >= ?1 AND time < ?2 AND value IS NOT NULL ORDER BY time, metering_point_code, measure_type",
+    )?;
+    let intervals = statement.query_map(params![to_sql_time(&query.start), to_sql_time(&query.stop)], |row| {
+        let resolution: String = row.get(3)?;
+        Ok(PricedInterval {
+            time: from_sql_time(row.get(0)?)?,
+            metering_point_code: row.get(1)?,
+            measurement_type: MeasurementType::from(row.get::<_, i32>(2)?),
+            resolution: resolution
+                .parse()
+                .map_err(|err| rusqlite::Error::FromSqlConversionFailure(3, rusqlite::types::Type::Text, Box::new(err)))?,
+            unit: row.get(4)?,
+            value: row.get(5)?,
+            contract_type: ContractType::try_from(row.get::<_, i16>(6)?)
+                .map_err(|err| rusqlite::Error::FromSqlConversionFailure(6, rusqlite::types::Type::Integer, Box::new(err)))?,
+            night: row.get(7)?,
+            spot_price: row.get(8)?,
+            energy_basic_fee: row.get::<_, Option<f32>>(9)?.unwrap_or_default(),
+            energy_fee: row.get(10)?,
+            energy_margin: row.get::<_, Option<f32>>(11)?.unwrap_or_default(),
+            transfer_basic_fee: row.get::<_, Option<f32>>(12)?.unwrap_or_default(),
+            transfer_fee: row.get::<_, Option<f32>>(13)?.unwrap_or_default(),
+            transfer_tax_fee: row.get::<_, Option<f32>>(14)?.unwrap_or_default(),
+            tax_percentage: row.get(15)?,
+        })
+    })?;
+
+    let mut matching = Vec::new();
+    for interval in intervals {
+        let interval = interval?;
+        if query.matches(&interval) {
+            matching.push(interval);
+        }
+    }
+    Ok(matching)
+}
+
+/// Day-ahead prices of `start..stop` in EUR/MWh.
+fn get_day_ahead_prices(connection: &Connection, start: DateTime<Utc>, stop: DateTime<Utc>) -> Result<SpotPrices, rusqlite::Error> {
+    let mut statement = connection.prepare("SELECT time, price FROM day_ahead_prices WHERE time >= ?1 AND time < ?2")?;
+    let prices = statement.query_map(params![to_sql_time(&start), to_sql_time(&stop)], |row| Ok((from_sql_time(row.get(0)?)?, row.get(1)?)))?;
+    prices.collect()
+}
+
+/// Upserts the prices in EUR/MWh in one transaction, returns how many were written.
+fn upsert_day_ahead_prices(connection: &mut Connection, prices: &[DayAheadPrice]) -> Result<usize, rusqlite::Error> {
+    let trans = connection.transaction()?;
+    {
+        let mut statement =
+            trans.prepare("INSERT INTO day_ahead_prices (time, price) VALUES (?1, ?2) ON CONFLICT (time) DO UPDATE SET price = ?2")?;
+        for price in prices {
+            statement.execute(params![to_sql_time(&price.time), price.price])?;
+        }
+    }
+    trans.commit()?;
+    Ok(prices.len())
+}
+
+/// Opens the database, creating the tables when missing.
+fn connect_to_db(path: &str) -> Result<Connection, rusqlite::Error> {
+    let connection = Connection::open(path)?;
+    connection.execute_batch(SCHEMA)?;
+    Ok(connection)
+}
+
+/// Times are stored as RFC 3339 UTC text so that they sort and compare in time order.
+fn to_sql_time(time: &DateTime<Utc>) -> String {
+    time.to_rfc3339_opts(SecondsFormat::Secs, true)
+}
+
+fn from_sql_time(time: String) -> Result<DateTime<Utc>, rusqlite::Error> {
+    DateTime::parse_from_rfc3339(&time)
+        .map(|time| time.with_timezone(&Utc))
+        .map_err(|err| rusqlite::Error::FromSqlConversionFailure(0, rusqlite::types::Type::Text, Box::new(err)))
+}
+
+#[cfg(test)]
+mod tests {
+    use api::{ConsumptionsResult, ResolutionDuration};
+    use chrono::{Duration as ChronoDuration, TimeZone};
+
+    use super::*;
+    use crate::pricing::{price_intervals, time_span};
+    use crate::settings::config_model::MeteringPointConfig;
+
+    #[tokio::test]
+    async fn test_sqlite_sink() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("energies.sqlite3");
+        let sink = SqliteSink::new(SqliteConfig { enabled: true, path: path.to_string_lossy().to_string() });
+
+        let config = crate::settings::config::load_settings("configs/test.yaml").unwrap();
+        let data: ConsumptionsResult = serde_json::from_str(&api::mock::fixture("consumption_2022-08-01_PT1H.json")).unwrap();
+        let metering_point = MeteringPointConfig::new("1337", MeasurementType::Consumption, vec![ResolutionDuration::PT1H]);
+        let (start, stop) = time_span(&data);
+
+        // Prices of the first half of the day
+        {
+            let connection = connect_to_db(&path.to_string_lossy()).unwrap();
+            for hour in 0..12 {
+                let time = start + ChronoDuration::hours(hour);
+                connection.execute("INSERT INTO day_ahead_prices (time, price) VALUES (?1, ?2)", params![to_sql_time(&time), 100.0]).unwrap();
+            }
+        }
+        let prices = sink.day_ahead_prices(start, stop).await.unwrap().unwrap();
+        assert_eq!(prices.get(start), Some(100.0));
+        assert_eq!(prices.get(start + ChronoDuration::hours(12)), None);
+
+        let intervals = price_intervals(&data, MeasurementType::Consumption, config.get_metering_point_contracts(&metering_point), &prices);
+        let batch = MeterDataBatch { metering_point: &metering_point, resolution: ResolutionDuration::PT1H, intervals: &intervals };
+        // Upserting again replaces the rows
+        assert_eq!(sink.write(&batch).await.unwrap(), 24);
+        assert_eq!(sink.write(&batch).await.unwrap(), 24);
+        sink.refresh(MeasurementType::Consumption).await.unwrap();
+
+        let connection = connect_to_db(&path.to_string_lossy()).unwrap();
+        let count: i64 = connection.query_row("SELECT COUNT(*) FROM energies", [], |row| row.get(0)).unwrap();
+        assert_eq!(count, 24);
+
+        let (energy, unpriced, spot_price): (f64, i64, f64) = connection
+            .query_row("SELECT energy, unpriced, spot_price FROM energy_rollups WHERE period = 'day' AND time = ?1", params![to_sql_time(&start)], |row| {
+                Ok((row.get(0)?, row.get(1)?, row.get(2)?))
+            })
+            .unwrap();
+        let total: f32 = intervals.iter().map(|interval| interval.value).sum();
+        assert!((energy - total as f64).abs() < 1e-4);
+        assert_eq!(unpriced, 12);
+        assert!((spot_price - 10.0).abs() < 1e-6);
+
+        let hours: i64 = connection.query_row("SELECT COUNT(*) FROM energy_rollups WHERE period = 'hour'", [], |row| row.get(0)).unwrap();
+        assert_eq!(hours, 24);
+        let months: Vec<String> = connection
+            .prepare("SELECT time FROM energy_rollups WHERE period = 'month'")
+            .unwrap()
+            .query_map([], |row| row.get(0))
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(months, vec![to_sql_time(&Utc.ymd(2022, 7, 31).and_hms(21, 0, 0))]);
+
+        // A row with an unknown contract type fails the read, not the process
+        connection.execute("UPDATE energies SET contract_type = 9 WHERE time = ?1", params![to_sql_time(&start)]).unwrap();
+        let query = IntervalQuery { start, stop, points: vec![], resolution: None, pending_price_only: false };
+        let err = sink.read_intervals(&query).await.unwrap_err();
+        assert!(format!("{:#}", err).contains("Invalid contract type 9"), "{:#}", err);
+    }
+}
@@ -1,202 +1,296 @@
-use api::{ConsumptionsResult, ResolutionDuration};
-use tokio_postgres::{Error, NoTls};
-
-use crate::settings::config_model::{ContractType, SettingsConfig};
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::fs::File;
+use std::io::BufReader;
+use std::str::FromStr;
+use std::sync::Arc;
+
+use anyhow::{bail, Context};
+use api::MeasurementType;
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use deadpool_postgres::{Manager, ManagerConfig, Pool, RecyclingMethod};
+use futures_util::pin_mut;
+use rustls::{ClientConfig, OwnedTrustAnchor, RootCertStore};
+use tokio_postgres::binary_copy::BinaryCopyInWriter;
+use tokio_postgres::config::SslMode;
+use tokio_postgres::types::Type;
+use tokio_postgres::NoTls;
+use tokio_postgres_rustls::MakeRustlsConnect;
+
+use crate::entsoe::DayAheadPrice;
+use crate::pricing::{PricedInterval, SpotPrices};
+use crate::settings::config_model::{ContractType, SettingsConfig, TimescaleDbConfig};
+use crate::storage::sink::{IntervalQuery, MeterDataBatch, MeterDataSink};
+
+pub struct TimescaleDbSink {
+    pool: Pool,
+}
 
-pub fn is_enabled() -> bool {
-    dotenv::var("TIMESCALEDB_ENABLED")
-        .map(|var| var.parse::<bool>())
-        .unwrap_or(Ok(false))
-        .unwrap()
+impl TimescaleDbSink {
+    pub fn from_config(config: &SettingsConfig) -> Result<Option<Arc<dyn MeterDataSink>>, anyhow::Error> {
+        if !config.timescaledb.enabled {
+            return Ok(None);
+        }
+        let pool = create_pool(&config.timescaledb).context("Failed to create the TimescaleDB connection pool")?;
+        Ok(Some(Arc::new(Self { pool })))
+    }
 }
 
-pub async fn upsert_productions_into_timescaledb(
-    data: &ConsumptionsResult,
-    config: &SettingsConfig,
-) -> Result<(), Error> {
-    if !is_enabled() {
-        return Ok(());
+#[async_trait]
+impl MeterDataSink for TimescaleDbSink {
+    fn name(&self) -> &'static str {
+        "TimescaleDB"
     }
 
-    let mut messages = Vec::new();
+    async fn write(&self, batch: &MeterDataBatch<'_>) -> Result<usize, anyhow::Error> {
+        upsert_intervals_into_timescaledb(&self.pool, batch.intervals).await
+    }
 
-    let mut client = connect_to_db().await?;
-    let trans = client.transaction().await?;
-    let resolution = &data
-        .getconsumptionsresult
-        .consumptiondata
-        .timeseries
-        .resolution;
-    let resolution_duration = ResolutionDuration::from_str(resolution);
-
-    for (pos, tsv) in data
-        .getconsumptionsresult
-        .consumptiondata
-        .timeseries
-        .values
-        .tsv
-        .iter()
-        .enumerate()
-    {
-        let time = &tsv.get_timestamp_utc_calculated(pos, &resolution_duration);
-        if time.is_none() {
-            warn!("TimescaleDB | Skipping production logging because time couldn't be parsed");
-            continue;
-        }
+    async fn refresh(&self, measurement_type: MeasurementType) -> Result<(), anyhow::Error> {
+        refresh_views(&self.pool, measurement_type).await
+    }
 
-        if tsv.quantity.is_none() {
-            // warn!("TimescaleDB | Skipping production logging because quantity was null");
-            continue;
-        }
+    async fn read_intervals(&self, query: &IntervalQuery) -> Result<Option<Vec<PricedInterval>>, anyhow::Error> {
+        Ok(Some(get_intervals(&self.pool, query).await?))
+    }
 
-        let time = time.unwrap();
-        let contract = config.production.get_contract(time);
-        if contract.is_none() {
-            warn!("TimescaleDB | Skipping production logging because contract couldn't be found");
-            continue;
-        }
+    async fn day_ahead_prices(&self, start: DateTime<Utc>, stop: DateTime<Utc>) -> Result<Option<SpotPrices>, anyhow::Error> {
+        Ok(Some(get_day_ahead_prices(&self.pool, start, stop).await?))
+    }
+
+    async fn write_day_ahead_prices(&self, prices: &[DayAheadPrice]) -> Result<Option<usize>, anyhow::Error> {
+        Ok(Some(upsert_day_ahead_prices(&self.pool, prices).await?))
+    }
+
+    async fn health_check(&self) -> Result<(), anyhow::Error> {
+        self.pool.get().await?.simple_query("SELECT 1").await?;
+        Ok(())
+    }
+}
+
+/// Columns of `energies` loaded through the staging table, in COPY order.
+const ENERGIES_COLUMNS: &str = "time, metering_point_code, measure_type, contract_type, source, measure_unit, value, energy_basic_fee, energy_fee, energy_margin, transfer_basic_fee, transfer_fee, transfer_tax_fee, tax_percentage, night, spot_price, resolution_duration";
+
+/// Upserts the intervals in one transaction, returns how many were written.
+///
+/// The intervals are COPYed into a temporary staging table and upserted into `energies` with one
+/// statement. Rows staged without a spot price get it from `day_ahead_prices` unless they're on a
+/// spot contract, those stay pending until repricing sets the energy fee too. A refetch without
+/// prices keeps the spot price, and the energy fee of spot contracts, already stored.
+pub async fn upsert_intervals_into_timescaledb(pool: &Pool, intervals: &[PricedInterval]) -> Result<usize, anyhow::Error> {
+    let intervals = latest_by_key(intervals);
+    if intervals.is_empty() {
+        return Ok(0);
+    }
 
-        let contract = contract.unwrap();
-        let contract_type: i16 = contract.contract_type.clone().into();
-        let transfer_fee = contract.get_transfer_fee(time);
-        let meteringpointcode = &data.getconsumptionsresult.consumptiondata.meteringpointcode;
-        let measurementtype: i32 = 6;
-        let unit = &data.getconsumptionsresult.consumptiondata.sum.unit;
-        let value = tsv.quantity.unwrap();
-
-        // time, metering_point_code, measure_type, contract_type, source, measure_unit, value, energy_basic_fee, energy_fee, energy_margin, transfer_basic_fee, transfer_fee, tax_fee, tax_percentage
-
-        let tax_percentage = contract.get_tax_percentage();
-
-        let transfer_basic_fee = contract.get_transfer_basic_fee();
-        let transfer_tax_fee = contract.get_transfer_tax_fee();
-        let energy_basic_fee = contract.get_energy_basic_fee();
-        let energy_margin = contract.get_energy_margin();
-
-        let _ = trans
-            .execute("INSERT INTO energies (time, metering_point_code, measure_type, contract_type, source, measure_unit, value, energy_basic_fee, energy_margin, transfer_basic_fee, transfer_fee, transfer_tax_fee, tax_percentage, spot_price, resolution_duration)
-                                VALUES ($1, $2, $3, $4, 'wattivahti', $5, $6, $7, $8, $9, $10, $11, $12, COALESCE((SELECT (price / 10.) FROM day_ahead_prices WHERE time = $1), (SELECT (price / 10.) FROM day_ahead_prices WHERE time = date_trunc('hour', $1))), $13)
-                                ON CONFLICT (time, metering_point_code, measure_type, resolution_duration) DO UPDATE
-                                    SET contract_type = $4, source = 'wattivahti', measure_unit = $5, value = $6, energy_basic_fee = $7, energy_margin = $8, transfer_basic_fee = $9, transfer_fee = $10, transfer_tax_fee = $11, tax_percentage = $12, spot_price = EXCLUDED.spot_price, resolution_duration = $13",
-            &[&time, &meteringpointcode.to_string(), &measurementtype, &contract_type, &unit.to_string(), &value, &energy_basic_fee, &energy_margin, &transfer_basic_fee, &transfer_fee, &transfer_tax_fee, &tax_percentage, &resolution_duration.to_str()])
+    let mut client = pool.get().await?;
+    let trans = client.transaction().await?;
+
+    trans
+        .batch_execute("CREATE TEMPORARY TABLE energies_staging (LIKE energies INCLUDING DEFAULTS) ON COMMIT DROP")
         .await?;
 
-        messages.push(format!("TimescaleDB | Production {} - {:.2}", time, value));
+    let sink = trans
+        .copy_in(format!("COPY energies_staging ({}) FROM STDIN BINARY", ENERGIES_COLUMNS).as_str())
+        .await?;
+    let writer = BinaryCopyInWriter::new(
+        sink,
+        &[
+            Type::TIMESTAMPTZ,
+            Type::TEXT,
+            Type::INT4,
+            Type::INT2,
+            Type::TEXT,
+            Type::VARCHAR,
+            Type::FLOAT4,
+            Type::FLOAT4,
+            Type::FLOAT4,
+            Type::FLOAT4,
+            Type::FLOAT4,
+            Type::FLOAT4,
+            Type::FLOAT4,
+            Type::FLOAT4,
+            Type::BOOL,
+            Type::FLOAT4,
+            Type::VARCHAR,
+        ],
+    );
+    pin_mut!(writer);
+
+    for interval in &intervals {
+        let contract_type: i16 = interval.contract_type.clone().into();
+        let measurementtype: i32 = interval.measurement_type.code();
+        let resolution = interval.resolution.to_string();
+
+        writer
+            .as_mut()
+            .write(&[
+                &interval.time,
+                &interval.metering_point_code,
+                &measurementtype,
+                &contract_type,
+                &"wattivahti",
+                &interval.unit,
+                &interval.value,
+                &interval.energy_basic_fee,
+                &interval.energy_fee,
+                &interval.energy_margin,
+                &interval.transfer_basic_fee,
+                &interval.transfer_fee,
+                &interval.transfer_tax_fee,
+                &interval.tax_percentage,
+                &interval.night,
+                &interval.spot_price,
+                &resolution,
+            ])
+            .await?;
     }
+    let staged = writer.finish().await?;
+
+    // Quarter hours without their own day-ahead price use the price of their hour, like `SpotPrices::get`
+    let spot_contract: i16 = ContractType::Spot.into();
+    let upserted = trans
+        .execute(
+            format!(
+                "INSERT INTO energies ({columns})
+                    SELECT s.time, s.metering_point_code, s.measure_type, s.contract_type, s.source, s.measure_unit, s.value, s.energy_basic_fee, s.energy_fee, s.energy_margin, s.transfer_basic_fee, s.transfer_fee, s.transfer_tax_fee, s.tax_percentage, s.night,
+                        CASE WHEN s.spot_price IS NULL AND s.contract_type <> $1 THEN COALESCE(p.price, h.price) / 10 ELSE s.spot_price END,
+                        s.resolution_duration
+                    FROM energies_staging s
+                    LEFT JOIN day_ahead_prices p ON p.time = s.time
+                    LEFT JOIN day_ahead_prices h ON h.time = date_trunc('hour', s.time)
+                    ON CONFLICT (time, metering_point_code, measure_type, resolution_duration) DO UPDATE
+                        SET contract_type = EXCLUDED.contract_type, source = EXCLUDED.source, measure_unit = EXCLUDED.measure_unit, value = EXCLUDED.value, energy_basic_fee = EXCLUDED.energy_basic_fee,
+                            energy_fee = CASE WHEN EXCLUDED.contract_type = $1 AND energies.contract_type = $1 THEN COALESCE(EXCLUDED.energy_fee, energies.energy_fee) ELSE EXCLUDED.energy_fee END,
+                            energy_margin = EXCLUDED.energy_margin, transfer_basic_fee = EXCLUDED.transfer_basic_fee, transfer_fee = EXCLUDED.transfer_fee, transfer_tax_fee = EXCLUDED.transfer_tax_fee, tax_percentage = EXCLUDED.tax_percentage, night = EXCLUDED.night,
+                            spot_price = COALESCE(EXCLUDED.spot_price, energies.spot_price)",
+                columns = ENERGIES_COLUMNS
+            )
+            .as_str(),
+            &[&spot_contract],
+        )
+        .await?;
 
     trans.commit().await?;
 
-    let all_messages = messages.join("\n");
-    info!("{}", all_messages);
+    info!("TimescaleDB | Staged {} intervals, upserted {} rows", staged, upserted);
 
-    Ok(())
+    Ok(upserted as usize)
 }
 
-pub async fn upsert_consumptions_into_timescaledb(
-    data: &ConsumptionsResult,
-    config: &SettingsConfig,
-) -> Result<(), Error> {
-    if !is_enabled() {
-        return Ok(());
+/// The last interval for each key of the `energies` unique constraint, as one upsert statement
+/// can't update the same row twice.
+fn latest_by_key(intervals: &[PricedInterval]) -> Vec<&PricedInterval> {
+    let mut positions = HashMap::new();
+    let mut latest: Vec<&PricedInterval> = Vec::with_capacity(intervals.len());
+
+    for interval in intervals {
+        let key = (interval.time, interval.metering_point_code.as_str(), interval.measurement_type.code(), interval.resolution.to_string());
+        match positions.entry(key) {
+            Entry::Occupied(entry) => latest[*entry.get()] = interval,
+            Entry::Vacant(entry) => {
+                entry.insert(latest.len());
+                latest.push(interval);
+            }
+        }
     }
 
-    let mut messages = Vec::new();
+    latest
+}
 
-    let mut client = connect_to_db().await?;
-    let trans = client.transaction().await?;
-    let resolution = &data
-        .getconsumptionsresult
-        .consumptiondata
-        .timeseries
-        .resolution;
-    let resolution_duration = ResolutionDuration::from_str(resolution);
-
-    for (pos, tsv) in data
-        .getconsumptionsresult
-        .consumptiondata
-        .timeseries
-        .values
-        .tsv
-        .iter()
-        .enumerate()
-    {
-        let time = &tsv.get_timestamp_utc_calculated(pos, &resolution_duration);
-        if time.is_none() {
-            warn!("TimescaleDB | Skipping consumption logging because time couldn't be parsed");
-            continue;
-        }
+/// Stored intervals matching the query in time order.
+pub async fn get_intervals(pool: &Pool, query: &IntervalQuery) -> Result<Vec<PricedInterval>, anyhow::Error> {
+    let client = pool.get().await?;
+    let resolution = query.resolution.map(|resolution| resolution.to_string());
+    let spot_contract: i16 = ContractType::Spot.into();
+    let rows = client
+        .query(
+            "SELECT time, metering_point_code, measure_type, resolution_duration, measure_unit, value, contract_type, night, spot_price, energy_basic_fee, energy_fee, energy_margin, transfer_basic_fee, transfer_fee, transfer_tax_fee, tax_percentage
+                FROM energies
+                WHERE time >= $1 AND time < $2 AND value IS NOT NULL
+                    AND (cardinality($3::text[]) = 0 OR metering_point_code = ANY($3))
+                    AND ($4::text IS NULL OR resolution_duration = $4)
+                    AND (NOT $5 OR (contract_type = $6 AND spot_price IS NULL))
+                ORDER BY time, metering_point_code, measure_type",
+            &[&query.start, &query.stop, &query.points, &resolution, &query.pending_price_only, &spot_contract],
+        )
+        .await?;
 
-        if tsv.quantity.is_none() {
-            // warn!("TimescaleDB | Skipping consumption logging because quantity was null");
-            continue;
-        }
+    rows.iter()
+        .map(|row| {
+            let resolution: String = row.get(3);
+            Ok(PricedInterval {
+                time: row.get(0),
+                metering_point_code: row.get(1),
+                measurement_type: MeasurementType::from(row.get::<_, i32>(2)),
+                resolution: resolution.parse()?,
+                unit: row.get(4),
+                value: row.get(5),
+                contract_type: ContractType::try_from(row.get::<_, i16>(6))?,
+                night: row.get(7),
+                spot_price: row.get(8),
+                energy_basic_fee: row.get::<_, Option<f32>>(9).unwrap_or_default(),
+                energy_fee: row.get(10),
+                energy_margin: row.get::<_, Option<f32>>(11).unwrap_or_default(),
+                transfer_basic_fee: row.get::<_, Option<f32>>(12).unwrap_or_default(),
+                transfer_fee: row.get::<_, Option<f32>>(13).unwrap_or_default(),
+                transfer_tax_fee: row.get::<_, Option<f32>>(14).unwrap_or_default(),
+                tax_percentage: row.get(15),
+            })
+        })
+        .collect()
+}
 
-        let time = time.unwrap();
-        let contract = config.consumption.get_contract(time);
-        if contract.is_none() {
-            warn!("TimescaleDB | Skipping consumption logging because contract couldn't be found");
-            continue;
-        }
+/// Day-ahead prices of `start..stop` in EUR/MWh.
+async fn get_day_ahead_prices(pool: &Pool, start: DateTime<Utc>, stop: DateTime<Utc>) -> Result<SpotPrices, anyhow::Error> {
+    let client = pool.get().await?;
+    let rows = client
+        .query("SELECT time, price::real FROM day_ahead_prices WHERE time >= $1 AND time < $2", &[&start, &stop])
+        .await?;
 
-        let contract = contract.unwrap();
-        let contract_type: i16 = contract.contract_type.clone().into();
-        let meteringpointcode = &data.getconsumptionsresult.consumptiondata.meteringpointcode;
-        let measurementtype: i32 = 1;
-        let unit = &data.getconsumptionsresult.consumptiondata.sum.unit;
-        let value = tsv.quantity.unwrap();
-        let is_night = contract.get_is_night(time);
-        let tax_percentage = contract.get_tax_percentage();
-
-        match contract.contract_type {
-            ContractType::Fixed | ContractType::Hybrid => {
-                let transfer_basic_fee = contract.get_transfer_basic_fee();
-                let transfer_fee = contract.get_transfer_fee(time);
-                let transfer_tax_fee = contract.get_transfer_tax_fee();
-                let energy_basic_fee = contract.get_energy_basic_fee();
-                let energy_fee = contract.get_energy_fee_fixed(time);
-
-                // time, metering_point_code, measure_type, contract_type, source, measure_unit, value, energy_basic_fee, energy_fee, energy_margin, transfer_basic_fee, transfer_fee, transfer_tax_fee, tax_percentage
-
-                
-                let _ = trans
-                    .execute("INSERT INTO energies (time, metering_point_code, measure_type, contract_type, source, measure_unit, value, energy_basic_fee, energy_fee, transfer_basic_fee, transfer_fee, transfer_tax_fee, tax_percentage, night, spot_price, resolution_duration) 
-                                        VALUES ($1, $2, $3, $4, 'wattivahti', $5, $6, $7, $8, $9, $10, $11, $12, $13, COALESCE((SELECT (price / 10.) FROM day_ahead_prices WHERE time = $1), (SELECT (price / 10.) FROM day_ahead_prices WHERE time = date_trunc('hour', $1))), $14)
-                                        ON CONFLICT (time, metering_point_code, measure_type, resolution_duration) DO UPDATE
-                                            SET contract_type = $4, source = 'wattivahti', measure_unit = $5, value = $6, energy_basic_fee = $7, energy_fee = $8, transfer_basic_fee = $9, transfer_fee = $10, transfer_tax_fee = $11, tax_percentage = $12, night = $13, spot_price = EXCLUDED.spot_price, resolution_duration = $14",
-                    &[&time, &meteringpointcode.to_string(), &measurementtype, &contract_type, &unit.to_string(), &value, &energy_basic_fee, &energy_fee, &transfer_basic_fee, &transfer_fee, &transfer_tax_fee, &tax_percentage, &is_night, &resolution_duration.to_str()])
-                .await?;
-            }
-            ContractType::Spot => {
-                let transfer_basic_fee = contract.get_transfer_basic_fee();
-                let transfer_fee = contract.get_transfer_fee(time);
-                let transfer_tax_fee = contract.get_transfer_tax_fee();
-                let energy_basic_fee = contract.get_energy_basic_fee();
-                let energy_margin = contract.get_energy_margin();
-
-                let _ = trans
-                    .execute("INSERT INTO energies (time, metering_point_code, measure_type, contract_type, source, measure_unit, value, energy_basic_fee, energy_margin, transfer_basic_fee, transfer_fee, transfer_tax_fee, tax_percentage, night, spot_price, resolution_duration) 
-                                        VALUES ($1, $2, $3, $4, 'wattivahti', $5, $6, $7, $8, $9, $10, $11, $12, $13, COALESCE((SELECT (price / 10.) FROM day_ahead_prices WHERE time = $1), (SELECT (price / 10.) FROM day_ahead_prices WHERE time = date_trunc('hour', $1))), $14)
-                                        ON CONFLICT (time, metering_point_code, measure_type, resolution_duration) DO UPDATE
-                                            SET contract_type = $4, source = 'wattivahti', measure_unit = $5, value = $6, energy_basic_fee = $7, energy_margin = $8, transfer_basic_fee = $9, transfer_fee = $10, transfer_tax_fee = $11, tax_percentage = $12, night = $13, spot_price = EXCLUDED.spot_price, resolution_duration = $14",
-                    &[&time, &meteringpointcode.to_string(), &measurementtype, &contract_type, &unit.to_string(), &value, &energy_basic_fee, &energy_margin, &transfer_basic_fee, &transfer_fee, &transfer_tax_fee, &tax_percentage, &is_night, &resolution_duration.to_str()])
-                .await?;
-            }
-            ContractType::None => todo!(),
-        }
+    Ok(rows.iter().map(|row| (row.get::<_, DateTime<Utc>>(0), row.get::<_, f32>(1))).collect())
+}
 
-        messages.push(format!("TimescaleDB | Consumption {} - {:.2}", time, value));
+/// Upserts the prices in EUR/MWh with one statement, returns how many rows it wrote. The last
+/// price of a time wins.
+pub async fn upsert_day_ahead_prices(pool: &Pool, prices: &[DayAheadPrice]) -> Result<usize, anyhow::Error> {
+    if prices.is_empty() {
+        return Ok(0);
     }
 
-    trans.commit().await?;
+    let times: Vec<DateTime<Utc>> = prices.iter().map(|price| price.time).collect();
+    let values: Vec<f32> = prices.iter().map(|price| price.price).collect();
+
+    let client = pool.get().await?;
+    let upserted = client
+        .execute(
+            "INSERT INTO day_ahead_prices (time, price)
+                SELECT DISTINCT ON (time) time, price
+                FROM unnest($1::timestamptz[], $2::real[]) WITH ORDINALITY AS p(time, price, position)
+                ORDER BY time, position DESC
+                ON CONFLICT (time) DO UPDATE SET price = EXCLUDED.price",
+            &[&times, &values],
+        )
+        .await?;
 
-    let all_messages = messages.join("\n");
-    info!("{}", all_messages);
+    info!("TimescaleDB | Upserted {} day-ahead price rows", upserted);
 
-    Ok(())
+    Ok(upserted as usize)
+}
+
+/// Refreshes the continuous aggregates built on top of the given measurement type.
+pub async fn refresh_views(pool: &Pool, measurement_type: MeasurementType) -> Result<(), anyhow::Error> {
+    match measurement_type {
+        MeasurementType::Consumption => refresh_consumption_views(pool).await,
+        MeasurementType::Production => refresh_production_views(pool).await,
+        MeasurementType::Other(_) => Ok(()),
+    }
 }
 
-pub async fn refresh_consumption_views() -> Result<(), Error> {
-    let client = connect_to_db().await?;
+pub async fn refresh_consumption_views(pool: &Pool) -> Result<(), anyhow::Error> {
+    let client = pool.get().await?;
 
     // Execute the refresh commands
     client
@@ -233,8 +327,8 @@ pub async fn refresh_consumption_views() -> Result<(), Error> {
     Ok(())
 }
 
-pub async fn refresh_production_views() -> Result<(), Error> {
-    let client = connect_to_db().await?;
+pub async fn refresh_production_views(pool: &Pool) -> Result<(), anyhow::Error> {
+    let client = pool.get().await?;
 
     // Execute the refresh commands
     client
@@ -271,22 +365,184 @@ pub async fn refresh_production_views() -> Result<(), Error> {
     Ok(())
 }
 
-async fn connect_to_db() -> Result<tokio_postgres::Client, Error> {
-    let (client, connection) = tokio_postgres::connect(
-        &dotenv::var("TIMESCALEDB_CONNECTION_STRING").unwrap_or(
-            "host=localhost user=myuser password=mysecretpassword dbname=electricity".to_string(),
-        ),
-        NoTls,
-    )
-    .await?;
-
-    // The connection object performs the actual communication with the database,
-    // so spawn it off to run on its own.
-    tokio::spawn(async move {
-        if let Err(e) = connection.await {
-            error!("connection error: {}", e);
+/// Pool of connections opened on first use. Connections are unencrypted unless TLS is asked for,
+/// see [`uses_tls`].
+fn create_pool(settings: &TimescaleDbConfig) -> Result<Pool, anyhow::Error> {
+    let config = tokio_postgres::Config::from_str(&settings.connection_string)?;
+    let manager_config = ManagerConfig { recycling_method: RecyclingMethod::Fast };
+    let manager = if uses_tls(&config, settings) {
+        Manager::from_config(config, tls_connector(settings)?, manager_config)
+    } else {
+        Manager::from_config(config, NoTls, manager_config)
+    };
+
+    Ok(Pool::builder(manager).max_size(settings.pool_size).build()?)
+}
+
+/// TLS is used with `sslmode=require` or a `ca_certificate`. The default `sslmode=prefer` stays
+/// unencrypted, as a server with a self-signed certificate would fail the handshake.
+fn uses_tls(config: &tokio_postgres::Config, settings: &TimescaleDbConfig) -> bool {
+    matches!(config.get_ssl_mode(), SslMode::Require) || settings.ca_certificate.is_some()
+}
+
+/// Verifies the server against the public web roots and the configured CA certificates.
+fn tls_connector(settings: &TimescaleDbConfig) -> Result<MakeRustlsConnect, anyhow::Error> {
+    let mut roots = RootCertStore::empty();
+    roots.add_server_trust_anchors(
+        webpki_roots::TLS_SERVER_ROOTS
+            .iter()
+            .map(|anchor| OwnedTrustAnchor::from_subject_spki_name_constraints(anchor.subject, anchor.spki, anchor.name_constraints)),
+    );
+
+    if let Some(path) = &settings.ca_certificate {
+        let file = File::open(path).with_context(|| format!("Failed to open {}", path))?;
+        let certificates = rustls_pemfile::certs(&mut BufReader::new(file))?;
+        if roots.add_parsable_certificates(&certificates).0 == 0 {
+            bail!("No CA certificates in {}", path);
         }
-    });
+    }
+
+    let config = ClientConfig::builder().with_safe_defaults().with_root_certificates(roots).with_no_client_auth();
+    Ok(MakeRustlsConnect::new(config))
+}
+
+#[cfg(test)]
+mod tests {
+    use api::ResolutionDuration;
+    use chrono::TimeZone;
+
+    use super::*;
+
+    /// The tables of `scripts/create_tables.sql` without the TimescaleDB specific parts. The lock
+    /// keeps tests running in parallel from creating them at the same time.
+    const TEST_TABLES: &str = "
+        BEGIN;
+        SELECT pg_advisory_xact_lock(20240101);
+        CREATE TABLE IF NOT EXISTS energies (
+            time TIMESTAMP WITH TIME ZONE NOT NULL, metering_point_code TEXT NOT NULL, measure_type INTEGER NOT NULL,
+            contract_type SMALLINT NOT NULL, source TEXT NULL, measure_unit VARCHAR(3) NOT NULL, value REAL NULL,
+            energy_basic_fee REAL NULL, energy_fee REAL NULL, energy_margin REAL NULL, transfer_basic_fee REAL NULL,
+            transfer_fee REAL NULL, transfer_tax_fee REAL NULL, tax_percentage REAL NOT NULL DEFAULT '24',
+            night BOOLEAN NOT NULL DEFAULT 'false', resolution_duration VARCHAR(10) NOT NULL, spot_price REAL NULL,
+            UNIQUE (time, metering_point_code, measure_type, resolution_duration)
+        );
+        CREATE TABLE IF NOT EXISTS day_ahead_prices (time TIMESTAMP WITH TIME ZONE NOT NULL UNIQUE, price REAL NOT NULL);
+        COMMIT;";
+
+    /// Pool of the database in `TIMESCALEDB_TEST_CONNECTION_STRING`, e.g. a throwaway PostgreSQL.
+    async fn test_pool() -> Pool {
+        dotenv::dotenv().ok();
+        let settings = TimescaleDbConfig {
+            enabled: true,
+            connection_string: dotenv::var("TIMESCALEDB_TEST_CONNECTION_STRING").unwrap(),
+            ..Default::default()
+        };
+        let pool = create_pool(&settings).unwrap();
+        pool.get().await.unwrap().batch_execute(TEST_TABLES).await.unwrap();
+        pool
+    }
+
+    fn interval(code: &str, time: DateTime<Utc>, spot_price: Option<f32>, energy_fee: Option<f32>) -> PricedInterval {
+        PricedInterval {
+            time,
+            metering_point_code: code.to_string(),
+            measurement_type: MeasurementType::Consumption,
+            resolution: ResolutionDuration::PT1H,
+            unit: "kWh".to_string(),
+            value: 1.5,
+            contract_type: ContractType::Spot,
+            night: false,
+            spot_price,
+            energy_basic_fee: 3.0,
+            energy_fee,
+            energy_margin: 0.5,
+            transfer_basic_fee: 10.0,
+            transfer_fee: 4.0,
+            transfer_tax_fee: 2.0,
+            tax_percentage: 24.0,
+        }
+    }
+
+    fn price(time: DateTime<Utc>, price: f32) -> DayAheadPrice {
+        DayAheadPrice {
+            time,
+            resolution: ResolutionDuration::PT1H,
+            price,
+            in_domain: "10YFI-1--------U".to_string(),
+            out_domain: "10YFI-1--------U".to_string(),
+            currency: "EUR".to_string(),
+            price_measure: "MWH".to_string(),
+            curve_type: "A01".to_string(),
+        }
+    }
+
+    #[test]
+    fn test_tls_only_when_asked_for() {
+        let settings = TimescaleDbConfig::default();
+        let config = |connection_string: &str| tokio_postgres::Config::from_str(connection_string).unwrap();
 
-    Ok(client)
+        assert!(!uses_tls(&config(&settings.connection_string), &settings));
+        assert!(!uses_tls(&config("host=localhost sslmode=prefer"), &settings));
+        assert!(uses_tls(&config("host=localhost sslmode=require"), &settings));
+
+        let settings = TimescaleDbConfig { ca_certificate: Some("ca.pem".to_string()), ..settings };
+        assert!(uses_tls(&config("host=localhost"), &settings));
+    }
+
+    #[tokio::test]
+    #[ignore = "requires a PostgreSQL database in TIMESCALEDB_TEST_CONNECTION_STRING"]
+    async fn test_upsert_day_ahead_prices_in_one_statement() {
+        let pool = test_pool().await;
+        let start = Utc.ymd(2001, 2, 1).and_hms(0, 0, 0);
+        let stop = start + chrono::Duration::hours(3);
+        pool.get().await.unwrap().execute("DELETE FROM day_ahead_prices WHERE time >= $1 AND time < $2", &[&start, &stop]).await.unwrap();
+
+        let hour = chrono::Duration::hours(1);
+        let prices = vec![price(start, 10.0), price(start + hour, 20.0), price(start + hour, 25.0)];
+        assert_eq!(upsert_day_ahead_prices(&pool, &prices).await.unwrap(), 2);
+        assert_eq!(upsert_day_ahead_prices(&pool, &[price(start, 12.0), price(start + hour * 2, 30.0)]).await.unwrap(), 2);
+        assert_eq!(upsert_day_ahead_prices(&pool, &[]).await.unwrap(), 0);
+
+        let stored = get_day_ahead_prices(&pool, start, stop).await.unwrap();
+        assert_eq!(stored.get(start), Some(12.0));
+        assert_eq!(stored.get(start + hour), Some(25.0));
+        assert_eq!(stored.get(start + hour * 2), Some(30.0));
+    }
+
+    #[tokio::test]
+    #[ignore = "requires a PostgreSQL database in TIMESCALEDB_TEST_CONNECTION_STRING"]
+    async fn test_unpriced_refetch_keeps_the_stored_price() {
+        let pool = test_pool().await;
+        let code = "test-keep-price";
+        let time = Utc.ymd(2001, 1, 1).and_hms(0, 0, 0);
+        pool.get().await.unwrap().execute("DELETE FROM energies WHERE metering_point_code = $1", &[&code]).await.unwrap();
+
+        let priced = interval(code, time, Some(5.0), Some(6.7));
+        assert_eq!(upsert_intervals_into_timescaledb(&pool, &[priced]).await.unwrap(), 1);
+        let mut unpriced = interval(code, time, None, None);
+        unpriced.value = 2.5;
+        assert_eq!(upsert_intervals_into_timescaledb(&pool, &[unpriced]).await.unwrap(), 1);
+
+        let query = IntervalQuery {
+            start: time,
+            stop: time + chrono::Duration::hours(1),
+            points: vec![code.to_string()],
+            resolution: None,
+            pending_price_only: false,
+        };
+        let stored = get_intervals(&pool, &query).await.unwrap();
+        assert_eq!(stored.len(), 1);
+        assert_eq!(stored[0].value, 2.5);
+        assert_eq!(stored[0].spot_price, Some(5.0));
+        assert_eq!(stored[0].energy_fee, Some(6.7));
+
+        let pending = IntervalQuery { pending_price_only: true, ..query };
+        assert!(get_intervals(&pool, &pending).await.unwrap().is_empty());
+
+        // Rows stored with a spot price before the energy fee was stored aren't pending either
+        let later = time + chrono::Duration::hours(1);
+        assert_eq!(upsert_intervals_into_timescaledb(&pool, &[interval(code, later, Some(5.0), None)]).await.unwrap(), 1);
+        let pending = IntervalQuery { stop: later + chrono::Duration::hours(1), ..pending };
+        assert!(get_intervals(&pool, &pending).await.unwrap().is_empty());
+    }
 }
@@ -34,3 +34,16 @@ WHERE "resolution_duration" IS NULL;
 
 ALTER TABLE "energies"
 ALTER COLUMN "resolution_duration" SET NOT NULL;
+
+-- Migration where we add the spot price in c/kWh without VAT, NULL while the day-ahead price isn't known
+ALTER TABLE "energies"
+ADD COLUMN IF NOT EXISTS "spot_price" REAL NULL DEFAULT NULL;
+
+-- Day-ahead prices in EUR/MWh without VAT, upserted by the ENTSO-E price fetch of the logger.
+-- Tables created by other tools need the unique index on time for the upserts.
+CREATE TABLE IF NOT EXISTS "day_ahead_prices" (
+    "time" TIMESTAMP WITH TIME ZONE NOT NULL,
+    "price" REAL NOT NULL
+);
+
+CREATE UNIQUE INDEX IF NOT EXISTS day_ahead_prices_time_key ON "day_ahead_prices" ("time");